@@ -0,0 +1,250 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::Deserialize;
+
+pub const ACCESS_TOKEN: &str = "access";
+const KEY_SET_TTL: u64 = 3_600_000_000_000; // 1 hour
+const KEY_SET_MIN_REFRESH: u64 = 60_000_000_000; // 1 minute
+
+// A public key of the ID service as returned by its `GetSigningKeys`, times in nanoseconds
+#[derive(Clone, Debug)]
+pub struct SigningKey {
+    pub kid: String,
+    pub public_key: Vec<u8>,
+    pub expires_at: Option<u64>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Claims {
+    pub username: String,
+    pub typ: String,
+    #[serde(default)]
+    pub sid: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub scope: Option<String>, // set on third-party tokens only
+    pub exp: u64
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: String
+}
+
+fn decode_part<T: for<'de> Deserialize<'de>>(part: &str) -> Option<T> {
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).ok()?).ok()
+}
+
+fn find_key(keys: &[SigningKey], kid: &str, now: u64) -> Option<VerifyingKey> {
+    let key = keys.iter().find(|key| key.kid == kid && key.expires_at.map_or(true, |expires_at| expires_at > now))?;
+    VerifyingKey::from_bytes(key.public_key.as_slice().try_into().ok()?).ok()
+}
+
+// The `kid` a token claims to be signed with, to tell whether the key set needs a refresh
+pub fn token_kid(token: &str) -> Option<String> {
+    decode_part::<JwtHeader>(token.split('.').next()?).map(|header| header.kid)
+}
+
+pub fn has_key(keys: &[SigningKey], kid: &str, now: u64) -> bool {
+    find_key(keys, kid, now).is_some()
+}
+
+// The key set is refreshed hourly, or sooner for an unknown `kid`
+pub fn needs_refresh(keys: &[SigningKey], kid: &str, fetched_at: u64, now: u64) -> bool {
+    let since_refresh = now.saturating_sub(fetched_at);
+    since_refresh > KEY_SET_TTL || (!has_key(keys, kid, now) && since_refresh > KEY_SET_MIN_REFRESH)
+}
+
+// Reads the claims without checking the signature, only for tokens that passed `verify_access_token`
+pub fn decode_claims(token: &str) -> Option<Claims> {
+    decode_part(token.split('.').nth(1)?)
+}
+
+// First-party tokens carry no scope and grant everything
+pub fn has_scope(claims: &Claims, scope: &str) -> bool {
+    match &claims.scope {
+        Some(granted) => granted.split(' ').any(|granted| granted == scope),
+        None => true
+    }
+}
+
+// Checks the signature against `keys`, the expiry and that the access token grants `scope`.
+// Revocation, suspension and role changes since the token was issued are not visible from here.
+pub fn verify_access_token(token: &str, keys: &[SigningKey], scope: &str, now: u64) -> Option<Claims> {
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let header: JwtHeader = decode_part(parts[0])?;
+    if header.alg != "EdDSA" {
+        return None;
+    }
+    let key = find_key(keys, &header.kid, now)?;
+    let signature = Signature::from_slice(&URL_SAFE_NO_PAD.decode(parts[2]).ok()?).ok()?;
+    key.verify(&token.as_bytes()[..token.rfind('.')?], &signature).ok()?;
+    let claims: Claims = decode_part(parts[1])?;
+    if claims.typ != ACCESS_TOKEN || claims.exp <= now / 1_000_000_000 || !has_scope(&claims, scope) {
+        return None;
+    }
+    Some(claims)
+}
+
+// The canister side of the checks: the cached key set and the calls to the ID service, whose
+// principal is `$id_service`. A macro, so that each canister builds it against its own ic-cdk
+// and candid versions. The scopes a canister checks stay with it.
+#[macro_export]
+macro_rules! token_checks {
+    ($id_service:expr) => {
+        pub use $crate::{decode_claims, Claims};
+
+        #[derive(Clone, Debug, candid::Deserialize, candid::CandidType)]
+        pub struct PublicSigningKey {
+            pub kid: String,
+            pub public_key: Vec<u8>,
+            pub expires_at: Option<u64>
+        }
+
+        #[derive(Clone, Debug, candid::Deserialize, candid::CandidType)]
+        struct CheckAccessTokenParams {
+            token: String,
+            scope: String
+        }
+
+        #[derive(Clone, Debug, candid::Deserialize, candid::CandidType)]
+        pub struct AccessTokenResult {
+            pub error: String,
+            pub user_name: String,
+            pub roles: Vec<String>, // current roles, only "user" for scoped tokens
+            pub scoped: bool, // issued to a third-party app
+            pub step_up: bool // no TOTP enrolled, or a recent enough code verified for this token
+        }
+
+        thread_local! {
+            static KEY_SET: std::cell::RefCell<Vec<$crate::SigningKey>> = std::cell::RefCell::default();
+            static KEY_SET_FETCHED_AT: std::cell::Cell<u64> = std::cell::Cell::new(0);
+        }
+
+        async fn refresh_key_set() -> ic_cdk::api::call::CallResult<()> {
+            let (keys,) = ic_cdk::call::<(), (Vec<PublicSigningKey>,)>(candid::Principal::from_text($id_service).unwrap(), "GetSigningKeys", ()).await?;
+            let keys = keys.into_iter().map(|key| $crate::SigningKey {
+                kid: key.kid,
+                public_key: key.public_key,
+                expires_at: key.expires_at
+            }).collect();
+            KEY_SET.with(|key_set| *key_set.borrow_mut() = keys);
+            KEY_SET_FETCHED_AT.with(|fetched_at| fetched_at.set(ic_cdk::api::time()));
+            Ok(())
+        }
+
+        // Local replacement for the ID service's `CheckToken`: returns the token itself when it is
+        // valid and grants `scope`, and an empty string otherwise. Revoked sessions, suspensions and
+        // role changes go unnoticed until the token expires, see `check_token_online`.
+        pub async fn check_token(token: String, scope: &str) -> ic_cdk::api::call::CallResult<(String,)> {
+            let now = ic_cdk::api::time();
+            let kid = match $crate::token_kid(&token) {
+                Some(kid) => kid,
+                None => return Ok(("".to_string(),))
+            };
+            let fetched_at = KEY_SET_FETCHED_AT.with(|fetched_at| fetched_at.get());
+            if KEY_SET.with(|key_set| $crate::needs_refresh(&key_set.borrow(), &kid, fetched_at, now)) {
+                refresh_key_set().await?;
+            }
+            match KEY_SET.with(|key_set| $crate::verify_access_token(&token, &key_set.borrow(), scope, now)) {
+                Some(_) => Ok((token,)),
+                None => Ok(("".to_string(),))
+            }
+        }
+
+        // Asks the ID service's `CheckAccessToken`, for what must stop the moment a token is revoked
+        // or its user demoted or suspended. Returns the token when it is valid and grants `scope`,
+        // and an empty string otherwise, along with the user's current roles and step-up state.
+        // Role names are "user", "moderator", "admin" and "service".
+        pub async fn check_token_online(token: String, scope: &str) -> ic_cdk::api::call::CallResult<(String, AccessTokenResult)> {
+            let params = CheckAccessTokenParams {
+                token: token.clone(),
+                scope: scope.to_string()
+            };
+            let (access,) = ic_cdk::call::<(CheckAccessTokenParams,), (AccessTokenResult,)>(candid::Principal::from_text($id_service).unwrap(), "CheckAccessToken", (params,)).await?;
+            let token = if access.error.is_empty() { token } else { "".to_string() };
+            Ok((token, access))
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey as Ed25519SigningKey};
+    use serde_json::json;
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    fn signer() -> Ed25519SigningKey {
+        Ed25519SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn key_set() -> Vec<SigningKey> {
+        vec![SigningKey {
+            kid: "k1".to_string(),
+            public_key: signer().verifying_key().to_bytes().to_vec(),
+            expires_at: None
+        }]
+    }
+
+    fn token(kid: &str, claims: serde_json::Value) -> String {
+        let header = json!({ "alg": "EdDSA", "typ": "JWT", "kid": kid });
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = signer().sign(signing_input.as_bytes());
+        format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature.to_bytes()))
+    }
+
+    fn claims(typ: &str, scope: Option<&str>, exp: u64) -> serde_json::Value {
+        json!({ "username": "alice", "typ": typ, "sid": "s1", "scope": scope, "exp": exp })
+    }
+
+    #[test]
+    fn accepts_live_access_tokens() {
+        let exp = NOW / 1_000_000_000 + 60;
+        let first_party = token("k1", claims(ACCESS_TOKEN, None, exp));
+        assert_eq!(verify_access_token(&first_party, &key_set(), "chat:write", NOW).unwrap().username, "alice");
+        let scoped = token("k1", claims(ACCESS_TOKEN, Some("chat:read chat:write"), exp));
+        assert!(verify_access_token(&scoped, &key_set(), "chat:write", NOW).is_some());
+    }
+
+    #[test]
+    fn rejects_wrong_scope_type_expiry_and_key() {
+        let exp = NOW / 1_000_000_000 + 60;
+        let keys = key_set();
+        assert!(verify_access_token(&token("k1", claims(ACCESS_TOKEN, Some("chat:read"), exp)), &keys, "chat:write", NOW).is_none());
+        assert!(verify_access_token(&token("k1", claims("refresh", None, exp)), &keys, "chat:write", NOW).is_none());
+        assert!(verify_access_token(&token("k1", claims(ACCESS_TOKEN, None, NOW / 1_000_000_000)), &keys, "chat:write", NOW).is_none());
+        assert!(verify_access_token(&token("k2", claims(ACCESS_TOKEN, None, exp)), &keys, "chat:write", NOW).is_none());
+        let expired_keys = vec![SigningKey { expires_at: Some(NOW), ..keys[0].clone() }];
+        assert!(verify_access_token(&token("k1", claims(ACCESS_TOKEN, None, exp)), &expired_keys, "chat:write", NOW).is_none());
+    }
+
+    #[test]
+    fn refreshes_hourly_or_for_unknown_keys() {
+        let keys = key_set();
+        assert!(!needs_refresh(&keys, "k1", NOW - KEY_SET_TTL, NOW));
+        assert!(needs_refresh(&keys, "k1", NOW - KEY_SET_TTL - 1, NOW));
+        assert!(!needs_refresh(&keys, "k2", NOW - KEY_SET_MIN_REFRESH, NOW));
+        assert!(needs_refresh(&keys, "k2", NOW - KEY_SET_MIN_REFRESH - 1, NOW));
+    }
+
+    #[test]
+    fn rejects_tampered_claims() {
+        let exp = NOW / 1_000_000_000 + 60;
+        let original = token("k1", claims(ACCESS_TOKEN, Some("chat:read"), exp));
+        let forged_claims = URL_SAFE_NO_PAD.encode(claims(ACCESS_TOKEN, None, exp).to_string());
+        let parts: Vec<&str> = original.split('.').collect();
+        let forged = format!("{}.{}.{}", parts[0], forged_claims, parts[2]);
+        assert!(verify_access_token(&forged, &key_set(), "chat:write", NOW).is_none());
+    }
+}
@@ -3,9 +3,7 @@ use ic_cdk::export::candid::CandidType;
 use ic_cdk::api::time;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
-use hmac::{Hmac, Mac};
-use jwt::VerifyWithKey;
-use sha2::Sha256;
+use crate::token_utils;
 
 #[derive(Clone, Debug, Deserialize, CandidType)]
 pub struct GroupMessage {
@@ -181,6 +179,22 @@ pub struct ViewMessageResponse {
     pub result: bool
 }
 
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RemoveGroupMessageParams {
+    pub token: String, 
+    pub group_id: String,
+    pub msg_id: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RemoveGroupMessageResponse {
+    pub token: String, 
+    pub error: String, 
+    pub result: bool
+}
+
+const REMOVED_MESSAGE: &str = "This message was removed by a moderator";
+
 type GroupMessageStore = BTreeMap<String, Vec<GroupMessage>>;
 type DirectMessageStore = Vec<DirectMessage>;
 type GroupStore = Vec<Group>;
@@ -198,7 +212,7 @@ thread_local! {
 pub async fn create_group(
     params: CreateGroupParams
 ) -> CreateGroupResponse {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token,)).await;
+    let user_validation = token_utils::check_token(params.token, token_utils::CHAT_WRITE).await;
     match user_validation {
         Err(_err) => {
             CreateGroupResponse {
@@ -256,7 +270,7 @@ pub async fn create_group(
 pub async fn join_group(
     params: JoinGroupParams
 ) -> JoinGroupResponse {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token.clone(),)).await;
+    let user_validation = token_utils::check_token(params.token.clone(), token_utils::CHAT_WRITE).await;
     match user_validation {
         Err(_err) => {
             JoinGroupResponse{
@@ -316,7 +330,7 @@ pub async fn join_group(
 pub async fn leave_group(
     params: LeaveGroupParams
 ) -> LeaveGroupResponse {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token.clone(),)).await;
+    let user_validation = token_utils::check_token(params.token.clone(), token_utils::CHAT_WRITE).await;
     match user_validation {
         Err(_err) => {
             return LeaveGroupResponse{
@@ -370,7 +384,7 @@ pub async fn leave_group(
 pub async fn get_group_members(
     params: GetGroupMembersParams
 ) -> GetGroupMembersResponse {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token.clone(),)).await;
+    let user_validation = token_utils::check_token(params.token.clone(), token_utils::CHAT_READ).await;
     match user_validation {
         Err(_err) => {
             return GetGroupMembersResponse{
@@ -411,7 +425,7 @@ pub async fn get_group_members(
 pub async fn get_group_list(
     params: GetJoinedGroupParams
 ) -> GetJoinedGroupResponse {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token.clone(),)).await;
+    let user_validation = token_utils::check_token(params.token.clone(), token_utils::CHAT_READ).await;
     match user_validation {
         Err(_err) => {
             GetJoinedGroupResponse{
@@ -446,7 +460,7 @@ pub async fn get_group_list(
 pub async fn get_group_messages(
     params: GetGroupMessageParams
 ) -> GetGroupMessageResponse {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token.clone(),)).await;
+    let user_validation = token_utils::check_token(params.token.clone(), token_utils::CHAT_READ).await;
     match user_validation {
         Err(_err) => {
             GetGroupMessageResponse{
@@ -487,7 +501,7 @@ pub async fn get_group_messages(
 pub async fn send_group_message(
     params: SendGroupMessageParams
 ) -> SendGroupMessageResponse {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token.clone(),)).await;
+    let user_validation = token_utils::check_token(params.token.clone(), token_utils::CHAT_WRITE).await;
     match user_validation {
         Err(_err) => {
             SendGroupMessageResponse{
@@ -547,7 +561,7 @@ pub async fn send_group_message(
 pub async fn send_direct_message(
     params: SendDirectMessageParams
 ) -> SendDirectMessageResponse {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token.clone(),)).await;
+    let user_validation = token_utils::check_token(params.token.clone(), token_utils::CHAT_WRITE).await;
     match user_validation {
         Err(_err) => {
             SendDirectMessageResponse{
@@ -635,7 +649,7 @@ pub async fn send_direct_message(
 pub async fn get_friend_list(
     params: GetConnectedMemberParams
 ) -> GetConnectedMemberResponse {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token.clone(),)).await;
+    let user_validation = token_utils::check_token(params.token.clone(), token_utils::CHAT_READ).await;
     match user_validation {
         Err(_err) => {
             GetConnectedMemberResponse{
@@ -670,7 +684,7 @@ pub async fn get_friend_list(
 pub async fn get_direct_messages(
     params: GetDirectMessageParams
 ) -> GetDirectMessageResponse{
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token.clone(),)).await;
+    let user_validation = token_utils::check_token(params.token.clone(), token_utils::CHAT_READ).await;
     match user_validation {
         Err(_err) => {
             GetDirectMessageResponse{
@@ -733,7 +747,7 @@ pub async fn get_direct_messages(
 }
 
 pub async fn view_message(params: ViewMessageParams) -> ViewMessageResponse {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token.clone(),)).await;
+    let user_validation = token_utils::check_token(params.token.clone(), token_utils::CHAT_WRITE).await;
     match user_validation {
         Err(_err) => {
             ViewMessageResponse{
@@ -770,6 +784,138 @@ pub async fn view_message(params: ViewMessageParams) -> ViewMessageResponse {
     }
 }
 
+// Moderators and admins can take down a group message. The entry stays in place with its
+// content replaced, as message ids are positions in the group's list.
+pub async fn remove_group_message(
+    params: RemoveGroupMessageParams
+) -> RemoveGroupMessageResponse {
+    let user_validation = token_utils::check_token_online(params.token.clone(), token_utils::CHAT_WRITE).await;
+    match user_validation {
+        Err(_err) => {
+            RemoveGroupMessageResponse{
+                token: "".to_string(),
+                error: "Can't access ID service".to_string(),
+                result: false
+            }
+        }
+        Ok((token, access)) => {
+            if token == "".to_string() {
+                RemoveGroupMessageResponse{
+                    token,
+                    error: "Invalid token".to_string(),
+                    result: false
+                }
+            }
+            else if !access.roles.iter().any(|role| role == "moderator" || role == "admin") {
+                RemoveGroupMessageResponse{
+                    token,
+                    error: "Only moderators can remove messages".to_string(),
+                    result: false
+                }
+            }
+            else{
+                GROUP_MESSAGE_STORE.with(|group_message_store| {
+                    let mut group_message_store = group_message_store.borrow_mut();
+                    let message = group_message_store.get_mut(&params.group_id)
+                        .and_then(|messages| messages.iter_mut().find(|message| message.id == params.msg_id));
+                    match message {
+                        Some(message) => {
+                            message.content = REMOVED_MESSAGE.to_string();
+                            RemoveGroupMessageResponse{
+                                token,
+                                error: "".to_string(),
+                                result: true
+                            }
+                        }
+                        None => RemoveGroupMessageResponse{
+                            token,
+                            error: "Message doesn't exist".to_string(),
+                            result: false
+                        }
+                    }
+                })
+            }
+        }
+    }
+}
+
+// Called by the ID service when an account is deleted. Removes the user from every group
+// and friend list. Direct messages are matched by name, so they go too, or whoever registers
+// the name next would read them. Group messages are kept.
+pub fn delete_user(
+    user_name: String
+) -> bool {
+    if ic_cdk::caller() != Principal::from_text(token_utils::ID_SERVICE_CANISTER).unwrap() {
+        return false;
+    }
+    GROUP_STORE.with(|group_store| {
+        for group in group_store.borrow_mut().iter_mut() {
+            group.group_members.retain(|member| *member != user_name);
+        }
+    });
+    USER_GROUP_STORE.with(|user_group_store| {
+        user_group_store.borrow_mut().remove(&user_name);
+    });
+    USER_FRIEND_STORE.with(|user_friend_store| {
+        let mut user_friend_store = user_friend_store.borrow_mut();
+        user_friend_store.remove(&user_name);
+        for friend_list in user_friend_store.values_mut() {
+            friend_list.retain(|friend| *friend != user_name);
+        }
+    });
+    DIRECT_MESSAGE_STORE.with(|direct_message_store| {
+        direct_message_store.borrow_mut().retain(|message| message.sender_id != user_name && message.receiver_id != user_name);
+    });
+    true
+}
+
+// Called by the ID service after a username change. Chat keys everything by name, so the
+// old name is replaced in memberships, friend lists and message senders and receivers.
+pub fn rename_user(
+    old_user_name: String,
+    new_user_name: String
+) -> bool {
+    if ic_cdk::caller() != Principal::from_text(token_utils::ID_SERVICE_CANISTER).unwrap() {
+        return false;
+    }
+    let rename = |name: &mut String| {
+        if *name == old_user_name {
+            *name = new_user_name.clone();
+        }
+    };
+    GROUP_STORE.with(|group_store| {
+        for group in group_store.borrow_mut().iter_mut() {
+            group.group_members.iter_mut().for_each(rename);
+        }
+    });
+    GROUP_MESSAGE_STORE.with(|group_message_store| {
+        for messages in group_message_store.borrow_mut().values_mut() {
+            messages.iter_mut().for_each(|message| rename(&mut message.sender_id));
+        }
+    });
+    DIRECT_MESSAGE_STORE.with(|direct_message_store| {
+        for message in direct_message_store.borrow_mut().iter_mut() {
+            rename(&mut message.sender_id);
+            rename(&mut message.receiver_id);
+        }
+    });
+    USER_GROUP_STORE.with(|user_group_store| {
+        let mut user_group_store = user_group_store.borrow_mut();
+        if let Some(group_list) = user_group_store.remove(&old_user_name) {
+            user_group_store.insert(new_user_name.clone(), group_list);
+        }
+    });
+    USER_FRIEND_STORE.with(|user_friend_store| {
+        let mut user_friend_store = user_friend_store.borrow_mut();
+        if let Some(friend_list) = user_friend_store.remove(&old_user_name) {
+            user_friend_store.insert(new_user_name.clone(), friend_list);
+        }
+        for friend_list in user_friend_store.values_mut() {
+            friend_list.iter_mut().for_each(rename);
+        }
+    });
+    true
+}
 
 fn has_group_id(
     id: String
@@ -782,13 +928,8 @@ fn has_group_id(
 pub fn get_user_name(
     token: String
 ) -> String {
-    let key: Hmac<Sha256> = Hmac::new_from_slice(b"wzrd-secret-key").unwrap();
-    let veri_claims;
-    let result: Result<BTreeMap<String, String>, jwt::Error> = token.as_str().verify_with_key(&key);
-    match result{
-        Ok(okay_result) => veri_claims = okay_result,
-        Err(_) => return "".to_string()
-    };
-    let user_name = &veri_claims["username"];
-    user_name.clone()
+    match token_utils::decode_claims(&token) {
+        Some(claims) => claims.username,
+        None => "".to_string()
+    }
  }
\ No newline at end of file
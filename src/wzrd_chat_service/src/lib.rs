@@ -1,5 +1,6 @@
 use ic_cdk::update;
 mod chat_utils;
+mod token_utils;
 
 #[update(name = "a.CreateGroup")]
 pub async fn create_group(params: chat_utils::CreateGroupParams) -> chat_utils::CreateGroupResponse {
@@ -54,4 +55,19 @@ pub async fn get_direct_messages(params: chat_utils::GetDirectMessageParams) ->
 #[update(name = "k.ViewMessage")]
 pub async fn view_message(params: chat_utils::ViewMessageParams) -> chat_utils::ViewMessageResponse {
     chat_utils::view_message(params).await
+}
+
+#[update(name = "l.DeleteUser")]
+pub fn delete_user(user_name: String) -> bool {
+    chat_utils::delete_user(user_name)
+}
+
+#[update(name = "m.RenameUser")]
+pub fn rename_user(old_user_name: String, new_user_name: String) -> bool {
+    chat_utils::rename_user(old_user_name, new_user_name)
+}
+
+#[update(name = "n.RemoveGroupMessage")]
+pub async fn remove_group_message(params: chat_utils::RemoveGroupMessageParams) -> chat_utils::RemoveGroupMessageResponse {
+    chat_utils::remove_group_message(params).await
 }
\ No newline at end of file
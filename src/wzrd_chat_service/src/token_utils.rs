@@ -0,0 +1,7 @@
+pub const ID_SERVICE_CANISTER: &str = "o75p4-yqaaa-aaaal-adt2a-cai";
+// Scopes a third-party token must carry, first-party tokens pass every check
+pub const CHAT_READ: &str = "chat:read";
+pub const CHAT_WRITE: &str = "chat:write";
+
+// `check_token` for everything but moderation, which goes through `check_token_online`
+wzrd_auth::token_checks!(ID_SERVICE_CANISTER);
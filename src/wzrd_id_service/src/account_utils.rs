@@ -0,0 +1,318 @@
+use candid::Deserialize;
+use ic_cdk::api::time;
+use ic_cdk::export::candid::CandidType;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::audit_utils::{self, SecurityEventKind};
+use crate::cascade_utils::{self, CascadeTask};
+use crate::challenge_utils::{self, Ceremony};
+use crate::id_utils::{self, RequestResult, TokenResult, KEY_STORE};
+use crate::token_utils;
+use crate::{admin_utils, directory_utils, lockout_utils, principal_utils, profile_utils, recovery_utils, role_utils, session_utils, totp_utils, username_utils};
+
+// Old names keep pointing at the account for this long, and the account can't be renamed again
+// in the meantime, so that a name is never handed to someone else while links to it still work
+const REDIRECT_PERIOD: u64 = 2_592_000_000_000_000; // 30 days
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct DeleteAccountParams {
+    pub token: String,
+    pub key_id: String,
+    pub signature: String,
+    pub authenticator_data: String,
+    pub client_data_json: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct DeleteAccountResult {
+    pub error: String,
+    pub result: bool
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ChangeUsernameParams {
+    pub token: String,
+    pub new_user_name: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct Redirect {
+    pub user_id: String,
+    pub expires_at: u64
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct UsernameChange {
+    pub old_user_name: String,
+    pub new_user_name: String,
+    pub changed_at: u64
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct UsernameHistoryResult {
+    pub error: String,
+    pub result: Vec<UsernameChange>
+}
+
+pub type UserIdStore = BTreeMap<String, String>; //(user_name => user id)
+pub type RedirectStore = BTreeMap<String, Redirect>; //(old user_name => redirect)
+pub type UsernameHistoryStore = BTreeMap<String, Vec<UsernameChange>>; //(user id => changes, oldest first)
+type DeletionLock = BTreeSet<String>; //(user_name waiting on the wallet service to delete)
+
+thread_local! {
+    static DELETION_LOCK: RefCell<DeletionLock> = RefCell::default();
+    pub static USER_ID_STORE: RefCell<UserIdStore> = RefCell::default();
+    pub static REDIRECT_STORE: RefCell<RedirectStore> = RefCell::default();
+    pub static USERNAME_HISTORY_STORE: RefCell<UsernameHistoryStore> = RefCell::default();
+}
+
+// Every account is given its id at registration, so this never writes. Unknown names get none.
+pub fn user_id(user_name: &str) -> String {
+    USER_ID_STORE.with(|user_id_store| user_id_store.borrow().get(user_name).cloned().unwrap_or_default())
+}
+
+pub fn assign_user_id(user_name: &str) {
+    USER_ID_STORE.with(|user_id_store| {
+        user_id_store.borrow_mut().entry(user_name.to_string()).or_insert_with(token_utils::new_token_id);
+    });
+}
+
+// Gives an id to accounts created before user ids existed, run after every upgrade
+pub fn backfill_user_ids() {
+    let user_names: Vec<String> = KEY_STORE.with(|key_store| key_store.borrow().iter().map(|(user_name, _)| user_name).collect());
+    for user_name in user_names {
+        assign_user_id(&user_name);
+    }
+}
+
+fn user_name_of(user_id: &str) -> Option<String> {
+    USER_ID_STORE.with(|user_id_store| {
+        user_id_store.borrow().iter().find(|(_, id)| *id == user_id).map(|(user_name, _)| user_name.clone())
+    })
+}
+
+// Current name of the account that used to be called `user_name`
+pub fn resolve_redirect(user_name: &str) -> Option<String> {
+    let redirect = REDIRECT_STORE.with(|redirect_store| redirect_store.borrow().get(user_name).cloned())?;
+    if redirect.expires_at <= time() {
+        return None;
+    }
+    user_name_of(&redirect.user_id)
+}
+
+pub fn active_redirects() -> Vec<String> {
+    let now = time();
+    REDIRECT_STORE.with(|redirect_store| {
+        redirect_store.borrow().iter()
+            .filter(|(_, redirect)| redirect.expires_at > now)
+            .map(|(user_name, _)| user_name.clone())
+            .collect()
+    })
+}
+
+pub async fn delete_account_request(token: String) -> RequestResult {
+    let claims = match id_utils::verify_token(&token) {
+        Some(claims) => claims,
+        None => return RequestResult{
+            error: "Invalid token".to_string(),
+            result: "".to_string()
+        }
+    };
+    if !totp_utils::has_step_up(&claims) {
+        return RequestResult{
+            error: totp_utils::STEP_UP_REQUIRED.to_string(),
+            result: "".to_string()
+        };
+    }
+    match challenge_utils::issue_challenge(claims.username, Ceremony::DeleteAccount).await {
+        Ok(challenge) => RequestResult{
+            error: "".to_string(),
+            result: challenge
+        },
+        Err(error) => RequestResult{
+            error,
+            result: "".to_string()
+        }
+    }
+}
+
+// Deletes the account after a fresh passkey assertion, and a TOTP step-up when enabled. The
+// wallet is checked and removed first, chat cleanup is queued and retried until the chat
+// service accepts it.
+pub async fn delete_account(params: DeleteAccountParams) -> DeleteAccountResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return DeleteAccountResult{
+            error: "Invalid token".to_string(),
+            result: false
+        }
+    };
+    if !totp_utils::has_step_up(&claims) {
+        return DeleteAccountResult{
+            error: totp_utils::STEP_UP_REQUIRED.to_string(),
+            result: false
+        };
+    }
+    let error = id_utils::verify_passkey(
+        &claims.username,
+        &params.key_id,
+        &params.signature,
+        &params.authenticator_data,
+        &params.client_data_json,
+        Ceremony::DeleteAccount
+    );
+    if !error.is_empty() {
+        return DeleteAccountResult{
+            error,
+            result: false
+        };
+    }
+
+    let user_name = claims.username;
+    // Held across the wallet call, so that the account can't be renamed or deleted twice while
+    // its wallet goes
+    if !DELETION_LOCK.with(|deletion_lock| deletion_lock.borrow_mut().insert(user_name.clone())) {
+        return DeleteAccountResult{
+            error: "Account deletion already in progress".to_string(),
+            result: false
+        };
+    }
+    let error = cascade_utils::delete_wallet(user_name.clone()).await;
+    DELETION_LOCK.with(|deletion_lock| deletion_lock.borrow_mut().remove(&user_name));
+    if !error.is_empty() && error != "No wallet exist" {
+        return DeleteAccountResult{
+            error,
+            result: false
+        };
+    }
+
+    // Kept in the log for incident response, but no longer reachable through the account
+    audit_utils::record(&user_name, SecurityEventKind::AccountDeleted, "".to_string());
+    KEY_STORE.with(|key_store| key_store.borrow_mut().remove(&user_name));
+    if let Some(user_id) = USER_ID_STORE.with(|user_id_store| user_id_store.borrow_mut().remove(&user_name)) {
+        REDIRECT_STORE.with(|redirect_store| redirect_store.borrow_mut().retain(|_, redirect| redirect.user_id != user_id));
+        USERNAME_HISTORY_STORE.with(|history_store| history_store.borrow_mut().remove(&user_id));
+        audit_utils::forget_user(&user_id);
+    }
+    username_utils::unindex_username(&user_name);
+    directory_utils::unindex_user(&user_name);
+    profile_utils::delete_profile(&user_name);
+    principal_utils::unlink_user(&user_name);
+    role_utils::delete_user(&user_name);
+    admin_utils::delete_user(&user_name);
+    lockout_utils::delete_user(&user_name);
+    totp_utils::delete_user(&user_name);
+    recovery_utils::delete_user(&user_name);
+    session_utils::end_user_sessions(&user_name);
+    cascade_utils::enqueue(CascadeTask::ChatDelete { user_name });
+    DeleteAccountResult{
+        error: "".to_string(),
+        result: true
+    }
+}
+
+// Renames the account everywhere it's keyed by name and returns a new token pair for the
+// calling session. Chat and wallet are updated through the retry queue.
+pub fn change_username(params: ChangeUsernameParams) -> TokenResult {
+    let error_result = |error: &str| TokenResult{
+        error: error.to_string(),
+        access_token: "".to_string(),
+        refresh_token: "".to_string()
+    };
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return error_result("Invalid token")
+    };
+    let old_user_name = claims.username;
+    if DELETION_LOCK.with(|deletion_lock| deletion_lock.borrow().contains(&old_user_name)) {
+        return error_result("Account deletion in progress");
+    }
+    let user_id = user_id(&old_user_name);
+    let now = time();
+
+    let last_change = USERNAME_HISTORY_STORE.with(|history_store| {
+        history_store.borrow().get(&user_id).and_then(|changes| changes.last().map(|change| change.changed_at))
+    });
+    if let Some(changed_at) = last_change {
+        if changed_at + REDIRECT_PERIOD > now {
+            return error_result(username_utils::USERNAME_CHANGE_COOLDOWN);
+        }
+    }
+    if username_utils::fold(&params.new_user_name) == old_user_name {
+        return error_result(username_utils::USERNAME_UNCHANGED);
+    }
+    // The account's own name must not count as a look-alike of the new one
+    username_utils::unindex_username(&old_user_name);
+    let new_user_name = match username_utils::validate(&params.new_user_name) {
+        Ok(new_user_name) => new_user_name,
+        Err(error) => {
+            username_utils::index_username(&old_user_name);
+            return error_result(&error);
+        }
+    };
+
+    KEY_STORE.with(|key_store| {
+        let mut key_store = key_store.borrow_mut();
+        if let Some(keys) = key_store.remove(&old_user_name) {
+            key_store.insert(new_user_name.clone(), keys);
+        }
+    });
+    USER_ID_STORE.with(|user_id_store| {
+        let mut user_id_store = user_id_store.borrow_mut();
+        user_id_store.remove(&old_user_name);
+        user_id_store.insert(new_user_name.clone(), user_id.clone());
+    });
+    // The old name stays indexed while the redirect holds it
+    username_utils::index_username(&old_user_name);
+    username_utils::index_username(&new_user_name);
+    directory_utils::unindex_user(&old_user_name);
+    directory_utils::index_user(&new_user_name);
+    profile_utils::rename_profile(&old_user_name, &new_user_name);
+    principal_utils::rename_user(&old_user_name, &new_user_name);
+    role_utils::rename_user(&old_user_name, &new_user_name);
+    totp_utils::rename_user(&old_user_name, &new_user_name);
+    recovery_utils::rename_user(&old_user_name, &new_user_name);
+    session_utils::rename_user_sessions(&old_user_name, &new_user_name);
+
+    REDIRECT_STORE.with(|redirect_store| {
+        let mut redirect_store = redirect_store.borrow_mut();
+        redirect_store.retain(|_, redirect| redirect.expires_at > now);
+        redirect_store.insert(old_user_name.clone(), Redirect {
+            user_id: user_id.clone(),
+            expires_at: now + REDIRECT_PERIOD
+        });
+    });
+    audit_utils::record(&new_user_name, SecurityEventKind::UsernameChanged, format!("{} -> {}", old_user_name, new_user_name));
+    USERNAME_HISTORY_STORE.with(|history_store| {
+        history_store.borrow_mut().entry(user_id).or_default().push(UsernameChange {
+            old_user_name: old_user_name.clone(),
+            new_user_name: new_user_name.clone(),
+            changed_at: now
+        });
+    });
+    cascade_utils::enqueue(CascadeTask::WalletRename {
+        old_user_name: old_user_name.clone(),
+        new_user_name: new_user_name.clone()
+    });
+    cascade_utils::enqueue(CascadeTask::ChatRename {
+        old_user_name,
+        new_user_name: new_user_name.clone()
+    });
+
+    id_utils::issue_tokens(new_user_name, claims.key_id, claims.sid, None, None)
+}
+
+pub fn get_username_history(token: String) -> UsernameHistoryResult {
+    match id_utils::verify_token(&token) {
+        Some(claims) => UsernameHistoryResult{
+            error: "".to_string(),
+            result: USERNAME_HISTORY_STORE.with(|history_store| {
+                history_store.borrow().get(&user_id(&claims.username)).cloned().unwrap_or_default()
+            })
+        },
+        None => UsernameHistoryResult{
+            error: "Invalid token".to_string(),
+            result: vec![]
+        }
+    }
+}
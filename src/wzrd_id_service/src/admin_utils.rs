@@ -0,0 +1,286 @@
+use candid::Deserialize;
+use ic_cdk::api::time;
+use ic_cdk::export::candid::CandidType;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use crate::audit_utils::{self, SecurityEventKind};
+use crate::id_utils::{self, KEY_STORE};
+use crate::role_utils::{self, Role};
+use crate::{account_utils, session_utils};
+
+const MAX_USER_PAGE: u32 = 100;
+
+#[derive(Clone, Copy, Debug, Deserialize, CandidType, PartialEq, Eq)]
+pub enum SuspensionKind {
+    Suspended,
+    Banned
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct Suspension {
+    pub kind: SuspensionKind,
+    pub reason: String,
+    pub created_at: u64,
+    pub expires_at: Option<u64>, // None lasts until lifted
+    pub created_by: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct UserInfo {
+    pub user_name: String,
+    pub user_id: String,
+    pub registered_at: u64,
+    pub last_login_at: Option<u64>,
+    pub passkey_count: u32,
+    pub roles: Vec<Role>,
+    pub suspension: Option<Suspension>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ListUsersParams {
+    pub token: Option<String>,
+    pub start_after: Option<String>,
+    pub limit: u32
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ListUsersResult {
+    pub error: String,
+    pub result: Vec<UserInfo>,
+    pub next: Option<String>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct UserParams {
+    pub token: Option<String>,
+    pub user_name: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct UserInfoResult {
+    pub error: String,
+    pub result: Option<UserInfo>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct SuspendUserParams {
+    pub token: Option<String>,
+    pub user_name: String,
+    pub kind: SuspensionKind,
+    pub reason: String,
+    pub expires_at: Option<u64>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct SuspendUserResult {
+    pub error: String,
+    pub result: bool
+}
+
+pub type SuspensionStore = BTreeMap<String, Suspension>; //(user_name => suspension)
+
+thread_local! {
+    pub static SUSPENSION_STORE: RefCell<SuspensionStore> = RefCell::default();
+}
+
+// Controllers call without a token, admins with theirs. Returns who is acting, or an error.
+fn authorize(token: &Option<String>) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) {
+        return Ok(caller.to_string());
+    }
+    match token.as_ref().and_then(|token| id_utils::verify_token(token)) {
+        Some(claims) if role_utils::has_role(&claims.username, Role::Admin) => Ok(claims.username),
+        _ => Err("Only controllers and admins can manage users".to_string())
+    }
+}
+
+pub fn delete_user(user_name: &str) {
+    SUSPENSION_STORE.with(|suspension_store| {
+        suspension_store.borrow_mut().remove(user_name);
+    });
+}
+
+// Suspensions past their expiry are dropped on the next lookup
+pub fn active_suspension(user_name: &str) -> Option<Suspension> {
+    let now = time();
+    SUSPENSION_STORE.with(|suspension_store| {
+        let mut suspension_store = suspension_store.borrow_mut();
+        let suspension = suspension_store.get(user_name)?.clone();
+        if suspension.expires_at.map_or(false, |expires_at| expires_at <= now) {
+            suspension_store.remove(user_name);
+            return None;
+        }
+        Some(suspension)
+    })
+}
+
+pub fn is_suspended(user_name: &str) -> bool {
+    active_suspension(user_name).is_some()
+}
+
+// The error shown to a suspended user trying to sign in
+pub fn suspension_error(user_name: &str) -> String {
+    match active_suspension(user_name) {
+        Some(suspension) => {
+            let state = match suspension.kind {
+                SuspensionKind::Suspended => "suspended",
+                SuspensionKind::Banned => "banned"
+            };
+            match suspension.expires_at {
+                Some(expires_at) => format!("Account {} until {}: {}", state, expires_at, suspension.reason),
+                None => format!("Account {}: {}", state, suspension.reason)
+            }
+        }
+        None => "".to_string()
+    }
+}
+
+fn user_info(user_name: &str) -> Option<UserInfo> {
+    let keys = id_utils::passkeys(user_name)?;
+    let (registered_at, last_login_at, passkey_count) = (
+        keys.iter().map(|key| key.created_at).min().unwrap_or(0),
+        keys.iter().filter_map(|key| key.last_used_at).max(),
+        keys.len() as u32
+    );
+    Some(UserInfo {
+        user_name: user_name.to_string(),
+        user_id: account_utils::user_id(user_name),
+        registered_at,
+        last_login_at,
+        passkey_count,
+        roles: role_utils::roles(user_name),
+        suspension: active_suspension(user_name)
+    })
+}
+
+// Pages through users in username order, pass the returned `next` as `start_after`
+pub fn list_users(params: ListUsersParams) -> ListUsersResult {
+    if let Err(error) = authorize(&params.token) {
+        return ListUsersResult {
+            error,
+            result: vec![],
+            next: None
+        };
+    }
+    let limit = params.limit.clamp(1, MAX_USER_PAGE) as usize;
+    let (user_names, has_more) = KEY_STORE.with(|key_store| {
+        let key_store = key_store.borrow();
+        let start = match &params.start_after {
+            Some(start_after) => Bound::Excluded(start_after.clone()),
+            None => Bound::Unbounded
+        };
+        let mut user_names = key_store.range((start, Bound::Unbounded)).map(|(user_name, _)| user_name);
+        let page: Vec<String> = user_names.by_ref().take(limit).collect();
+        (page, user_names.next().is_some())
+    });
+    let result: Vec<UserInfo> = user_names.iter().filter_map(|user_name| user_info(user_name)).collect();
+    ListUsersResult {
+        error: "".to_string(),
+        next: if has_more { user_names.last().cloned() } else { None },
+        result
+    }
+}
+
+pub fn get_user_info(params: UserParams) -> UserInfoResult {
+    if let Err(error) = authorize(&params.token) {
+        return UserInfoResult {
+            error,
+            result: None
+        };
+    }
+    match user_info(&params.user_name) {
+        Some(info) => UserInfoResult {
+            error: "".to_string(),
+            result: Some(info)
+        },
+        None => UserInfoResult {
+            error: "Username not registered".to_string(),
+            result: None
+        }
+    }
+}
+
+// Ends every session of the user. Chat and wallet check tokens locally, so access tokens
+// they already hold keep working there until they expire.
+pub fn suspend_user(params: SuspendUserParams) -> SuspendUserResult {
+    let created_by = match authorize(&params.token) {
+        Ok(created_by) => created_by,
+        Err(error) => return SuspendUserResult {
+            error,
+            result: false
+        }
+    };
+    if !id_utils::has_user(&params.user_name) {
+        return SuspendUserResult {
+            error: "Username not registered".to_string(),
+            result: false
+        };
+    }
+    if role_utils::has_role(&params.user_name, Role::Admin) && !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return SuspendUserResult {
+            error: "Only controllers can suspend admins".to_string(),
+            result: false
+        };
+    }
+    if params.reason.trim().is_empty() {
+        return SuspendUserResult {
+            error: "A reason is required".to_string(),
+            result: false
+        };
+    }
+    if params.expires_at.map_or(false, |expires_at| expires_at <= time()) {
+        return SuspendUserResult {
+            error: "Expiry is in the past".to_string(),
+            result: false
+        };
+    }
+    let kind = params.kind;
+    SUSPENSION_STORE.with(|suspension_store| {
+        suspension_store.borrow_mut().insert(params.user_name.clone(), Suspension {
+            kind,
+            reason: params.reason.clone(),
+            created_at: time(),
+            expires_at: params.expires_at,
+            created_by
+        });
+    });
+    session_utils::end_user_sessions(&params.user_name);
+    let event_kind = match kind {
+        SuspensionKind::Suspended => SecurityEventKind::AccountSuspended,
+        SuspensionKind::Banned => SecurityEventKind::AccountBanned
+    };
+    audit_utils::record(&params.user_name, event_kind, params.reason);
+    SuspendUserResult {
+        error: "".to_string(),
+        result: true
+    }
+}
+
+pub fn unsuspend_user(params: UserParams) -> SuspendUserResult {
+    if let Err(error) = authorize(&params.token) {
+        return SuspendUserResult {
+            error,
+            result: false
+        };
+    }
+    if role_utils::has_role(&params.user_name, Role::Admin) && !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return SuspendUserResult {
+            error: "Only controllers can unsuspend admins".to_string(),
+            result: false
+        };
+    }
+    let removed = SUSPENSION_STORE.with(|suspension_store| suspension_store.borrow_mut().remove(&params.user_name));
+    if removed.is_none() {
+        return SuspendUserResult {
+            error: "User is not suspended".to_string(),
+            result: false
+        };
+    }
+    audit_utils::record(&params.user_name, SecurityEventKind::SuspensionLifted, "".to_string());
+    SuspendUserResult {
+        error: "".to_string(),
+        result: true
+    }
+}
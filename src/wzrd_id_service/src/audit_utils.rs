@@ -0,0 +1,242 @@
+use candid::{Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk::export::candid::CandidType;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use ic_stable_structures::StableBTreeMap;
+use crate::{account_utils, id_utils};
+use crate::memory_utils::{self, Candid, Memory};
+
+const MAX_EVENTS_PER_USER: usize = 200;
+const MAX_EVENTS: u64 = 100_000;
+const MAX_EXPORT_PAGE: u32 = 500;
+// Failed sign-ins within this long of the first one are counted on a single event, so that
+// anyone naming the user can't push the rest of their history out
+const LOGIN_FAILURE_WINDOW: u64 = 3_600_000_000_000; // 1 hour
+
+#[derive(Clone, Debug, Deserialize, CandidType, PartialEq, Eq)]
+pub enum SecurityEventKind {
+    Registered,
+    LoggedIn,
+    LoginFailed,
+    LoggedOut,
+    TokenRefreshed,
+    SessionRevoked,
+    ProfileUpdated,
+    ContactVerified,
+    PasskeyAdded,
+    PasskeyRemoved,
+    PrincipalLinked,
+    PrincipalUnlinked,
+    UsernameChanged,
+    RolesChanged,
+    ClientAuthorized,
+    AccountSuspended,
+    AccountBanned,
+    SuspensionLifted,
+    TotpEnabled,
+    TotpDisabled,
+    BackupCodeUsed,
+    GuardiansChanged,
+    GuardianRemoved, // a guardian deleted their account
+    RecoveryRequested,
+    RecoveryApproved,
+    RecoveryCancelled,
+    AccountRecovered,
+    AccountDeleted
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct SecurityEvent {
+    pub id: u64,
+    pub user_name: String, // name at the time of the event
+    pub kind: SecurityEventKind,
+    pub detail: String,
+    pub caller: Principal,
+    pub timestamp: u64,
+    pub count: Option<u32> // failed sign-ins merged into a `LoginFailed` event, the latest one's detail is kept
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct SecurityEventsResult {
+    pub error: String,
+    pub result: Vec<SecurityEvent>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ExportSecurityEventsParams {
+    pub start_after: Option<u64>,
+    pub limit: u32
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ExportSecurityEventsResult {
+    pub error: String,
+    pub result: Vec<SecurityEvent>,
+    pub next: Option<u64> // pass as `start_after` to get the next page
+}
+
+pub type AuditLog = StableBTreeMap<u64, Candid<SecurityEvent>, Memory>; //(event id => event)
+pub type UserEventIndex = BTreeMap<String, VecDeque<u64>>; //(user id => event ids, oldest first)
+
+thread_local! {
+    pub static AUDIT_LOG: RefCell<AuditLog> = RefCell::new(StableBTreeMap::init(memory_utils::memory(memory_utils::AUDIT_LOG)));
+    pub static USER_EVENT_INDEX: RefCell<UserEventIndex> = RefCell::default();
+    pub static NEXT_EVENT_ID: Cell<u64> = Cell::new(0);
+}
+
+// Events are only appended; the oldest ones are dropped once a user or the whole log is full.
+// Indexed by user id so that the history survives username changes.
+pub fn record(user_name: &str, kind: SecurityEventKind, detail: String) {
+    let id = NEXT_EVENT_ID.with(|next_id| {
+        let id = next_id.get();
+        next_id.set(id + 1);
+        id
+    });
+    let user_id = account_utils::user_id(user_name);
+    AUDIT_LOG.with(|audit_log| {
+        let mut audit_log = audit_log.borrow_mut();
+        audit_log.insert(id, Candid(SecurityEvent {
+            id,
+            user_name: user_name.to_string(),
+            kind,
+            detail,
+            caller: ic_cdk::caller(),
+            timestamp: time(),
+            count: None
+        }));
+        // Names without an account have no history to add to
+        if !user_id.is_empty() {
+            USER_EVENT_INDEX.with(|event_index| {
+                let mut event_index = event_index.borrow_mut();
+                let user_events = event_index.entry(user_id).or_default();
+                user_events.push_back(id);
+                while user_events.len() > MAX_EVENTS_PER_USER {
+                    if let Some(oldest) = user_events.pop_front() {
+                        audit_log.remove(&oldest);
+                    }
+                }
+            });
+        }
+        while audit_log.len() > MAX_EVENTS {
+            let oldest = match audit_log.first_key_value() {
+                Some((oldest, _)) => oldest,
+                None => break
+            };
+            audit_log.remove(&oldest);
+        }
+    });
+}
+
+// Same as `record`, for names that may not belong to an account
+pub fn record_if_registered(user_name: &str, kind: SecurityEventKind, detail: String) {
+    if id_utils::has_user(&user_name.to_string()) {
+        record(user_name, kind, detail);
+    }
+}
+
+fn merges_login_failure(event: &SecurityEvent, now: u64) -> bool {
+    event.kind == SecurityEventKind::LoginFailed && event.timestamp + LOGIN_FAILURE_WINDOW > now
+}
+
+// At most one `LoginFailed` event per user and window, see `LOGIN_FAILURE_WINDOW`
+pub fn record_login_failure(user_name: &str, detail: String) {
+    if !id_utils::has_user(&user_name.to_string()) {
+        return;
+    }
+    let now = time();
+    let user_id = account_utils::user_id(user_name);
+    let event_ids = USER_EVENT_INDEX.with(|event_index| event_index.borrow().get(&user_id).cloned().unwrap_or_default());
+    let merged = AUDIT_LOG.with(|audit_log| {
+        let mut audit_log = audit_log.borrow_mut();
+        let event = event_ids.iter().rev()
+            .filter_map(|id| audit_log.get(id).map(|event| event.0))
+            .take_while(|event| event.timestamp + LOGIN_FAILURE_WINDOW > now)
+            .find(|event| merges_login_failure(event, now));
+        match event {
+            Some(mut event) => {
+                event.count = Some(event.count.unwrap_or(1) + 1);
+                event.detail = detail.clone();
+                audit_log.insert(event.id, Candid(event));
+                true
+            }
+            None => false
+        }
+    });
+    if !merged {
+        record(user_name, SecurityEventKind::LoginFailed, detail);
+    }
+}
+
+pub fn forget_user(user_id: &str) {
+    USER_EVENT_INDEX.with(|event_index| event_index.borrow_mut().remove(user_id));
+}
+
+pub fn get_security_events(token: String) -> SecurityEventsResult {
+    let claims = match id_utils::verify_token(&token) {
+        Some(claims) => claims,
+        None => return SecurityEventsResult{
+            error: "Invalid token".to_string(),
+            result: vec![]
+        }
+    };
+    let user_id = account_utils::user_id(&claims.username);
+    let event_ids = USER_EVENT_INDEX.with(|event_index| event_index.borrow().get(&user_id).cloned().unwrap_or_default());
+    AUDIT_LOG.with(|audit_log| {
+        let audit_log = audit_log.borrow();
+        SecurityEventsResult{
+            error: "".to_string(),
+            result: event_ids.iter().rev().filter_map(|id| audit_log.get(id).map(|event| event.0)).collect()
+        }
+    })
+}
+
+pub fn export_security_events(params: ExportSecurityEventsParams) -> ExportSecurityEventsResult {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return ExportSecurityEventsResult{
+            error: "Only controllers can export security events".to_string(),
+            result: vec![],
+            next: None
+        };
+    }
+    let limit = params.limit.clamp(1, MAX_EXPORT_PAGE) as usize;
+    let start = params.start_after.map(|id| id + 1).unwrap_or(0);
+    AUDIT_LOG.with(|audit_log| {
+        let audit_log = audit_log.borrow();
+        let result: Vec<SecurityEvent> = audit_log.range(start..).take(limit).map(|(_, event)| event.0).collect();
+        let next = match result.last() {
+            Some(last) if audit_log.range(last.id + 1..).next().is_some() => Some(last.id),
+            _ => None
+        };
+        ExportSecurityEventsResult{
+            error: "".to_string(),
+            result,
+            next
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: SecurityEventKind, timestamp: u64) -> SecurityEvent {
+        SecurityEvent {
+            id: 0,
+            user_name: "alice".to_string(),
+            kind,
+            detail: "".to_string(),
+            caller: Principal::anonymous(),
+            timestamp,
+            count: None
+        }
+    }
+
+    #[test]
+    fn merges_login_failures_within_the_window() {
+        let now = 10 * LOGIN_FAILURE_WINDOW;
+        assert!(merges_login_failure(&event(SecurityEventKind::LoginFailed, now - LOGIN_FAILURE_WINDOW + 1), now));
+        assert!(!merges_login_failure(&event(SecurityEventKind::LoginFailed, now - LOGIN_FAILURE_WINDOW), now));
+        assert!(!merges_login_failure(&event(SecurityEventKind::LoggedIn, now), now));
+    }
+}
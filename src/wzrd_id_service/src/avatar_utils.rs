@@ -0,0 +1,223 @@
+use candid::Deserialize;
+use ic_cdk::api::time;
+use ic_cdk::export::candid::CandidType;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use ic_stable_structures::StableBTreeMap;
+use crate::audit_utils::{self, SecurityEventKind};
+use crate::memory_utils::{self, Candid, Memory};
+use crate::{admin_utils, http_utils, id_utils, token_utils};
+
+const MAX_AVATAR_SIZE: usize = 1_048_576; // 1 MiB
+const MAX_CHUNK_SIZE: usize = 262_144; // 256 KiB, well under the message size limit
+const UPLOAD_TTL: u64 = 600_000_000_000; // 10 minutes to finish an upload
+const ALLOWED_MIME_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/webp", "image/gif"];
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct Avatar {
+    pub mime_type: String,
+    pub data: Vec<u8>,
+    pub updated_at: u64
+}
+
+#[derive(Clone, Debug)]
+struct PendingUpload {
+    upload_id: String,
+    mime_type: String,
+    total_size: usize,
+    data: Vec<u8>,
+    started_at: u64
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct BeginAvatarUploadParams {
+    pub token: String,
+    pub mime_type: String,
+    pub total_size: u32
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct AvatarChunkParams {
+    pub token: String,
+    pub upload_id: String,
+    pub chunk: Vec<u8>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct CommitAvatarParams {
+    pub token: String,
+    pub upload_id: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct AvatarResult {
+    pub error: String,
+    pub result: String
+}
+
+pub type AvatarStore = StableBTreeMap<String, Candid<Avatar>, Memory>; //(user_name => avatar)
+type UploadStore = BTreeMap<String, PendingUpload>; //(user_name => upload in progress)
+
+thread_local! {
+    pub static AVATAR_STORE: RefCell<AvatarStore> = RefCell::new(StableBTreeMap::init(memory_utils::memory(memory_utils::AVATARS)));
+    static UPLOAD_STORE: RefCell<UploadStore> = RefCell::default();
+}
+
+fn avatar_error(error: &str) -> AvatarResult {
+    AvatarResult {
+        error: error.to_string(),
+        result: "".to_string()
+    }
+}
+
+// Checks the leading bytes, so that the declared type is the one browsers will sniff
+fn matches_mime_type(mime_type: &str, data: &[u8]) -> bool {
+    match mime_type {
+        "image/png" => data.starts_with(b"\x89PNG\r\n\x1a\n"),
+        "image/jpeg" => data.starts_with(&[0xff, 0xd8, 0xff]),
+        "image/gif" => data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a"),
+        "image/webp" => data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP",
+        _ => false
+    }
+}
+
+// Served by `http_request`, the version makes clients refetch after a change
+pub fn avatar_url(user_name: &str) -> Option<String> {
+    let avatar = get_avatar(user_name)?;
+    Some(format!("{}/avatar/{}?v={}", http_utils::raw_origin(), user_name, avatar.updated_at))
+}
+
+// Avatars are public like display names, whatever the profile's visibility, as anyone holding
+// the URL can fetch them. Those of suspended accounts are withheld, as in the directory.
+pub fn get_avatar(user_name: &str) -> Option<Avatar> {
+    if admin_utils::is_suspended(user_name) {
+        return None;
+    }
+    AVATAR_STORE.with(|avatar_store| avatar_store.borrow().get(&user_name.to_string()).map(|avatar| avatar.0))
+}
+
+// Starts an upload, replacing any unfinished one of the same user. Returns the upload id.
+pub fn begin_avatar_upload(params: BeginAvatarUploadParams) -> AvatarResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return avatar_error("Invalid token")
+    };
+    if !ALLOWED_MIME_TYPES.contains(&params.mime_type.as_str()) {
+        return avatar_error("Avatar must be a PNG, JPEG, WebP or GIF image");
+    }
+    let total_size = params.total_size as usize;
+    if total_size == 0 || total_size > MAX_AVATAR_SIZE {
+        return avatar_error("Avatar must be at most 1 MiB");
+    }
+    let upload_id = token_utils::new_token_id();
+    UPLOAD_STORE.with(|upload_store| {
+        let now = time();
+        let mut upload_store = upload_store.borrow_mut();
+        upload_store.retain(|_, upload| upload.started_at + UPLOAD_TTL > now);
+        upload_store.insert(claims.username, PendingUpload {
+            upload_id: upload_id.clone(),
+            mime_type: params.mime_type,
+            total_size,
+            data: Vec::with_capacity(total_size),
+            started_at: now
+        });
+    });
+    AvatarResult {
+        error: "".to_string(),
+        result: upload_id
+    }
+}
+
+// Chunks are appended in the order they arrive
+pub fn upload_avatar_chunk(params: AvatarChunkParams) -> AvatarResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return avatar_error("Invalid token")
+    };
+    if params.chunk.len() > MAX_CHUNK_SIZE {
+        return avatar_error("Chunk must be at most 256 KiB");
+    }
+    UPLOAD_STORE.with(|upload_store| {
+        let mut upload_store = upload_store.borrow_mut();
+        let upload = match upload_store.get_mut(&claims.username) {
+            Some(upload) if upload.upload_id == params.upload_id && upload.started_at + UPLOAD_TTL > time() => upload,
+            _ => return avatar_error("Upload doesn't exist or has expired")
+        };
+        if upload.data.len() + params.chunk.len() > upload.total_size {
+            return avatar_error("Chunk exceeds the declared size");
+        }
+        upload.data.extend_from_slice(&params.chunk);
+        AvatarResult {
+            error: "".to_string(),
+            result: upload.data.len().to_string()
+        }
+    })
+}
+
+// Publishes a complete upload. Returns the new avatar URL.
+pub fn commit_avatar(params: CommitAvatarParams) -> AvatarResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return avatar_error("Invalid token")
+    };
+    let upload = UPLOAD_STORE.with(|upload_store| {
+        let mut upload_store = upload_store.borrow_mut();
+        match upload_store.get(&claims.username) {
+            Some(upload) if upload.upload_id == params.upload_id => upload_store.remove(&claims.username),
+            _ => None
+        }
+    });
+    let upload = match upload {
+        Some(upload) if upload.started_at + UPLOAD_TTL > time() => upload,
+        _ => return avatar_error("Upload doesn't exist or has expired")
+    };
+    if upload.data.len() != upload.total_size {
+        return avatar_error("Upload is incomplete");
+    }
+    if !matches_mime_type(&upload.mime_type, &upload.data) {
+        return avatar_error("Image data doesn't match its type");
+    }
+    AVATAR_STORE.with(|avatar_store| {
+        avatar_store.borrow_mut().insert(claims.username.clone(), Candid(Avatar {
+            mime_type: upload.mime_type,
+            data: upload.data,
+            updated_at: time()
+        }));
+    });
+    audit_utils::record(&claims.username, SecurityEventKind::ProfileUpdated, "avatar".to_string());
+    AvatarResult {
+        error: "".to_string(),
+        result: avatar_url(&claims.username).unwrap_or_default()
+    }
+}
+
+pub fn remove_avatar(token: String) -> AvatarResult {
+    let claims = match id_utils::verify_token(&token) {
+        Some(claims) => claims,
+        None => return avatar_error("Invalid token")
+    };
+    let removed = AVATAR_STORE.with(|avatar_store| avatar_store.borrow_mut().remove(&claims.username));
+    if removed.is_none() {
+        return avatar_error("No avatar set");
+    }
+    audit_utils::record(&claims.username, SecurityEventKind::ProfileUpdated, "avatar".to_string());
+    AvatarResult {
+        error: "".to_string(),
+        result: "".to_string()
+    }
+}
+
+pub fn rename_avatar(old_user_name: &str, new_user_name: &str) {
+    AVATAR_STORE.with(|avatar_store| {
+        let mut avatar_store = avatar_store.borrow_mut();
+        if let Some(avatar) = avatar_store.remove(&old_user_name.to_string()) {
+            avatar_store.insert(new_user_name.to_string(), avatar);
+        }
+    });
+    UPLOAD_STORE.with(|upload_store| upload_store.borrow_mut().remove(old_user_name));
+}
+
+pub fn delete_avatar(user_name: &str) {
+    AVATAR_STORE.with(|avatar_store| avatar_store.borrow_mut().remove(&user_name.to_string()));
+    UPLOAD_STORE.with(|upload_store| upload_store.borrow_mut().remove(user_name));
+}
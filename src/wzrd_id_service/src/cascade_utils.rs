@@ -0,0 +1,323 @@
+use candid::{Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk::export::candid::CandidType;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use crate::token_utils;
+
+const CHAT_SERVICE_CANISTER: &str = "ok26r-zyaaa-aaaal-adtzq-cai";
+const WALLET_SERVICE_CANISTER: &str = "oy4ji-viaaa-aaaal-adt2q-cai";
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const BASE_BACKOFF: u64 = 60_000_000_000; // 1 minute, doubled on every failure
+const MAX_BACKOFF: u64 = 86_400_000_000_000; // 1 day
+// Tasks are never dropped, as the other canister would be left out of step for good. Past this
+// many attempts they keep being retried daily, and are flagged in the log every time.
+const STUCK_ATTEMPTS: u32 = 30;
+// As returned by the wallet service when the new username already has a wallet
+const WALLET_RENAME_CONFLICT: &str = "New username already has a wallet";
+
+// Changes that other canisters have to apply after the ID service committed them
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub enum CascadeTask {
+    ChatDelete { user_name: String },
+    ChatRename { old_user_name: String, new_user_name: String },
+    WalletRename { old_user_name: String, new_user_name: String }
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct PendingTask {
+    pub task: CascadeTask,
+    pub attempts: u32,
+    pub next_attempt_at: u64,
+    pub last_error: Option<String>,
+    pub conflict: Option<String> // retrying can't help, waits for a controller's `RetryCascadeTask`
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct DeleteWalletResult {
+    pub error: String,
+    pub result: bool
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RenameWalletResult {
+    pub error: String,
+    pub result: bool
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct CascadeTaskInfo {
+    pub task_id: String,
+    pub pending: PendingTask
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RetryTaskResult {
+    pub error: String,
+    pub result: bool
+}
+
+#[derive(Debug)]
+enum TaskError {
+    Transient(String),
+    Conflict(String)
+}
+
+pub type TaskQueue = BTreeMap<String, PendingTask>; //(task id => pending task)
+
+thread_local! {
+    pub static TASK_QUEUE: RefCell<TaskQueue> = RefCell::default();
+}
+
+fn backoff(attempts: u32) -> u64 {
+    BASE_BACKOFF.saturating_mul(1 << attempts.min(20)).min(MAX_BACKOFF)
+}
+
+fn is_due(pending: &PendingTask, now: u64) -> bool {
+    pending.conflict.is_none() && pending.next_attempt_at <= now
+}
+
+// Pushes the task back before it runs, so that an overlapping run doesn't repeat it
+fn schedule_attempt(pending: &mut PendingTask, now: u64) {
+    pending.attempts += 1;
+    pending.next_attempt_at = now + backoff(pending.attempts);
+}
+
+fn record_failure(pending: &mut PendingTask, error: TaskError) {
+    match error {
+        TaskError::Transient(error) => pending.last_error = Some(error),
+        TaskError::Conflict(error) => {
+            pending.last_error = Some(error.clone());
+            pending.conflict = Some(error);
+        }
+    }
+}
+
+fn mentions(task: &CascadeTask, user_name: &str) -> bool {
+    match task {
+        CascadeTask::ChatDelete { user_name: deleted } => deleted == user_name,
+        CascadeTask::ChatRename { old_user_name, new_user_name } | CascadeTask::WalletRename { old_user_name, new_user_name } => {
+            old_user_name == user_name || new_user_name == user_name
+        }
+    }
+}
+
+// A name stays taken until every change involving it reached the other canisters, so that a
+// newcomer never inherits a wallet or chats that haven't moved or been deleted yet
+pub fn holds_name(user_name: &str) -> bool {
+    TASK_QUEUE.with(|task_queue| task_queue.borrow().values().any(|pending| mentions(&pending.task, user_name)))
+}
+
+// Lets the wallet service hold off creating a wallet that the rename would collide with
+pub fn is_rename_pending(user_name: String) -> bool {
+    if ic_cdk::caller() != Principal::from_text(WALLET_SERVICE_CANISTER).unwrap() {
+        return false;
+    }
+    TASK_QUEUE.with(|task_queue| {
+        task_queue.borrow().values().any(|pending| {
+            matches!(pending.task, CascadeTask::WalletRename { .. }) && mentions(&pending.task, &user_name)
+        })
+    })
+}
+
+pub fn start_retry_timer() {
+    ic_cdk_timers::set_timer_interval(RETRY_INTERVAL, || ic_cdk::spawn(process_queue()));
+}
+
+pub fn enqueue(task: CascadeTask) {
+    TASK_QUEUE.with(|task_queue| {
+        task_queue.borrow_mut().insert(token_utils::new_token_id(), PendingTask {
+            task,
+            attempts: 0,
+            next_attempt_at: time(),
+            last_error: None,
+            conflict: None
+        });
+    });
+    ic_cdk::spawn(process_queue());
+}
+
+async fn run(task: &CascadeTask) -> Result<(), TaskError> {
+    match task {
+        CascadeTask::ChatDelete { user_name } => {
+            let res = ic_cdk::call::<(String,), (bool,)>(Principal::from_text(CHAT_SERVICE_CANISTER).unwrap(), "l.DeleteUser", (user_name.clone(),)).await;
+            match res {
+                Ok((true,)) => Ok(()),
+                Ok((false,)) => Err(TaskError::Transient("Rejected by chat service".to_string())),
+                Err((_, error)) => Err(TaskError::Transient(error))
+            }
+        }
+        CascadeTask::ChatRename { old_user_name, new_user_name } => {
+            let res = ic_cdk::call::<(String, String), (bool,)>(Principal::from_text(CHAT_SERVICE_CANISTER).unwrap(), "m.RenameUser", (old_user_name.clone(), new_user_name.clone())).await;
+            match res {
+                Ok((true,)) => Ok(()),
+                Ok((false,)) => Err(TaskError::Transient("Rejected by chat service".to_string())),
+                Err((_, error)) => Err(TaskError::Transient(error))
+            }
+        }
+        CascadeTask::WalletRename { old_user_name, new_user_name } => {
+            let res = ic_cdk::call::<(String, String), (RenameWalletResult,)>(Principal::from_text(WALLET_SERVICE_CANISTER).unwrap(), "Rename_User_Wallet", (old_user_name.clone(), new_user_name.clone())).await;
+            match res {
+                Ok((result,)) if result.result => Ok(()),
+                Ok((result,)) if result.error == WALLET_RENAME_CONFLICT => Err(TaskError::Conflict(result.error)),
+                Ok((result,)) => Err(TaskError::Transient(result.error)),
+                Err((_, error)) => Err(TaskError::Transient(error))
+            }
+        }
+    }
+}
+
+pub async fn process_queue() {
+    let now = time();
+    let due: Vec<(String, PendingTask)> = TASK_QUEUE.with(|task_queue| {
+        task_queue.borrow_mut().iter_mut()
+            .filter(|(_, pending)| is_due(pending, now))
+            .map(|(task_id, pending)| {
+                schedule_attempt(pending, now);
+                (task_id.clone(), pending.clone())
+            })
+            .collect()
+    });
+    for (task_id, pending) in due {
+        let error = match run(&pending.task).await {
+            Ok(()) => {
+                TASK_QUEUE.with(|task_queue| task_queue.borrow_mut().remove(&task_id));
+                continue;
+            }
+            Err(error) => error
+        };
+        match &error {
+            TaskError::Conflict(error) => ic_cdk::print(format!("Conflict on {:?}, waiting for a controller: {}", pending.task, error)),
+            TaskError::Transient(error) if pending.attempts >= STUCK_ATTEMPTS => {
+                ic_cdk::print(format!("Stuck on {:?} after {} attempts: {}", pending.task, pending.attempts, error))
+            }
+            TaskError::Transient(error) => ic_cdk::print(format!("Retrying {:?} later: {}", pending.task, error))
+        }
+        TASK_QUEUE.with(|task_queue| {
+            if let Some(pending) = task_queue.borrow_mut().get_mut(&task_id) {
+                record_failure(pending, error);
+            }
+        });
+    }
+}
+
+pub fn get_cascade_tasks() -> Vec<CascadeTaskInfo> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return vec![];
+    }
+    TASK_QUEUE.with(|task_queue| {
+        task_queue.borrow().iter().map(|(task_id, pending)| CascadeTaskInfo {
+            task_id: task_id.clone(),
+            pending: pending.clone()
+        }).collect()
+    })
+}
+
+// For a task blocked by a conflict, once a controller resolved it on the other canister
+pub fn retry_cascade_task(task_id: String) -> RetryTaskResult {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return RetryTaskResult {
+            error: "Only controllers can retry tasks".to_string(),
+            result: false
+        };
+    }
+    let found = TASK_QUEUE.with(|task_queue| {
+        match task_queue.borrow_mut().get_mut(&task_id) {
+            Some(pending) => {
+                pending.conflict = None;
+                pending.next_attempt_at = time();
+                true
+            }
+            None => false
+        }
+    });
+    if !found {
+        return RetryTaskResult {
+            error: "No such task".to_string(),
+            result: false
+        };
+    }
+    ic_cdk::spawn(process_queue());
+    RetryTaskResult {
+        error: "".to_string(),
+        result: true
+    }
+}
+
+// The wallet refuses while any balance is non-zero, so this runs before anything is deleted
+pub async fn delete_wallet(user_name: String) -> String {
+    let res = ic_cdk::call::<(String,), (DeleteWalletResult,)>(Principal::from_text(WALLET_SERVICE_CANISTER).unwrap(), "Delete_User_Wallet", (user_name,)).await;
+    match res {
+        Ok((result,)) if result.result => "".to_string(),
+        Ok((result,)) => result.error,
+        Err(_) => "Can't access wallet service".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wallet_rename() -> PendingTask {
+        PendingTask {
+            task: CascadeTask::WalletRename {
+                old_user_name: "alice".to_string(),
+                new_user_name: "alicia".to_string()
+            },
+            attempts: 0,
+            next_attempt_at: 0,
+            last_error: None,
+            conflict: None
+        }
+    }
+
+    #[test]
+    fn backs_off_up_to_a_day() {
+        let mut pending = wallet_rename();
+        schedule_attempt(&mut pending, 0);
+        assert_eq!(pending.next_attempt_at, 2 * BASE_BACKOFF);
+        assert!(!is_due(&pending, BASE_BACKOFF));
+        assert!(is_due(&pending, 2 * BASE_BACKOFF));
+        schedule_attempt(&mut pending, 0);
+        assert_eq!(pending.next_attempt_at, 4 * BASE_BACKOFF);
+        for _ in 0..100 {
+            schedule_attempt(&mut pending, 0);
+        }
+        assert_eq!(pending.next_attempt_at, MAX_BACKOFF);
+    }
+
+    #[test]
+    fn keeps_retrying_transient_failures() {
+        let mut pending = wallet_rename();
+        for attempt in 1..=STUCK_ATTEMPTS * 2 {
+            let now = pending.next_attempt_at;
+            assert!(is_due(&pending, now));
+            schedule_attempt(&mut pending, now);
+            record_failure(&mut pending, TaskError::Transient("Canister unreachable".to_string()));
+            assert_eq!(pending.attempts, attempt);
+        }
+        assert_eq!(pending.last_error.as_deref(), Some("Canister unreachable"));
+        assert!(pending.conflict.is_none());
+    }
+
+    #[test]
+    fn holds_conflicts_until_retried() {
+        let mut pending = wallet_rename();
+        schedule_attempt(&mut pending, 0);
+        record_failure(&mut pending, TaskError::Conflict(WALLET_RENAME_CONFLICT.to_string()));
+        assert!(!is_due(&pending, u64::MAX));
+        pending.conflict = None;
+        assert!(is_due(&pending, pending.next_attempt_at));
+    }
+
+    #[test]
+    fn tasks_mention_both_names() {
+        let pending = wallet_rename();
+        assert!(mentions(&pending.task, "alice"));
+        assert!(mentions(&pending.task, "alicia"));
+        assert!(!mentions(&pending.task, "bob"));
+        assert!(mentions(&CascadeTask::ChatDelete { user_name: "bob".to_string() }, "bob"));
+    }
+}
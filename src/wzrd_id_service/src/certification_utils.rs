@@ -0,0 +1,140 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ciborium::value::Value;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// Label the HTTP gateway looks up certified response bodies under
+const HTTP_ASSETS_LABEL: &[u8] = b"http_assets";
+const CBOR_SELF_DESCRIBED_TAG: u64 = 55799;
+
+// The IC's hash tree, see https://internetcomputer.org/docs/current/references/ic-interface-spec#certificate
+enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>)
+}
+
+pub type CertifiedBodyStore = BTreeMap<String, Vec<u8>>; //(path => body)
+
+thread_local! {
+    static CERTIFIED_BODY_STORE: RefCell<CertifiedBodyStore> = RefCell::default();
+}
+
+fn domain_hash(domain: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([domain.len() as u8]);
+    hasher.update(domain.as_bytes());
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+impl HashTree {
+    fn digest(&self) -> [u8; 32] {
+        match self {
+            HashTree::Empty => domain_hash("ic-hashtree-empty", &[]),
+            HashTree::Fork(left, right) => domain_hash("ic-hashtree-fork", &[&left.digest(), &right.digest()]),
+            HashTree::Labeled(label, tree) => domain_hash("ic-hashtree-labeled", &[label, &tree.digest()]),
+            HashTree::Leaf(value) => domain_hash("ic-hashtree-leaf", &[value])
+        }
+    }
+
+    fn to_cbor(&self) -> Value {
+        let node = |tag: u64, mut fields: Vec<Value>| {
+            fields.insert(0, Value::Integer(tag.into()));
+            Value::Array(fields)
+        };
+        match self {
+            HashTree::Empty => node(0, vec![]),
+            HashTree::Fork(left, right) => node(1, vec![left.to_cbor(), right.to_cbor()]),
+            HashTree::Labeled(label, tree) => node(2, vec![Value::Bytes(label.clone()), tree.to_cbor()]),
+            HashTree::Leaf(value) => node(3, vec![Value::Bytes(value.clone())])
+        }
+    }
+}
+
+// Forks keep the labels in order, which is how the gateway finds a path
+fn fork_all(mut nodes: Vec<HashTree>) -> HashTree {
+    match nodes.len() {
+        0 => HashTree::Empty,
+        1 => nodes.remove(0),
+        len => {
+            let right = nodes.split_off(len / 2);
+            HashTree::Fork(Box::new(fork_all(nodes)), Box::new(fork_all(right)))
+        }
+    }
+}
+
+fn http_assets_tree(bodies: &CertifiedBodyStore) -> HashTree {
+    let assets = bodies.iter().map(|(path, body)| {
+        HashTree::Labeled(path.as_bytes().to_vec(), Box::new(HashTree::Leaf(Sha256::digest(body).to_vec())))
+    }).collect();
+    HashTree::Labeled(HTTP_ASSETS_LABEL.to_vec(), Box::new(fork_all(assets)))
+}
+
+// Replaces every certified body, must run in an update call (or init and post_upgrade)
+pub fn certify(bodies: CertifiedBodyStore) {
+    ic_cdk::api::set_certified_data(&http_assets_tree(&bodies).digest());
+    CERTIFIED_BODY_STORE.with(|body_store| *body_store.borrow_mut() = bodies);
+}
+
+// The body certified for `path` and the value of the IC-Certificate header proving it. The
+// certificate is only available in query calls, which is how the gateway fetches these paths.
+pub fn certified_response(path: &str) -> Option<(Vec<u8>, Option<String>)> {
+    CERTIFIED_BODY_STORE.with(|body_store| {
+        let body_store = body_store.borrow();
+        let body = body_store.get(path)?.clone();
+        let header = ic_cdk::api::data_certificate().and_then(|certificate| {
+            let mut tree = Vec::new();
+            let cbor = Value::Tag(CBOR_SELF_DESCRIBED_TAG, Box::new(http_assets_tree(&body_store).to_cbor()));
+            ciborium::ser::into_writer(&cbor, &mut tree).ok()?;
+            Some(format!("certificate=:{}:, tree=:{}:", STANDARD.encode(certificate), STANDARD.encode(tree)))
+        });
+        Some((body, header))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf_paths(tree: &HashTree, paths: &mut Vec<Vec<u8>>) {
+        match tree {
+            HashTree::Fork(left, right) => {
+                leaf_paths(left, paths);
+                leaf_paths(right, paths);
+            }
+            HashTree::Labeled(label, tree) => {
+                paths.push(label.clone());
+                leaf_paths(tree, paths);
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn keeps_paths_in_order() {
+        let bodies: CertifiedBodyStore = ["/c", "/a", "/b"].iter().map(|path| (path.to_string(), vec![])).collect();
+        let mut paths = vec![];
+        leaf_paths(&http_assets_tree(&bodies), &mut paths);
+        assert_eq!(paths, vec![b"http_assets".to_vec(), b"/a".to_vec(), b"/b".to_vec(), b"/c".to_vec()]);
+    }
+
+    #[test]
+    fn hashes_with_domain_separators() {
+        let leaf = HashTree::Leaf(b"body".to_vec());
+        let mut expected = Sha256::new();
+        expected.update(b"\x10ic-hashtree-leaf");
+        expected.update(b"body");
+        assert_eq!(leaf.digest(), <[u8; 32]>::from(expected.finalize()));
+        let labeled = HashTree::Labeled(b"path".to_vec(), Box::new(leaf));
+        let mut expected = Sha256::new();
+        expected.update(b"\x13ic-hashtree-labeled");
+        expected.update(b"path");
+        expected.update(HashTree::Leaf(b"body".to_vec()).digest());
+        assert_eq!(labeled.digest(), <[u8; 32]>::from(expected.finalize()));
+    }
+}
@@ -0,0 +1,84 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use candid::{Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk::export::candid::CandidType;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const CHALLENGE_TTL: u64 = 300_000_000_000; // 5 minutes
+
+#[derive(Clone, Debug, Deserialize, CandidType, PartialEq, Eq)]
+pub enum Ceremony {
+    Register,
+    Authenticate,
+    DeleteAccount,
+    Authorize,
+    AddPasskey,
+    RemovePasskey,
+    Recover,
+    EnrollTotp
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct PendingChallenge {
+    pub user_name: String,
+    pub ceremony: Ceremony,
+    pub expires_at: u64
+}
+
+type ChallengeStore = BTreeMap<String, PendingChallenge>; //(challenge => pending ceremony)
+
+thread_local! {
+    pub static CHALLENGE_STORE: RefCell<ChallengeStore> = RefCell::default();
+}
+
+// Issues a random base64url challenge bound to the user and ceremony
+pub async fn issue_challenge(user_name: String, ceremony: Ceremony) -> Result<String, String> {
+    let res = ic_cdk::call::<(), (Vec<u8>,)>(Principal::management_canister(), "raw_rand", ()).await;
+    match res {
+        Ok((entropy,)) => {
+            let challenge = URL_SAFE_NO_PAD.encode(&entropy);
+            let now = time();
+            CHALLENGE_STORE.with(|challenge_store| {
+                let mut challenge_store = challenge_store.borrow_mut();
+                challenge_store.retain(|_, pending| pending.expires_at > now);
+                challenge_store.insert(challenge.clone(), PendingChallenge {
+                    user_name,
+                    ceremony,
+                    expires_at: now + CHALLENGE_TTL
+                });
+            });
+            Ok(challenge)
+        }
+        Err((_, error)) => Err(error)
+    }
+}
+
+pub fn live_challenges(user_name: &str, ceremony: Ceremony) -> usize {
+    let now = time();
+    CHALLENGE_STORE.with(|challenge_store| {
+        challenge_store.borrow().values()
+            .filter(|pending| pending.user_name == user_name && pending.ceremony == ceremony && pending.expires_at > now)
+            .count()
+    })
+}
+
+// Removes the challenge whatever the outcome, so every challenge can be presented only once.
+// Returns an empty string when it was issued for this user and ceremony and has not expired.
+pub fn consume_challenge(challenge: &str, user_name: &str, ceremony: Ceremony) -> String {
+    let pending = CHALLENGE_STORE.with(|challenge_store| challenge_store.borrow_mut().remove(challenge));
+    match pending {
+        Some(pending) => {
+            if pending.user_name != user_name || pending.ceremony != ceremony {
+                "Challenge mismatch".to_string()
+            }
+            else if pending.expires_at <= time() {
+                "Challenge expired".to_string()
+            }
+            else{
+                "".to_string()
+            }
+        }
+        None => "Unknown challenge".to_string()
+    }
+}
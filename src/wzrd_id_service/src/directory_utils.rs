@@ -0,0 +1,200 @@
+use candid::Deserialize;
+use ic_cdk::export::candid::CandidType;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use crate::id_utils::{self, KEY_STORE};
+use crate::{admin_utils, profile_utils};
+
+const MAX_SEARCH_PAGE: u32 = 50;
+const MAX_QUERY_LENGTH: usize = 64;
+// Substring matches need a scan, which stops after this many accounts and hands back a cursor
+// to carry on from, even when the page isn't full yet
+const MAX_SCANNED: usize = 2_000;
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct SearchUsersParams {
+    pub token: String,
+    pub query: String,
+    pub cursor: Option<String>,
+    pub limit: u32
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct UserSummary {
+    pub user_name: String,
+    pub display_name: Option<String>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct SearchUsersResult {
+    pub error: String,
+    pub result: Vec<UserSummary>,
+    pub next: Option<String> // pass back as `cursor` for the next page
+}
+
+type DirectoryIndex = BTreeMap<String, String>; //(lowercased username => user_name)
+
+thread_local! {
+    // Derived from the key store, rebuilt after every upgrade
+    static DIRECTORY_INDEX: RefCell<DirectoryIndex> = RefCell::default();
+}
+
+// Usernames starting with the query are listed before the other matches
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Rank {
+    Prefix,
+    Substring
+}
+
+fn encode_cursor(rank: Rank, key: &str) -> String {
+    let phase = match rank {
+        Rank::Prefix => 'p',
+        Rank::Substring => 's'
+    };
+    format!("{}:{}", phase, key)
+}
+
+fn decode_cursor(cursor: &str) -> Option<(Rank, String)> {
+    let (phase, key) = cursor.split_once(':')?;
+    let rank = match phase {
+        "p" => Rank::Prefix,
+        "s" => Rank::Substring,
+        _ => return None
+    };
+    Some((rank, key.to_string()))
+}
+
+pub fn index_user(user_name: &str) {
+    DIRECTORY_INDEX.with(|index| index.borrow_mut().insert(user_name.to_lowercase(), user_name.to_string()));
+}
+
+pub fn unindex_user(user_name: &str) {
+    DIRECTORY_INDEX.with(|index| index.borrow_mut().remove(&user_name.to_lowercase()));
+}
+
+pub fn rebuild_index() {
+    let user_names: Vec<String> = KEY_STORE.with(|key_store| key_store.borrow().iter().map(|(user_name, _)| user_name).collect());
+    DIRECTORY_INDEX.with(|index| index.borrow_mut().clear());
+    for user_name in user_names {
+        index_user(&user_name);
+    }
+}
+
+fn is_listed(user_name: &str) -> bool {
+    profile_utils::is_discoverable(user_name) && !admin_utils::is_suspended(user_name)
+}
+
+// Collects one page of matches, returning the cursor of the next one if there is more to see
+struct Page {
+    limit: usize,
+    result: Vec<UserSummary>,
+    last_listed: (Rank, String),
+    next: Option<String>
+}
+
+impl Page {
+    // Returns false once the page is full and a further match proved there's a next page
+    fn offer(&mut self, rank: Rank, key: &str, summary: UserSummary) -> bool {
+        if self.result.len() == self.limit {
+            self.next = Some(encode_cursor(self.last_listed.0, &self.last_listed.1));
+            return false;
+        }
+        self.last_listed = (rank, key.to_string());
+        self.result.push(summary);
+        true
+    }
+}
+
+// Case-insensitive search over usernames and public display names. Users who opted out of
+// the directory and suspended accounts are never listed.
+pub fn search_users(params: SearchUsersParams) -> SearchUsersResult {
+    if id_utils::verify_token(&params.token).is_none() {
+        return SearchUsersResult {
+            error: "Invalid token".to_string(),
+            result: vec![],
+            next: None
+        };
+    }
+    let query = params.query.trim().to_lowercase();
+    if query.is_empty() || query.chars().count() > MAX_QUERY_LENGTH {
+        return SearchUsersResult {
+            error: format!("Query must be 1 to {} characters", MAX_QUERY_LENGTH),
+            result: vec![],
+            next: None
+        };
+    }
+    let cursor = match params.cursor.as_deref().map(decode_cursor) {
+        Some(None) => return SearchUsersResult {
+            error: "Invalid cursor".to_string(),
+            result: vec![],
+            next: None
+        },
+        Some(cursor) => cursor,
+        None => None
+    };
+    let mut page = Page {
+        limit: params.limit.clamp(1, MAX_SEARCH_PAGE) as usize,
+        result: vec![],
+        last_listed: (Rank::Prefix, String::new()),
+        next: None
+    };
+
+    DIRECTORY_INDEX.with(|index| {
+        let index = index.borrow();
+        // Prefix matches are a contiguous range of the index
+        if !matches!(cursor, Some((Rank::Substring, _))) {
+            let start = match &cursor {
+                Some((_, key)) => Bound::Excluded(key.clone()),
+                None => Bound::Included(query.clone())
+            };
+            for (key, user_name) in index.range((start, Bound::Unbounded)) {
+                if !key.starts_with(&query) {
+                    break;
+                }
+                if !is_listed(user_name) {
+                    continue;
+                }
+                let summary = UserSummary {
+                    user_name: user_name.clone(),
+                    display_name: profile_utils::public_display_name(user_name)
+                };
+                if !page.offer(Rank::Prefix, key, summary) {
+                    return;
+                }
+            }
+        }
+        let start = match &cursor {
+            Some((Rank::Substring, key)) => Bound::Excluded(key.clone()),
+            _ => Bound::Unbounded
+        };
+        let mut last_scanned = None;
+        for (scanned, (key, user_name)) in index.range((start, Bound::Unbounded)).enumerate() {
+            if let Some(last_scanned) = last_scanned.filter(|_| scanned == MAX_SCANNED) {
+                page.next = Some(encode_cursor(Rank::Substring, last_scanned));
+                return;
+            }
+            last_scanned = Some(key);
+            if key.starts_with(&query) {
+                continue;
+            }
+            let display_name = profile_utils::public_display_name(user_name);
+            let matched = key.contains(&query) || display_name.as_ref().map_or(false, |name| name.to_lowercase().contains(&query));
+            if !matched || !is_listed(user_name) {
+                continue;
+            }
+            let summary = UserSummary {
+                user_name: user_name.clone(),
+                display_name
+            };
+            if !page.offer(Rank::Substring, key, summary) {
+                return;
+            }
+        }
+    });
+    SearchUsersResult {
+        error: "".to_string(),
+        result: page.result,
+        next: page.next
+    }
+}
@@ -0,0 +1,329 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use candid::Deserialize;
+use ic_cdk::export::candid::CandidType;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use crate::oauth_utils::{self, ExchangeCodeParams, KNOWN_SCOPES};
+use crate::profile_utils::PROFILE_STORE;
+use crate::token_utils::ACCESS_TOKEN_LIFETIME_SECS;
+use crate::{account_utils, avatar_utils, certification_utils, id_utils, profile_utils, token_utils, username_utils, webauthn_utils};
+
+const OPENID_CONFIGURATION_PATH: &str = "/.well-known/openid-configuration";
+const JWKS_PATH: &str = "/.well-known/jwks.json";
+
+// The HTTP gateway's interface for requests made to the canister's own URL
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub upgrade: Option<bool> // asks the gateway to repeat the request as `http_request_update`
+}
+
+// The `iss` of every token. Discovery and keys are certified, so relying parties can fetch them
+// from the certified domain.
+pub fn issuer() -> String {
+    format!("https://{}.icp0.io", ic_cdk::id())
+}
+
+// Everything else is answered per request without certification, so it's addressed on the raw domain
+pub fn raw_origin() -> String {
+    format!("https://{}.raw.icp0.io", ic_cdk::id())
+}
+
+fn text_response(status_code: u16, body: &str) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "text/plain; charset=utf-8".to_string())],
+        body: body.as_bytes().to_vec(),
+        upgrade: None
+    }
+}
+
+// Discovery, keys and userinfo are read by other origins' backends and browsers alike
+fn json_response(status_code: u16, body: Value) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+            ("Cache-Control".to_string(), "no-store".to_string())
+        ],
+        body: body.to_string().into_bytes(),
+        upgrade: None
+    }
+}
+
+fn header<'a>(request: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    request.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+}
+
+// Old usernames keep resolving for as long as their redirect lasts
+fn serve_avatar(user_name: &str) -> HttpResponse {
+    let avatar = username_utils::find_user(user_name).and_then(|user_name| avatar_utils::get_avatar(&user_name));
+    match avatar {
+        Some(avatar) => HttpResponse {
+            status_code: 200,
+            headers: vec![
+                ("Content-Type".to_string(), avatar.mime_type),
+                ("Cache-Control".to_string(), "public, max-age=3600".to_string()),
+                ("X-Content-Type-Options".to_string(), "nosniff".to_string())
+            ],
+            body: avatar.data,
+            upgrade: None
+        },
+        None => text_response(404, "Not found")
+    }
+}
+
+// The user approves a client on the first-party app's /authorize page, which runs
+// `AuthorizeRequest` and `Authorize` and redirects back with the code. Clients are public and
+// prove themselves with PKCE instead of a secret.
+fn openid_configuration() -> Value {
+    let issuer = issuer();
+    let raw_origin = raw_origin();
+    let app_origin = webauthn_utils::get_relying_party().and_then(|relying_party| relying_party.origins.first().cloned());
+    json!({
+        "issuer": issuer,
+        "authorization_endpoint": app_origin.map(|origin| format!("{}/authorize", origin)),
+        "token_endpoint": format!("{}/token", raw_origin),
+        "jwks_uri": format!("{}{}", issuer, JWKS_PATH),
+        "userinfo_endpoint": format!("{}/userinfo", raw_origin),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code", "refresh_token"],
+        "token_endpoint_auth_methods_supported": ["none"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["EdDSA"],
+        "code_challenge_methods_supported": ["S256"],
+        "scopes_supported": KNOWN_SCOPES,
+        "claims_supported": [
+            "iss", "sub", "aud", "iat", "exp", "auth_time", "nonce",
+            "preferred_username", "name", "given_name", "family_name", "picture",
+            "email", "email_verified", "phone_number", "phone_number_verified"
+        ]
+    })
+}
+
+// Every key that can still verify tokens, including retired ones within their grace period. A
+// retired key stays listed past its grace period until the next rotation, which is harmless.
+fn jwks() -> Value {
+    let keys: Vec<Value> = token_utils::get_signing_keys().into_iter().map(|key| json!({
+        "kty": "OKP",
+        "crv": "Ed25519",
+        "x": URL_SAFE_NO_PAD.encode(&key.public_key),
+        "kid": key.kid,
+        "use": "sig",
+        "alg": "EdDSA"
+    })).collect();
+    json!({ "keys": keys })
+}
+
+// Recomputes the certified bodies, whenever the relying party or the signing keys change
+pub fn certify_well_known() {
+    let mut bodies = BTreeMap::new();
+    bodies.insert(OPENID_CONFIGURATION_PATH.to_string(), openid_configuration().to_string().into_bytes());
+    bodies.insert(JWKS_PATH.to_string(), jwks().to_string().into_bytes());
+    certification_utils::certify(bodies);
+}
+
+fn serve_certified(path: &str) -> HttpResponse {
+    match certification_utils::certified_response(path) {
+        Some((body, certificate)) => {
+            let mut response = json_response(200, Value::Null);
+            response.body = body;
+            if let Some(certificate) = certificate {
+                response.headers.push(("IC-Certificate".to_string(), certificate));
+            }
+            response
+        }
+        None => text_response(404, "Not found")
+    }
+}
+
+// Claims about the token's owner, limited by the OpenID scopes of third-party tokens
+fn userinfo(request: &HttpRequest) -> HttpResponse {
+    let claims = header(request, "Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| id_utils::verify_access_token(token.trim()));
+    let claims = match claims {
+        Some(claims) if oauth_utils::has_scope(&claims, "openid") => claims,
+        _ => {
+            let mut response = json_response(401, json!({ "error": "invalid_token" }));
+            response.headers.push(("WWW-Authenticate".to_string(), "Bearer error=\"invalid_token\"".to_string()));
+            return response;
+        }
+    };
+    let mut info = Map::new();
+    info.insert("sub".to_string(), json!(account_utils::user_id(&claims.username)));
+    info.insert("preferred_username".to_string(), json!(claims.username));
+    let profile = PROFILE_STORE.with(|profile_store| profile_store.borrow().get(&claims.username).cloned());
+    if oauth_utils::has_scope(&claims, "profile") {
+        if let Some(name) = profile_utils::public_display_name(&claims.username) {
+            info.insert("name".to_string(), json!(name));
+        }
+        if let Some(profile) = &profile {
+            if let Some(first_name) = &profile.first_name {
+                info.insert("given_name".to_string(), json!(first_name));
+            }
+            if let Some(last_name) = &profile.last_name {
+                info.insert("family_name".to_string(), json!(last_name));
+            }
+        }
+        if let Some(picture) = avatar_utils::avatar_url(&claims.username) {
+            info.insert("picture".to_string(), json!(picture));
+        }
+    }
+    if let Some(profile) = &profile {
+        if oauth_utils::has_scope(&claims, "email") {
+            if let Some(email) = &profile.email {
+                info.insert("email".to_string(), json!(email));
+                info.insert("email_verified".to_string(), json!(profile.email_verified.unwrap_or(false)));
+            }
+        }
+        if oauth_utils::has_scope(&claims, "phone") {
+            if let Some(phone) = &profile.phone {
+                info.insert("phone_number".to_string(), json!(phone));
+                info.insert("phone_number_verified".to_string(), json!(profile.phone_verified.unwrap_or(false)));
+            }
+        }
+    }
+    json_response(200, Value::Object(info))
+}
+
+// Decodes an application/x-www-form-urlencoded body, None if it isn't valid UTF-8
+fn form_params(body: &[u8]) -> Option<BTreeMap<String, String>> {
+    fn decode(value: &str) -> Option<String> {
+        let bytes = value.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'+' => decoded.push(b' '),
+                b'%' => {
+                    let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                    decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                    i += 2;
+                }
+                byte => decoded.push(byte)
+            }
+            i += 1;
+        }
+        String::from_utf8(decoded).ok()
+    }
+    let body = std::str::from_utf8(body).ok()?;
+    body.split('&').filter(|pair| !pair.is_empty()).map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        Some((decode(key)?, decode(value)?))
+    }).collect()
+}
+
+fn token_error(error: &str) -> HttpResponse {
+    json_response(400, json!({ "error": error }))
+}
+
+// The OAuth token endpoint, for clients that can't call the canister directly
+fn token_endpoint(request: &HttpRequest) -> HttpResponse {
+    let params = match form_params(&request.body) {
+        Some(params) => params,
+        None => return token_error("invalid_request")
+    };
+    let param = |name: &str| params.get(name).cloned().unwrap_or_default();
+    let (access_token, refresh_token, id_token) = match param("grant_type").as_str() {
+        "authorization_code" => {
+            let result = oauth_utils::exchange_code(ExchangeCodeParams {
+                client_id: param("client_id"),
+                code: param("code"),
+                code_verifier: param("code_verifier"),
+                redirect_uri: param("redirect_uri")
+            });
+            if !result.error.is_empty() {
+                return token_error("invalid_grant");
+            }
+            (result.access_token, result.refresh_token, result.id_token)
+        }
+        "refresh_token" => {
+            let result = id_utils::refresh_token(param("refresh_token"));
+            if !result.error.is_empty() {
+                return token_error("invalid_grant");
+            }
+            (result.access_token, result.refresh_token, "".to_string())
+        }
+        _ => return token_error("unsupported_grant_type")
+    };
+    let mut body = json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "expires_in": ACCESS_TOKEN_LIFETIME_SECS,
+        "refresh_token": refresh_token
+    });
+    if !id_token.is_empty() {
+        body["id_token"] = json!(id_token);
+    }
+    json_response(200, body)
+}
+
+fn path(request: &HttpRequest) -> &str {
+    request.url.split('?').next().unwrap_or_default()
+}
+
+fn segments(request: &HttpRequest) -> Vec<&str> {
+    path(request).trim_start_matches('/').split('/').collect()
+}
+
+pub fn http_request(request: HttpRequest) -> HttpResponse {
+    let segments = segments(&request);
+    // Redeeming a code changes state, so the gateway is sent on to `http_request_update`
+    if request.method == "POST" && segments.as_slice() == ["token"] {
+        return HttpResponse {
+            upgrade: Some(true),
+            ..text_response(200, "")
+        };
+    }
+    if request.method != "GET" && request.method != "HEAD" {
+        return text_response(405, "Method not allowed");
+    }
+    let mut response = match segments.as_slice() {
+        ["avatar", user_name] if !user_name.is_empty() => serve_avatar(user_name),
+        [".well-known", _] => serve_certified(path(&request)),
+        ["userinfo"] => userinfo(&request),
+        _ => text_response(404, "Not found")
+    };
+    if request.method == "HEAD" {
+        response.body = vec![];
+    }
+    response
+}
+
+pub fn http_request_update(request: HttpRequest) -> HttpResponse {
+    match (request.method.as_str(), segments(&request).as_slice()) {
+        ("POST", ["token"]) => token_endpoint(&request),
+        _ => text_response(404, "Not found")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_form_bodies() {
+        let params = form_params(b"grant_type=authorization_code&redirect_uri=https%3A%2F%2Fapp.example%2Fcb&scope=openid+email").unwrap();
+        assert_eq!(params["grant_type"], "authorization_code");
+        assert_eq!(params["redirect_uri"], "https://app.example/cb");
+        assert_eq!(params["scope"], "openid email");
+    }
+
+    #[test]
+    fn rejects_broken_escapes() {
+        assert!(form_params(b"code=%zz").is_none());
+        assert!(form_params(b"code=%4").is_none());
+    }
+}
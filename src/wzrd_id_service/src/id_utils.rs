@@ -1,17 +1,23 @@
 use ic_cdk::api::time;
 use ic_cdk::export::candid::CandidType;
 use std::cell::RefCell;
-use std::collections::BTreeMap;
-use hmac::{Hmac, Mac};
-use jwt::{SignWithKey, VerifyWithKey};
-use sha2::Sha256;
+use ic_stable_structures::StableBTreeMap;
 use candid::Deserialize;
+use crate::challenge_utils::{self, Ceremony};
+use crate::token_utils::{self, Claims, ACCESS_TOKEN, REFRESH_TOKEN};
+use crate::audit_utils::{self, SecurityEventKind};
+use crate::memory_utils::{self, Candid, Memory};
+use crate::role_utils::{self, Role};
+use crate::{account_utils, admin_utils, directory_utils, lockout_utils, oauth_utils, recovery_utils, session_utils, totp_utils, username_utils, webauthn_utils};
 
 #[derive(Clone, Debug, Deserialize, CandidType)]
 pub struct RegisterParams{
     pub user_name: String,
     pub key_id: String,
-    pub public_key: String
+    pub public_key: String,
+    pub nickname: Option<String>,
+    pub attestation_object: String, // `AuthenticatorAttestationResponse.attestationObject`
+    pub client_data_json: String // carries the challenge from `RegisterRequest`
 }
 
 #[derive(Clone, Debug, Deserialize, CandidType)]
@@ -21,21 +27,39 @@ pub struct RequestResult{
 }
 
 #[derive(Clone, Debug, Deserialize, CandidType)]
-pub struct SetProfileResult{
+pub struct TokenResult{
+    pub error: String,
+    pub access_token: String,
+    pub refresh_token: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct LogoutResult{
     pub error: String,
     pub result: bool
 }
 
 #[derive(Clone, Debug, Deserialize, CandidType)]
-pub struct GetProfileResult{
+pub struct CheckAccessTokenParams{
+    pub token: String,
+    pub scope: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct AccessTokenResult{
     pub error: String,
-    pub result: Option<Profile>
+    pub user_name: String,
+    pub roles: Vec<String>, // current roles, only "user" for scoped tokens
+    pub scoped: bool, // issued to a third-party app
+    pub step_up: bool // no TOTP enrolled, or a recent enough code verified for this token
 }
 
 #[derive(Clone, Debug, Deserialize, CandidType)]
 pub struct AuthResult{
     pub error: String,
-    pub result: bool
+    pub result: bool,
+    pub recovery_codes: Vec<String>, // shown once
+    pub recovery_codes_error: String // why there are no recovery codes, call `RegenerateRecoveryCodes` once signed in
 }
 
 #[derive(Clone, Debug, Deserialize, CandidType)]
@@ -43,251 +67,632 @@ pub struct AuthenticationParams {
     pub user_name: String,
     pub key_id: String,
     pub signature: String,
-    pub authenticator_data: String
+    pub authenticator_data: String,
+    pub client_data_json: String,
+    pub client_label: Option<String>
 }
 
 #[derive(Clone, Debug, Deserialize, CandidType)]
-pub struct Profile {
-    pub phone: Option<String>,
-    pub email: Option<String>,
-    pub first_name: Option<String>,
-    pub last_name: Option<String>,
+pub struct FidoKey {
+    pub key_id: String,
+    pub public_key: String,
+    pub nickname: String,
+    pub created_at: u64,
+    pub last_used_at: Option<u64>
 }
 
 #[derive(Clone, Debug, Deserialize, CandidType)]
-pub struct FidoKey {
+pub struct PasskeyInfo {
+    pub key_id: String,
+    pub nickname: String,
+    pub created_at: u64,
+    pub last_used_at: Option<u64>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct AddPasskeyParams {
+    pub token: String,
     pub key_id: String,
     pub public_key: String,
+    pub nickname: Option<String>,
+    pub attestation_object: String, // the new passkey's ceremony, over the `AddPasskeyRequest` challenge
+    pub client_data_json: String,
+    pub existing_key_id: String, // an assertion from a registered passkey over the same challenge
+    pub signature: String,
+    pub authenticator_data: String,
+    pub assertion_client_data_json: String
 }
 
 #[derive(Clone, Debug, Deserialize, CandidType)]
-pub struct SetProfileParams {
-    pub user_name: String,
-    pub first_name: Option<String>,
-    pub last_name: Option<String>,
-    pub email: Option<String>,
-    pub phone: Option<String>
+pub struct RemovePasskeyParams {
+    pub token: String,
+    pub key_id: String,
+    pub existing_key_id: String, // another registered passkey, asserting over the `RemovePasskeyRequest` challenge
+    pub signature: String,
+    pub authenticator_data: String,
+    pub client_data_json: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct PasskeyResult {
+    pub error: String,
+    pub result: bool
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ListPasskeysResult {
+    pub error: String,
+    pub result: Vec<PasskeyInfo>
 }
 
-type KeyStore = BTreeMap<String, FidoKey>; //(user_name => Fido key)
-type ProfileStore = BTreeMap<String, Profile>; //(user_name => vec<key_id>)
+pub type KeyStore = StableBTreeMap<String, Candid<Vec<FidoKey>>, Memory>; //(user_name => Fido keys)
 
 thread_local! {
-    pub static KEY_STORE: RefCell<KeyStore> = RefCell::default();
-    pub static PROFILE_STORE: RefCell<ProfileStore> = RefCell::default();
+    pub static KEY_STORE: RefCell<KeyStore> = RefCell::new(StableBTreeMap::init(memory_utils::memory(memory_utils::KEYS)));
 }
 
-pub fn register_request(username: String) -> RequestResult {
-    KEY_STORE.with( |key_store| {
-        if key_store.borrow().get(&username).is_some() {
-            RequestResult{
-                error: "Username already registered".to_string(),
-                result: "".to_string()
-            }
+pub async fn register_request(username: String) -> RequestResult {
+    let username = match username_utils::validate(&username) {
+        Ok(username) => username,
+        Err(error) => return RequestResult{
+            error,
+            result: "".to_string()
         }
-        else{
-            RequestResult{
-                error: "".to_string(),
-                result: get_challenge()
-            }
+    };
+    match challenge_utils::issue_challenge(username, Ceremony::Register).await {
+        Ok(challenge) => RequestResult{
+            error: "".to_string(),
+            result: challenge
+        },
+        Err(error) => RequestResult{
+            error,
+            result: "".to_string()
         }
-    })
+    }
 }
 
-pub fn register(params: RegisterParams) -> AuthResult {
-    KEY_STORE.with( |key_store| {
-        if key_store.borrow().get(&params.user_name).is_some() {
-            AuthResult{
-                error: "Username already registered".to_string(),
-                result: false
+// Creates the account and hands out its recovery codes. If randomness isn't available the
+// account is still created, and the codes can be generated later with `RegenerateRecoveryCodes`.
+pub async fn register(params: RegisterParams) -> AuthResult {
+    let user_name = username_utils::fold(&params.user_name);
+    let challenge_error = match webauthn_utils::parse_client_data(&params.client_data_json) {
+        Ok(client_data) => {
+            let error = challenge_utils::consume_challenge(&client_data.challenge, &user_name, Ceremony::Register);
+            if error.is_empty() {
+                check_new_passkey(&params.key_id, &params.public_key, &params.attestation_object, &client_data)
             }
-        }
-        else{
-            let new_key = FidoKey{
-                key_id: params.key_id,
-                public_key: params.public_key
-            };
-            key_store.borrow_mut().insert(params.user_name, new_key);
-            AuthResult{
-                error: "".to_string(),
-                result: true
+            else{
+                error
             }
         }
-    })
+        Err(error) => error
+    };
+    if !challenge_error.is_empty() {
+        return AuthResult{
+            error: challenge_error,
+            result: false,
+            recovery_codes: vec![],
+            recovery_codes_error: "".to_string()
+        };
+    }
+    // Someone may have taken the name or a look-alike since the challenge was issued
+    if let Err(error) = username_utils::validate(&user_name) {
+        return AuthResult{
+            error,
+            result: false,
+            recovery_codes: vec![],
+            recovery_codes_error: "".to_string()
+        };
+    }
+    account_utils::assign_user_id(&user_name);
+    audit_utils::record(&user_name, SecurityEventKind::Registered, params.key_id.clone());
+    let new_key = FidoKey{
+        nickname: params.nickname.unwrap_or_else(|| params.key_id.clone()),
+        key_id: params.key_id,
+        public_key: params.public_key,
+        created_at: time(),
+        last_used_at: None
+    };
+    KEY_STORE.with(|key_store| key_store.borrow_mut().insert(user_name.clone(), Candid(vec![new_key])));
+    username_utils::index_username(&user_name);
+    directory_utils::index_user(&user_name);
+    let (recovery_codes, recovery_codes_error) = match recovery_utils::new_recovery_codes(&user_name).await {
+        Ok(recovery_codes) => (recovery_codes, "".to_string()),
+        Err(error) => (vec![], error)
+    };
+    AuthResult{
+        error: "".to_string(),
+        result: true,
+        recovery_codes,
+        recovery_codes_error
+    }
 }
 
-pub fn authentication_request(user_name: String) -> RequestResult {
-    KEY_STORE.with( |key_store| {
-        if key_store.borrow().get(&user_name).is_some() {
-            RequestResult{
-                result: get_challenge(),
-                error: "".to_string()
-            }
+// Checks a passkey's creation ceremony, leaving its challenge to the caller: the key must be one
+// we can verify assertions with, attested by the authenticator, and created for our relying party
+pub fn check_new_passkey(
+    key_id: &str,
+    public_key: &str,
+    attestation_object: &str,
+    client_data: &webauthn_utils::CollectedClientData
+) -> String {
+    match webauthn_utils::verify_attestation(attestation_object, key_id, public_key) {
+        Ok(auth_data) => webauthn_utils::check_ceremony(client_data, &auth_data, webauthn_utils::CREATE_CEREMONY),
+        Err(error) => error
+    }
+}
+
+pub async fn authentication_request(user_name: String) -> RequestResult {
+    let user_name = match username_utils::find_user(&user_name) {
+        Some(user_name) => user_name,
+        None => return RequestResult{
+            error: "Username not registered".to_string(),
+            result: "".to_string()
         }
-        else{
-            RequestResult{
-                error: "Username not registered".to_string(),
-                result: "".to_string()
-            }
+    };
+    // Only the caller is throttled here, so that failures in someone's name never keep them
+    // from signing in. Signed-out callers get a bounded number of live challenges per account.
+    let mut lockout_error = lockout_utils::check_caller_attempt();
+    if lockout_error.is_empty() {
+        lockout_error = lockout_utils::check_challenge_budget(challenge_utils::live_challenges(&user_name, Ceremony::Authenticate));
+    }
+    if !lockout_error.is_empty() {
+        return RequestResult{
+            error: lockout_error,
+            result: "".to_string()
+        };
+    }
+    match challenge_utils::issue_challenge(user_name, Ceremony::Authenticate).await {
+        Ok(challenge) => RequestResult{
+            error: "".to_string(),
+            result: challenge
+        },
+        Err(error) => RequestResult{
+            error,
+            result: "".to_string()
         }
-    })
-}
-
-pub fn authentication(params: AuthenticationParams) -> RequestResult {
-    KEY_STORE.with( |key_store| {
-        if key_store.borrow().get(&params.user_name).is_some() {
-            let key = key_store.borrow().get(&params.user_name).unwrap().clone();
-            if key.key_id == params.key_id {
-                RequestResult{
-                    error: "".to_string(),
-                    result: generate_token(params.user_name, params.key_id)
-                }
+    }
+}
+
+// Assertions can't be guessed, so sign-in is throttled per caller and by the challenges issued,
+// never per user: a counter in the user's name would let anyone lock the owner out.
+pub fn authentication(mut params: AuthenticationParams) -> TokenResult {
+    if let Some(user_name) = username_utils::find_user(&params.user_name) {
+        params.user_name = user_name;
+    }
+    let lockout_error = lockout_utils::check_caller_attempt();
+    if !lockout_error.is_empty() {
+        return TokenResult{
+            error: lockout_error,
+            access_token: "".to_string(),
+            refresh_token: "".to_string()
+        };
+    }
+    let error = consume_passkey_challenge(
+        &params.user_name,
+        &params.authenticator_data,
+        &params.client_data_json,
+        Ceremony::Authenticate
+    );
+    if !error.is_empty() {
+        lockout_utils::record_caller_failure();
+        return TokenResult{
+            error,
+            access_token: "".to_string(),
+            refresh_token: "".to_string()
+        };
+    }
+    let error = check_passkey_assertion(
+        &params.user_name,
+        &params.key_id,
+        &params.signature,
+        &params.authenticator_data,
+        &params.client_data_json
+    );
+    if error.is_empty() {
+        lockout_utils::record_success(&params.user_name);
+        // Checked after the passkey so that suspensions aren't disclosed to anyone but the owner
+        let suspension_error = admin_utils::suspension_error(&params.user_name);
+        if !suspension_error.is_empty() {
+            return TokenResult{
+                error: suspension_error,
+                access_token: "".to_string(),
+                refresh_token: "".to_string()
+            };
+        }
+        audit_utils::record(&params.user_name, SecurityEventKind::LoggedIn, params.key_id.clone());
+        let session_id = session_utils::create_session(params.user_name.clone(), params.key_id.clone(), params.client_label, None);
+        issue_tokens(params.user_name, params.key_id, session_id, None, None)
+    }
+    else{
+        lockout_utils::record_caller_failure();
+        audit_utils::record_login_failure(&params.user_name, error.clone());
+        TokenResult{
+            error,
+            access_token: "".to_string(),
+            refresh_token: "".to_string()
+        }
+    }
+}
+
+// Consumes the challenge embedded in the client data, checks the origin and authenticator flags,
+// then the assertion against the user's passkey. Returns an empty string on success.
+pub fn verify_passkey(
+    user_name: &str,
+    key_id: &str,
+    signature: &str,
+    authenticator_data: &str,
+    client_data_json: &str,
+    ceremony: Ceremony
+) -> String {
+    let challenge_error = consume_passkey_challenge(user_name, authenticator_data, client_data_json, ceremony);
+    if !challenge_error.is_empty() {
+        return challenge_error;
+    }
+    check_passkey_assertion(user_name, key_id, signature, authenticator_data, client_data_json)
+}
+
+fn consume_passkey_challenge(user_name: &str, authenticator_data: &str, client_data_json: &str, ceremony: Ceremony) -> String {
+    match webauthn_utils::parse_client_data(client_data_json) {
+        Ok(client_data) => {
+            let error = challenge_utils::consume_challenge(&client_data.challenge, user_name, ceremony);
+            if error.is_empty() {
+                webauthn_utils::check_ceremony(&client_data, authenticator_data, webauthn_utils::GET_CEREMONY)
             }
             else{
-                RequestResult{
-                    error: "Authentication failed".to_string(),
-                    result: "".to_string()
-                }
+                error
             }
         }
-        else{
-            RequestResult{
-                error: "Username not registered".to_string(),
-                result: "".to_string()
-            }
+        Err(error) => error
+    }
+}
+
+fn check_passkey_assertion(
+    user_name: &str,
+    key_id: &str,
+    signature: &str,
+    authenticator_data: &str,
+    client_data_json: &str
+) -> String {
+    let mut keys = match passkeys(user_name) {
+        Some(keys) => keys,
+        None => return "Username not registered".to_string()
+    };
+    match keys.iter_mut().find(|key| key.key_id == key_id) {
+        Some(key) if webauthn_utils::verify_assertion(
+            &key.public_key,
+            authenticator_data,
+            client_data_json,
+            signature
+        ).is_empty() => {
+            key.last_used_at = Some(time());
+            KEY_STORE.with(|key_store| key_store.borrow_mut().insert(user_name.to_string(), Candid(keys)));
+            "".to_string()
         }
-    })
-}
-
-pub fn get_challenge() -> String {
-    let mut number = time();
-    let char_set = vec![
-        "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r",
-        "s", "t", "u", "v", "w", "x", "y", "z", "A", "B", "C", "D", "E", "F", "G", "H", "I", "J",
-        "K", "L", "M", "N", "O", "P", "Q", "R", "S", "T", "U", "V", "W", "X", "Y", "Z", "0", "1",
-        "2", "3", "4", "5", "6", "7", "8", "9"
-    ];
-    let mut result: String = "".to_string();
-    while number > 0 {
-        let index = (number % 62) as usize;
-        result.push_str(char_set[index]);
-        number /= 62;
-    }
-    result
-}
-
-pub fn reverse_timestamp(timestamp: String) -> u64 {
-    let char_set = vec![
-        "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r",
-        "s", "t", "u", "v", "w", "x", "y", "z", "A", "B", "C", "D", "E", "F", "G", "H", "I", "J",
-        "K", "L", "M", "N", "O", "P", "Q", "R", "S", "T", "U", "V", "W", "X", "Y", "Z", "0", "1",
-        "2", "3", "4", "5", "6", "7", "8", "9"
-    ];
-    let mut num = 0u64;
-    for (index, c) in timestamp.chars().enumerate() {
-        num += (char_set.iter().position(|&x| x.to_string() == c.to_string()).unwrap() as u64) * 62u64.pow(index.clone() as u32);
-    }
-    num
-}
-
-pub fn generate_token(user_name: String, key_id: String) -> String {
-    let key: Hmac<Sha256> = Hmac::new_from_slice(b"wzrd-secret-key").unwrap();
-    let mut claims = BTreeMap::new();
-    claims.insert("username", user_name);
-    claims.insert("keyId", key_id);
-    claims.insert("timestamp", get_challenge());
-    let token_str = claims.sign_with_key(&key).unwrap();
-    token_str
+        _ => "Authentication failed".to_string()
+    }
 }
 
-pub fn check_token(token: String) -> String {
-    let key: Hmac<Sha256> = Hmac::new_from_slice(b"wzrd-secret-key").unwrap();
-    let veri_claims;
-    let result: Result<BTreeMap<String, String>, jwt::Error> = token.as_str().verify_with_key(&key);
-    match result{
-        Ok(okay_result) => veri_claims = okay_result,
-        Err(_) => return "".to_string()
+// Issues an access and refresh token pair for the session `sid`. Third-party tokens are limited
+// to `scope`, addressed to `client_id` and never carry the user's privileged roles.
+pub fn issue_tokens(user_name: String, key_id: String, sid: String, scope: Option<String>, client_id: Option<String>) -> TokenResult {
+    let uid = account_utils::user_id(&user_name);
+    let roles = match scope {
+        Some(_) => vec![Role::User.as_str().to_string()],
+        None => role_utils::token_roles(&user_name)
     };
-    let user_name = &veri_claims["username"];
-    let key_id = &veri_claims["keyId"];
-    let timestamp_str = &veri_claims["timestamp"];
-    let timestamp = reverse_timestamp(timestamp_str.clone());
+    let mut access_claims = token_utils::new_claims(user_name.clone(), uid.clone(), key_id.clone(), sid.clone(), roles.clone(), scope.clone(), ACCESS_TOKEN);
+    let mut refresh_claims = token_utils::new_claims(user_name, uid, key_id, sid, roles, scope, REFRESH_TOKEN);
+    if let Some(client_id) = client_id {
+        access_claims.aud = client_id.clone();
+        refresh_claims.aud = client_id;
+    }
+    match (token_utils::sign_claims(&access_claims), token_utils::sign_claims(&refresh_claims)) {
+        (Some(access_token), Some(refresh_token)) => TokenResult{
+            error: "".to_string(),
+            access_token,
+            refresh_token
+        },
+        _ => TokenResult{
+            error: "Token signing key not ready".to_string(),
+            access_token: "".to_string(),
+            refresh_token: "".to_string()
+        }
+    }
+}
 
-    if time() - timestamp > 3600000000000 {
-        "".to_string()
+// Any live access token, first-party or scoped. Refresh tokens are only good for `refresh_token`.
+pub fn verify_access_token(token: &str) -> Option<Claims> {
+    let claims = token_utils::verify_claims(token)?;
+    if claims.typ == ACCESS_TOKEN && has_key(&claims.username, &claims.key_id) && !admin_utils::is_suspended(&claims.username) {
+        Some(claims)
     }
     else{
-        KEY_STORE.with( |key_store| {
-            if key_store.borrow().get(user_name).is_some() {
-                let key = key_store.borrow().get(user_name).unwrap().clone();
-                if key.key_id == *key_id {
-                    generate_token(user_name.clone(), key_id.clone())
-                }
-                else{
-                    "".to_string()
-                }
-            }
-            else{
-                "".to_string()
-            }
-        })
+        None
     }
 }
 
-pub fn has_user(user_name: &String) -> bool {
-    KEY_STORE.with(|key_store| key_store.borrow().get(user_name).is_some())
+// Only first-party tokens authorize calls here, scoped tokens are for chat, wallet and userinfo
+pub fn verify_token(token: &str) -> Option<Claims> {
+    verify_access_token(token).filter(|claims| claims.scope.is_none())
 }
 
-pub fn set_profile(params: SetProfileParams) -> SetProfileResult {
-    KEY_STORE.with( |key_store| {
-        if key_store.borrow().get(&params.user_name).is_some() {
-            let profile = Profile {
-                first_name: params.first_name,
-                last_name: params.last_name,
-                phone: params.phone,
-                email: params.email,
-            };
-            PROFILE_STORE.with(|profile_store| {
-                profile_store.borrow_mut().insert(params.user_name, profile);
-            });
-            SetProfileResult{
+pub fn check_token(token: String) -> String {
+    match verify_token(&token) {
+        Some(_) => token,
+        None => "".to_string()
+    }
+}
+
+// For chat and wallet operations that can't wait for a token to expire. Unlike their local
+// check this sees revoked sessions, suspensions, removed passkeys and role changes.
+pub fn check_access_token(params: CheckAccessTokenParams) -> AccessTokenResult {
+    let error_result = |error: &str| AccessTokenResult{
+        error: error.to_string(),
+        user_name: "".to_string(),
+        roles: vec![],
+        scoped: false,
+        step_up: false
+    };
+    let claims = match verify_access_token(&params.token) {
+        Some(claims) => claims,
+        None => return error_result("Invalid token")
+    };
+    if !oauth_utils::has_scope(&claims, &params.scope) {
+        return error_result("Scope not granted");
+    }
+    let roles = match claims.scope {
+        Some(_) => claims.roles.clone(),
+        None => role_utils::token_roles(&claims.username)
+    };
+    AccessTokenResult{
+        error: "".to_string(),
+        step_up: totp_utils::has_step_up(&claims),
+        scoped: claims.scope.is_some(),
+        user_name: claims.username,
+        roles
+    }
+}
+
+pub fn refresh_token(token: String) -> TokenResult {
+    if let Some(claims) = token_utils::verify_claims(&token).filter(|claims| claims.typ == REFRESH_TOKEN) {
+        // Sessions follow username changes, so the new pair carries the current name
+        match session_utils::touch_session(&claims.sid) {
+            Some(session) if has_key(&session.user_name, &claims.key_id) && !admin_utils::is_suspended(&session.user_name) => {
+                // Refresh tokens are single use, the new pair continues the same session
+                token_utils::revoke(claims.jti, claims.exp);
+                audit_utils::record(&session.user_name, SecurityEventKind::TokenRefreshed, claims.sid.clone());
+                return issue_tokens(session.user_name, claims.key_id, claims.sid, claims.scope, session.client_id);
+            }
+            _ => {}
+        }
+    }
+    TokenResult{
+        error: "Invalid refresh token".to_string(),
+        access_token: "".to_string(),
+        refresh_token: "".to_string()
+    }
+}
+
+// Accepts either token of the session and revokes both
+pub fn logout(token: String) -> LogoutResult {
+    match token_utils::verify_claims(&token) {
+        Some(claims) => {
+            audit_utils::record_if_registered(&claims.username, SecurityEventKind::LoggedOut, claims.sid.clone());
+            session_utils::end_session(claims.sid);
+            LogoutResult{
                 error: "".to_string(),
                 result: true
             }
         }
-        else{
-            SetProfileResult{
-                error: "Username doesn't exist".to_string(),
-                result: false
-            }
+        None => LogoutResult{
+            error: "Invalid token".to_string(),
+            result: false
         }
-    })
-}
-
-pub fn get_profile(user_name: String) -> GetProfileResult {
-    KEY_STORE.with(|key_store| {
-        if key_store.borrow().get(&user_name).is_some() {
-            PROFILE_STORE.with(|profile_store| {
-                if profile_store.borrow().get(&user_name).is_some() {
-                    let user_profile = profile_store.borrow().get(&user_name).unwrap().clone();
-                    GetProfileResult{
-                        error: "".to_string(),
-                        result: Some(user_profile)
-                    }
-                }
-                else{
-                    GetProfileResult{
-                        error: "User profile doesn't exist".to_string(),
-                        result: None
-                    }
-                }
-            })
+    }
+}
+
+// A copy of the user's passkeys, write changes back with `KEY_STORE.insert`
+pub fn passkeys(user_name: &str) -> Option<Vec<FidoKey>> {
+    KEY_STORE.with(|key_store| key_store.borrow().get(&user_name.to_string()).map(|keys| keys.0))
+}
+
+pub fn has_key(user_name: &str, key_id: &str) -> bool {
+    match passkeys(user_name) {
+        Some(keys) => keys.iter().any(|key| key.key_id == key_id),
+        None => false
+    }
+}
+
+pub fn has_user(user_name: &String) -> bool {
+    KEY_STORE.with(|key_store| key_store.borrow().contains_key(user_name))
+}
+
+// Challenge for `AddPasskey`, which both the new passkey and an existing one must sign
+pub async fn add_passkey_request(token: String) -> RequestResult {
+    step_up_challenge(token, Ceremony::AddPasskey).await
+}
+
+// Challenge for `RemovePasskey`, signed by a passkey the user keeps
+pub async fn remove_passkey_request(token: String) -> RequestResult {
+    step_up_challenge(token, Ceremony::RemovePasskey).await
+}
+
+// Issues a challenge for a passkey assertion guarding a change to the account's credentials,
+// after the TOTP step-up when the user enabled it
+pub async fn step_up_challenge(token: String, ceremony: Ceremony) -> RequestResult {
+    let claims = match verify_token(&token) {
+        Some(claims) => claims,
+        None => return RequestResult{
+            error: "Invalid token".to_string(),
+            result: "".to_string()
         }
-        else {
-            GetProfileResult{
-                error: "Username doesn't exist".to_string(),
-                result: None
-            }
+    };
+    if !totp_utils::has_step_up(&claims) {
+        return RequestResult{
+            error: totp_utils::STEP_UP_REQUIRED.to_string(),
+            result: "".to_string()
+        };
+    }
+    match challenge_utils::issue_challenge(claims.username, ceremony).await {
+        Ok(challenge) => RequestResult{
+            error: "".to_string(),
+            result: challenge
+        },
+        Err(error) => RequestResult{
+            error,
+            result: "".to_string()
+        }
+    }
+}
+
+// A stolen token alone can't plant a passkey: the new key's creation and an assertion from a
+// registered key must both answer the same `AddPasskeyRequest` challenge.
+pub fn add_passkey(params: AddPasskeyParams) -> PasskeyResult {
+    let claims = match verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return PasskeyResult{
+            error: "Invalid token".to_string(),
+            result: false
+        }
+    };
+    if !totp_utils::has_step_up(&claims) {
+        return PasskeyResult{
+            error: totp_utils::STEP_UP_REQUIRED.to_string(),
+            result: false
+        };
+    }
+    let new_key_error = match (
+        webauthn_utils::parse_client_data(&params.client_data_json),
+        webauthn_utils::parse_client_data(&params.assertion_client_data_json)
+    ) {
+        (Ok(client_data), Ok(assertion_client_data)) if client_data.challenge == assertion_client_data.challenge => {
+            check_new_passkey(&params.key_id, &params.public_key, &params.attestation_object, &client_data)
+        }
+        (Ok(_), Ok(_)) => "Challenge mismatch".to_string(),
+        (Err(error), _) | (_, Err(error)) => error
+    };
+    if !new_key_error.is_empty() {
+        return PasskeyResult{
+            error: new_key_error,
+            result: false
+        };
+    }
+    let error = verify_passkey(
+        &claims.username,
+        &params.existing_key_id,
+        &params.signature,
+        &params.authenticator_data,
+        &params.assertion_client_data_json,
+        Ceremony::AddPasskey
+    );
+    if !error.is_empty() {
+        return PasskeyResult{
+            error,
+            result: false
+        };
+    }
+    let mut keys = passkeys(&claims.username).unwrap_or_default();
+    if keys.iter().any(|key| key.key_id == params.key_id) {
+        PasskeyResult{
+            error: "Passkey already registered".to_string(),
+            result: false
         }
-    })
+    }
+    else{
+        audit_utils::record(&claims.username, SecurityEventKind::PasskeyAdded, params.key_id.clone());
+        keys.push(FidoKey{
+            nickname: params.nickname.unwrap_or_else(|| params.key_id.clone()),
+            key_id: params.key_id,
+            public_key: params.public_key,
+            created_at: time(),
+            last_used_at: None
+        });
+        KEY_STORE.with(|key_store| key_store.borrow_mut().insert(claims.username, Candid(keys)));
+        PasskeyResult{
+            error: "".to_string(),
+            result: true
+        }
+    }
+}
+
+pub fn list_passkeys(token: String) -> ListPasskeysResult {
+    let claims = match verify_token(&token) {
+        Some(claims) => claims,
+        None => return ListPasskeysResult{
+            error: "Invalid token".to_string(),
+            result: vec![]
+        }
+    };
+    let result = passkeys(&claims.username).unwrap_or_default().into_iter().map(|key| PasskeyInfo {
+        key_id: key.key_id,
+        nickname: key.nickname,
+        created_at: key.created_at,
+        last_used_at: key.last_used_at
+    }).collect();
+    ListPasskeysResult{
+        error: "".to_string(),
+        result
+    }
+}
+
+// Like adding one, removing a passkey takes more than a token: a step-up and an assertion from
+// one of the passkeys that stay
+pub fn remove_passkey(params: RemovePasskeyParams) -> PasskeyResult {
+    let claims = match verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return PasskeyResult{
+            error: "Invalid token".to_string(),
+            result: false
+        }
+    };
+    if !totp_utils::has_step_up(&claims) {
+        return PasskeyResult{
+            error: totp_utils::STEP_UP_REQUIRED.to_string(),
+            result: false
+        };
+    }
+    if params.existing_key_id == params.key_id {
+        return PasskeyResult{
+            error: "Sign with a passkey other than the one being removed".to_string(),
+            result: false
+        };
+    }
+    let error = verify_passkey(
+        &claims.username,
+        &params.existing_key_id,
+        &params.signature,
+        &params.authenticator_data,
+        &params.client_data_json,
+        Ceremony::RemovePasskey
+    );
+    if !error.is_empty() {
+        return PasskeyResult{
+            error,
+            result: false
+        };
+    }
+    let mut keys = passkeys(&claims.username).unwrap_or_default();
+    if !keys.iter().any(|key| key.key_id == params.key_id) {
+        PasskeyResult{
+            error: "Passkey doesn't exist".to_string(),
+            result: false
+        }
+    }
+    else if keys.len() == 1 {
+        PasskeyResult{
+            error: "Can't remove the last passkey".to_string(),
+            result: false
+        }
+    }
+    else{
+        keys.retain(|key| key.key_id != params.key_id);
+        KEY_STORE.with(|key_store| key_store.borrow_mut().insert(claims.username.clone(), Candid(keys)));
+        session_utils::end_key_sessions(&claims.username, &params.key_id);
+        audit_utils::record(&claims.username, SecurityEventKind::PasskeyRemoved, params.key_id.clone());
+        PasskeyResult{
+            error: "".to_string(),
+            result: true
+        }
+    }
 }
@@ -1,29 +1,116 @@
-use ic_cdk::{query, update};
+use candid::Principal;
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
+use ic_cdk::{init, post_upgrade, pre_upgrade, query, update};
+use std::time::Duration;
 mod id_utils;
+mod account_utils;
+mod admin_utils;
+mod audit_utils;
+mod avatar_utils;
+mod cascade_utils;
+mod certification_utils;
+mod challenge_utils;
+mod directory_utils;
+mod http_utils;
+mod lockout_utils;
+mod memory_utils;
+mod oauth_utils;
+mod principal_utils;
+mod recovery_utils;
+mod profile_utils;
+mod role_utils;
+mod session_utils;
+mod state_utils;
+mod token_utils;
+mod totp_utils;
+mod username_utils;
+mod verification_utils;
+mod webauthn_utils;
 
-#[query(name = "RegisterRequest")]
-pub fn register_request(user_name: String) -> id_utils::RequestResult {
-    id_utils::register_request(user_name)
+#[init]
+fn init(args: Option<webauthn_utils::InstallArgs>) {
+    webauthn_utils::configure_on_install(args, false);
+    http_utils::certify_well_known();
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(token_utils::init_signing_key()));
+    cascade_utils::start_retry_timer();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    state_utils::save_state()
+}
+
+#[post_upgrade]
+fn post_upgrade(args: Option<webauthn_utils::InstallArgs>) {
+    state_utils::restore_state();
+    webauthn_utils::configure_on_install(args, true);
+    account_utils::backfill_user_ids();
+    username_utils::rebuild_index();
+    directory_utils::rebuild_index();
+    http_utils::certify_well_known();
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(token_utils::init_signing_key()));
+    cascade_utils::start_retry_timer();
+}
+
+#[update(name = "RegisterRequest")]
+pub async fn register_request(user_name: String) -> id_utils::RequestResult {
+    id_utils::register_request(user_name).await
 }
 
 #[update(name = "Register")]
-pub fn register(params: id_utils::RegisterParams) -> id_utils::AuthResult {
-    id_utils::register(params)
+pub async fn register(params: id_utils::RegisterParams) -> id_utils::AuthResult {
+    id_utils::register(params).await
 }
 
-#[query(name = "AuthenticationRequest")]
-pub fn authentication_request(user_name: String) -> id_utils::RequestResult {
-    id_utils::authentication_request(user_name)
+#[update(name = "AuthenticationRequest")]
+pub async fn authentication_request(user_name: String) -> id_utils::RequestResult {
+    id_utils::authentication_request(user_name).await
 }
 
-#[query(name = "Authentication")]
-pub fn authentication(params: id_utils::AuthenticationParams) -> id_utils::RequestResult {
+#[update(name = "Authentication")]
+pub fn authentication(params: id_utils::AuthenticationParams) -> id_utils::TokenResult {
     id_utils::authentication(params)
 }
 
+#[update(name = "RefreshToken")]
+pub fn refresh_token(refresh_token: String) -> id_utils::TokenResult {
+    id_utils::refresh_token(refresh_token)
+}
+
+#[update(name = "Logout")]
+pub fn logout(token: String) -> id_utils::LogoutResult {
+    id_utils::logout(token)
+}
+
+#[query(name = "ValidateUsername")]
+pub fn validate_username(user_name: String) -> username_utils::UsernameResult {
+    username_utils::validate_username(user_name)
+}
+
+#[query(name = "GetUsernamePolicy")]
+pub fn get_username_policy() -> username_utils::UsernamePolicy {
+    username_utils::get_username_policy()
+}
+
+#[update(name = "SetUsernamePolicy")]
+pub fn set_username_policy(params: username_utils::SetUsernamePolicyParams) -> username_utils::PolicyResult {
+    username_utils::set_username_policy(params)
+}
+
+#[update(name = "AddReservedNames")]
+pub fn add_reserved_names(names: Vec<String>) -> username_utils::PolicyResult {
+    username_utils::add_reserved_names(names)
+}
+
+#[update(name = "RemoveReservedNames")]
+pub fn remove_reserved_names(names: Vec<String>) -> username_utils::PolicyResult {
+    username_utils::remove_reserved_names(names)
+}
+
+// Matches the way sign-in resolves names, folded and through redirects of renamed accounts
 #[query(name = "CheckUser")]
 pub fn check_user(user_name: String) -> bool {
-    id_utils::has_user(&user_name)
+    username_utils::find_user(&user_name).is_some()
 }
 
 #[query(name = "CheckToken")]
@@ -31,17 +118,372 @@ pub fn check_token(token: String) -> String {
     id_utils::check_token(token)
 }
 
+#[query(name = "CheckAccessToken")]
+pub fn check_access_token(params: id_utils::CheckAccessTokenParams) -> id_utils::AccessTokenResult {
+    id_utils::check_access_token(params)
+}
+
+#[query(name = "SearchUsers")]
+pub fn search_users(params: directory_utils::SearchUsersParams) -> directory_utils::SearchUsersResult {
+    directory_utils::search_users(params)
+}
+
 #[query(name = "GetPrincipal")]
 pub fn get_principal() -> String {
     ic_cdk::caller().to_string()
 }
 
+#[update(name = "LinkPrincipal")]
+pub fn link_principal(token: String) -> principal_utils::PrincipalResult {
+    principal_utils::link_principal(token)
+}
+
+#[update(name = "UnlinkPrincipal")]
+pub fn unlink_principal(params: principal_utils::UnlinkPrincipalParams) -> principal_utils::PrincipalResult {
+    principal_utils::unlink_principal(params)
+}
+
+#[query(name = "ListPrincipals")]
+pub fn list_principals(token: String) -> principal_utils::ListPrincipalsResult {
+    principal_utils::list_principals(token)
+}
+
+#[query(name = "ResolvePrincipal")]
+pub fn resolve_principal(principal: Principal) -> Option<String> {
+    principal_utils::resolve_principal(principal)
+}
+
 #[query(name = "GetProfile")]
-pub fn get_profile(user_name: String) -> id_utils::GetProfileResult {
-    id_utils::get_profile(user_name)
+pub fn get_profile(params: profile_utils::GetProfileParams) -> profile_utils::GetProfileResult {
+    profile_utils::get_profile(params)
+}
+
+#[update(name = "RegisterClient")]
+pub async fn register_client(params: oauth_utils::RegisterClientParams) -> oauth_utils::ClientResult {
+    oauth_utils::register_client(params).await
+}
+
+#[update(name = "RemoveClient")]
+pub fn remove_client(client_id: String) -> oauth_utils::ClientResult {
+    oauth_utils::remove_client(client_id)
+}
+
+#[query(name = "GetClient")]
+pub fn get_client(client_id: String) -> Option<oauth_utils::OAuthClient> {
+    oauth_utils::get_client(client_id)
+}
+
+#[update(name = "AuthorizeRequest")]
+pub async fn authorize_request(params: oauth_utils::AuthorizeRequestParams) -> id_utils::RequestResult {
+    oauth_utils::authorize_request(params).await
+}
+
+#[update(name = "Authorize")]
+pub async fn authorize(params: oauth_utils::AuthorizeParams) -> oauth_utils::AuthorizeResult {
+    oauth_utils::authorize(params).await
+}
+
+#[update(name = "ExchangeCode")]
+pub fn exchange_code(params: oauth_utils::ExchangeCodeParams) -> oauth_utils::ExchangeCodeResult {
+    oauth_utils::exchange_code(params)
+}
+
+#[update(name = "TotpEnrollmentRequest")]
+pub async fn totp_enrollment_request(token: String) -> id_utils::RequestResult {
+    totp_utils::totp_enrollment_request(token).await
+}
+
+#[update(name = "BeginTotpEnrollment")]
+pub async fn begin_totp_enrollment(params: totp_utils::BeginTotpEnrollmentParams) -> totp_utils::TotpEnrollmentResult {
+    totp_utils::begin_totp_enrollment(params).await
+}
+
+#[update(name = "ConfirmTotpEnrollment")]
+pub async fn confirm_totp_enrollment(params: totp_utils::TotpCodeParams) -> totp_utils::BackupCodesResult {
+    totp_utils::confirm_totp_enrollment(params).await
+}
+
+#[update(name = "RegenerateBackupCodes")]
+pub async fn regenerate_backup_codes(params: totp_utils::TotpCodeParams) -> totp_utils::BackupCodesResult {
+    totp_utils::regenerate_backup_codes(params).await
+}
+
+#[update(name = "DisableTotp")]
+pub fn disable_totp(params: totp_utils::TotpCodeParams) -> totp_utils::TotpResult {
+    totp_utils::disable_totp(params)
+}
+
+#[query(name = "GetTotpStatus")]
+pub fn get_totp_status(token: String) -> totp_utils::TotpStatusResult {
+    totp_utils::get_totp_status(token)
+}
+
+#[update(name = "StepUp")]
+pub fn step_up(params: totp_utils::TotpCodeParams) -> id_utils::TokenResult {
+    totp_utils::step_up(params)
+}
+
+#[update(name = "RegenerateRecoveryCodes")]
+pub async fn regenerate_recovery_codes(token: String) -> recovery_utils::RecoveryCodesResult {
+    recovery_utils::regenerate_recovery_codes(token).await
+}
+
+#[query(name = "GetRecoveryStatus")]
+pub fn get_recovery_status(token: String) -> recovery_utils::RecoveryStatusResult {
+    recovery_utils::get_recovery_status(token)
+}
+
+#[update(name = "RecoverPasskeyRequest")]
+pub async fn recover_passkey_request(user_name: String) -> id_utils::RequestResult {
+    recovery_utils::recover_passkey_request(user_name).await
+}
+
+#[update(name = "RecoverWithCode")]
+pub fn recover_with_code(params: recovery_utils::RecoverWithCodeParams) -> id_utils::PasskeyResult {
+    recovery_utils::recover_with_code(params)
+}
+
+#[update(name = "SetGuardians")]
+pub fn set_guardians(params: recovery_utils::SetGuardiansParams) -> recovery_utils::RecoveryResult {
+    recovery_utils::set_guardians(params)
+}
+
+#[update(name = "RequestGuardianRecovery")]
+pub fn request_guardian_recovery(params: recovery_utils::RequestGuardianRecoveryParams) -> recovery_utils::RecoveryResult {
+    recovery_utils::request_guardian_recovery(params)
+}
+
+#[query(name = "ListRecoveryRequests")]
+pub fn list_recovery_requests(token: String) -> recovery_utils::ListRecoveryRequestsResult {
+    recovery_utils::list_recovery_requests(token)
+}
+
+#[update(name = "ApproveRecovery")]
+pub fn approve_recovery(params: recovery_utils::ApproveRecoveryParams) -> recovery_utils::ApproveRecoveryResult {
+    recovery_utils::approve_recovery(params)
+}
+
+#[update(name = "CancelRecovery")]
+pub fn cancel_recovery(token: String) -> recovery_utils::RecoveryResult {
+    recovery_utils::cancel_recovery(token)
+}
+
+#[update(name = "DeleteAccountRequest")]
+pub async fn delete_account_request(token: String) -> id_utils::RequestResult {
+    account_utils::delete_account_request(token).await
+}
+
+#[update(name = "DeleteAccount")]
+pub async fn delete_account(params: account_utils::DeleteAccountParams) -> account_utils::DeleteAccountResult {
+    account_utils::delete_account(params).await
+}
+
+#[update(name = "ChangeUsername")]
+pub fn change_username(params: account_utils::ChangeUsernameParams) -> id_utils::TokenResult {
+    account_utils::change_username(params)
+}
+
+#[query(name = "GetUsernameHistory")]
+pub fn get_username_history(token: String) -> account_utils::UsernameHistoryResult {
+    account_utils::get_username_history(token)
 }
 
 #[update(name = "SetProfile")]
-pub fn set_profile(params: id_utils::SetProfileParams) -> id_utils::SetProfileResult {
-    id_utils::set_profile(params)
+pub fn set_profile(params: profile_utils::SetProfileParams) -> profile_utils::SetProfileResult {
+    profile_utils::set_profile(params)
+}
+
+#[update(name = "SetStatus")]
+pub fn set_status(params: profile_utils::SetStatusParams) -> profile_utils::SetProfileResult {
+    profile_utils::set_status(params)
+}
+
+#[update(name = "BeginAvatarUpload")]
+pub fn begin_avatar_upload(params: avatar_utils::BeginAvatarUploadParams) -> avatar_utils::AvatarResult {
+    avatar_utils::begin_avatar_upload(params)
+}
+
+#[update(name = "UploadAvatarChunk")]
+pub fn upload_avatar_chunk(params: avatar_utils::AvatarChunkParams) -> avatar_utils::AvatarResult {
+    avatar_utils::upload_avatar_chunk(params)
+}
+
+#[update(name = "CommitAvatar")]
+pub fn commit_avatar(params: avatar_utils::CommitAvatarParams) -> avatar_utils::AvatarResult {
+    avatar_utils::commit_avatar(params)
+}
+
+#[update(name = "RemoveAvatar")]
+pub fn remove_avatar(token: String) -> avatar_utils::AvatarResult {
+    avatar_utils::remove_avatar(token)
+}
+
+#[query(name = "http_request")]
+pub fn http_request(request: http_utils::HttpRequest) -> http_utils::HttpResponse {
+    http_utils::http_request(request)
+}
+
+#[update(name = "http_request_update")]
+pub fn http_request_update(request: http_utils::HttpRequest) -> http_utils::HttpResponse {
+    http_utils::http_request_update(request)
+}
+
+#[update(name = "AddContact")]
+pub fn add_contact(params: profile_utils::ContactParams) -> profile_utils::ContactResult {
+    profile_utils::add_contact(params)
+}
+
+#[update(name = "RemoveContact")]
+pub fn remove_contact(params: profile_utils::ContactParams) -> profile_utils::ContactResult {
+    profile_utils::remove_contact(params)
+}
+
+#[query(name = "ListContacts")]
+pub fn list_contacts(token: String) -> profile_utils::ListContactsResult {
+    profile_utils::list_contacts(token)
+}
+
+#[update(name = "AddPasskeyRequest")]
+pub async fn add_passkey_request(token: String) -> id_utils::RequestResult {
+    id_utils::add_passkey_request(token).await
+}
+
+#[update(name = "AddPasskey")]
+pub fn add_passkey(params: id_utils::AddPasskeyParams) -> id_utils::PasskeyResult {
+    id_utils::add_passkey(params)
+}
+
+#[query(name = "ListPasskeys")]
+pub fn list_passkeys(token: String) -> id_utils::ListPasskeysResult {
+    id_utils::list_passkeys(token)
+}
+
+#[update(name = "RemovePasskeyRequest")]
+pub async fn remove_passkey_request(token: String) -> id_utils::RequestResult {
+    id_utils::remove_passkey_request(token).await
+}
+
+#[update(name = "RemovePasskey")]
+pub fn remove_passkey(params: id_utils::RemovePasskeyParams) -> id_utils::PasskeyResult {
+    id_utils::remove_passkey(params)
+}
+
+#[update(name = "RequestVerification")]
+pub async fn request_verification(params: verification_utils::RequestVerificationParams) -> verification_utils::VerificationResult {
+    verification_utils::request_verification(params).await
+}
+
+#[update(name = "ConfirmVerification")]
+pub fn confirm_verification(params: verification_utils::ConfirmVerificationParams) -> verification_utils::VerificationResult {
+    verification_utils::confirm_verification(params)
+}
+
+#[update(name = "SetVerificationSender")]
+pub fn set_verification_sender(sender: verification_utils::CodeSender) -> verification_utils::VerificationResult {
+    verification_utils::set_code_sender(sender)
+}
+
+#[query(name = "GetMockCode")]
+pub fn get_mock_code(target: String) -> Option<String> {
+    verification_utils::get_mock_code(target)
+}
+
+#[query(name = "transform")]
+pub fn transform(response: TransformArgs) -> HttpResponse {
+    verification_utils::transform(response)
+}
+
+#[update(name = "ClearLockout")]
+pub fn clear_lockout(params: lockout_utils::ClearLockoutParams) -> lockout_utils::ClearLockoutResult {
+    lockout_utils::clear_lockout(params)
+}
+
+#[query(name = "IsRenamePending")]
+pub fn is_rename_pending(user_name: String) -> bool {
+    cascade_utils::is_rename_pending(user_name)
+}
+
+#[query(name = "GetCascadeTasks")]
+pub fn get_cascade_tasks() -> Vec<cascade_utils::CascadeTaskInfo> {
+    cascade_utils::get_cascade_tasks()
+}
+
+#[update(name = "RetryCascadeTask")]
+pub fn retry_cascade_task(task_id: String) -> cascade_utils::RetryTaskResult {
+    cascade_utils::retry_cascade_task(task_id)
+}
+
+#[query(name = "ListUsers")]
+pub fn list_users(params: admin_utils::ListUsersParams) -> admin_utils::ListUsersResult {
+    admin_utils::list_users(params)
+}
+
+#[query(name = "GetUserInfo")]
+pub fn get_user_info(params: admin_utils::UserParams) -> admin_utils::UserInfoResult {
+    admin_utils::get_user_info(params)
+}
+
+#[update(name = "SuspendUser")]
+pub fn suspend_user(params: admin_utils::SuspendUserParams) -> admin_utils::SuspendUserResult {
+    admin_utils::suspend_user(params)
+}
+
+#[update(name = "UnsuspendUser")]
+pub fn unsuspend_user(params: admin_utils::UserParams) -> admin_utils::SuspendUserResult {
+    admin_utils::unsuspend_user(params)
+}
+
+#[update(name = "SetRoles")]
+pub fn set_roles(params: role_utils::SetRolesParams) -> role_utils::RolesResult {
+    role_utils::set_roles(params)
+}
+
+#[query(name = "GetRoles")]
+pub fn get_roles(user_name: String) -> role_utils::RolesResult {
+    role_utils::get_roles(user_name)
+}
+
+#[query(name = "ListPrivilegedUsers")]
+pub fn list_privileged_users() -> Vec<role_utils::UserRoles> {
+    role_utils::list_privileged_users()
+}
+
+#[query(name = "GetSecurityEvents")]
+pub fn get_security_events(token: String) -> audit_utils::SecurityEventsResult {
+    audit_utils::get_security_events(token)
+}
+
+#[query(name = "ExportSecurityEvents")]
+pub fn export_security_events(params: audit_utils::ExportSecurityEventsParams) -> audit_utils::ExportSecurityEventsResult {
+    audit_utils::export_security_events(params)
+}
+
+#[query(name = "ListSessions")]
+pub fn list_sessions(token: String) -> session_utils::ListSessionsResult {
+    session_utils::list_sessions(token)
+}
+
+#[update(name = "RevokeSession")]
+pub fn revoke_session(params: session_utils::RevokeSessionParams) -> session_utils::RevokeSessionResult {
+    session_utils::revoke_session(params)
+}
+
+#[query(name = "GetSigningKeys")]
+pub fn get_signing_keys() -> Vec<token_utils::PublicSigningKey> {
+    token_utils::get_signing_keys()
+}
+
+#[update(name = "SetRelyingParty")]
+pub fn set_relying_party(relying_party: webauthn_utils::RelyingParty) -> webauthn_utils::RelyingPartyResult {
+    webauthn_utils::set_relying_party(relying_party)
+}
+
+#[query(name = "GetRelyingParty")]
+pub fn get_relying_party() -> Option<webauthn_utils::RelyingParty> {
+    webauthn_utils::get_relying_party()
+}
+
+#[update(name = "RotateSigningKey")]
+pub async fn rotate_signing_key() -> token_utils::RotateKeyResult {
+    token_utils::rotate_signing_key().await
 }
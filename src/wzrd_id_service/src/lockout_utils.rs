@@ -0,0 +1,204 @@
+use candid::{Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk::export::candid::CandidType;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const FREE_FAILURES: u32 = 3; // before any delay applies
+const BASE_BACKOFF: u64 = 1_000_000_000; // 1 second, doubled on every further failure
+const MAX_BACKOFF: u64 = 900_000_000_000; // 15 minutes
+const LOCKOUT_THRESHOLD: u32 = 10;
+const LOCKOUT_PERIOD: u64 = 3_600_000_000_000; // 1 hour
+const FAILURE_WINDOW: u64 = 86_400_000_000_000; // counters reset after a quiet day
+const MAX_ANONYMOUS_CHALLENGES: usize = 10; // live sign-in challenges per account, each good for one attempt
+
+#[derive(Clone, Debug, Deserialize, CandidType, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AttemptKey {
+    User(String),
+    Caller(Principal)
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct Attempts {
+    failures: u32,
+    last_failure_at: u64,
+    blocked_until: u64
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ClearLockoutParams {
+    pub user_name: Option<String>,
+    pub principal: Option<Principal>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ClearLockoutResult {
+    pub error: String,
+    pub result: bool
+}
+
+pub type AttemptStore = BTreeMap<AttemptKey, Attempts>; //(username or caller => failed attempts)
+
+thread_local! {
+    pub static ATTEMPT_STORE: RefCell<AttemptStore> = RefCell::default();
+}
+
+// The anonymous principal is shared by every browser that hasn't signed in, so it's never
+// counted; `check_challenge_budget` bounds it instead
+fn attempt_keys(user_name: Option<&str>) -> Vec<AttemptKey> {
+    let mut keys: Vec<AttemptKey> = user_name.map(|user_name| AttemptKey::User(user_name.to_string())).into_iter().collect();
+    let caller = ic_cdk::caller();
+    if caller != Principal::anonymous() {
+        keys.push(AttemptKey::Caller(caller));
+    }
+    keys
+}
+
+fn block_duration(failures: u32) -> u64 {
+    if failures >= LOCKOUT_THRESHOLD {
+        LOCKOUT_PERIOD
+    }
+    else if failures > FREE_FAILURES {
+        BASE_BACKOFF.saturating_mul(1 << (failures - FREE_FAILURES - 1)).min(MAX_BACKOFF)
+    }
+    else{
+        0
+    }
+}
+
+// Returns an empty string when the attempt may go ahead, otherwise an error with the retry delay
+pub fn check_attempt(user_name: &str) -> String {
+    check_keys(attempt_keys(Some(user_name)))
+}
+
+// For steps that mustn't be blocked by failures in the user's name, like issuing challenges
+pub fn check_caller_attempt() -> String {
+    check_keys(attempt_keys(None))
+}
+
+// Signed-out callers share one principal, so they share a bound on the challenges live for an
+// account rather than a failure counter. Other callers have their own.
+pub fn check_challenge_budget(live_challenges: usize) -> String {
+    if ic_cdk::caller() == Principal::anonymous() && live_challenges >= MAX_ANONYMOUS_CHALLENGES {
+        "Too many sign-ins in progress for this account, try again in a few minutes".to_string()
+    }
+    else{
+        "".to_string()
+    }
+}
+
+fn check_keys(keys: Vec<AttemptKey>) -> String {
+    let now = time();
+    let blocked_until = ATTEMPT_STORE.with(|attempt_store| {
+        let attempt_store = attempt_store.borrow();
+        keys.iter()
+            .filter_map(|key| attempt_store.get(key))
+            .map(|attempts| attempts.blocked_until)
+            .max()
+            .unwrap_or(0)
+    });
+    if blocked_until > now {
+        let retry_after = (blocked_until - now + 999_999_999) / 1_000_000_000;
+        format!("Too many failed attempts, retry after {} seconds", retry_after)
+    }
+    else{
+        "".to_string()
+    }
+}
+
+// Only for failures after a challenge issued for the user was consumed, anything earlier is
+// just a request naming them and counts against the caller alone
+pub fn record_failure(user_name: &str) {
+    record_keys(attempt_keys(Some(user_name)))
+}
+
+pub fn record_caller_failure() {
+    record_keys(attempt_keys(None))
+}
+
+fn record_keys(keys: Vec<AttemptKey>) {
+    let now = time();
+    ATTEMPT_STORE.with(|attempt_store| {
+        let mut attempt_store = attempt_store.borrow_mut();
+        attempt_store.retain(|_, attempts| attempts.blocked_until > now || attempts.last_failure_at + FAILURE_WINDOW > now);
+        for key in keys {
+            let attempts = attempt_store.entry(key).or_insert(Attempts {
+                failures: 0,
+                last_failure_at: now,
+                blocked_until: 0
+            });
+            attempts.failures += 1;
+            attempts.last_failure_at = now;
+            attempts.blocked_until = now + block_duration(attempts.failures);
+        }
+    });
+}
+
+pub fn record_success(user_name: &str) {
+    ATTEMPT_STORE.with(|attempt_store| {
+        let mut attempt_store = attempt_store.borrow_mut();
+        for key in attempt_keys(Some(user_name)) {
+            attempt_store.remove(&key);
+        }
+    });
+}
+
+pub fn delete_user(user_name: &str) {
+    ATTEMPT_STORE.with(|attempt_store| {
+        attempt_store.borrow_mut().remove(&AttemptKey::User(user_name.to_string()));
+    });
+}
+
+pub fn clear_lockout(params: ClearLockoutParams) -> ClearLockoutResult {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return ClearLockoutResult{
+            error: "Only controllers can clear lockouts".to_string(),
+            result: false
+        };
+    }
+    let mut keys = vec![];
+    if let Some(user_name) = params.user_name {
+        keys.push(AttemptKey::User(user_name));
+    }
+    if let Some(principal) = params.principal {
+        keys.push(AttemptKey::Caller(principal));
+    }
+    let cleared = ATTEMPT_STORE.with(|attempt_store| {
+        let mut attempt_store = attempt_store.borrow_mut();
+        keys.iter().filter(|key| attempt_store.remove(key).is_some()).count()
+    });
+    ClearLockoutResult{
+        error: "".to_string(),
+        result: cleared > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_failures_are_free() {
+        for failures in 0..=FREE_FAILURES {
+            assert_eq!(block_duration(failures), 0);
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(block_duration(FREE_FAILURES + 1), BASE_BACKOFF);
+        assert_eq!(block_duration(FREE_FAILURES + 2), 2 * BASE_BACKOFF);
+        assert_eq!(block_duration(FREE_FAILURES + 3), 4 * BASE_BACKOFF);
+        for failures in FREE_FAILURES + 1..LOCKOUT_THRESHOLD {
+            assert!(block_duration(failures) <= MAX_BACKOFF);
+            assert!(block_duration(failures) >= block_duration(failures - 1));
+        }
+    }
+
+    #[test]
+    fn locks_out_from_the_threshold() {
+        assert!(block_duration(LOCKOUT_THRESHOLD - 1) < LOCKOUT_PERIOD);
+        assert_eq!(block_duration(LOCKOUT_THRESHOLD), LOCKOUT_PERIOD);
+        assert_eq!(block_duration(LOCKOUT_THRESHOLD + 50), LOCKOUT_PERIOD);
+    }
+}
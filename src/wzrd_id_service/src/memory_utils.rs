@@ -0,0 +1,80 @@
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _, Storable};
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Stores that grow with every user live in stable memory directly, so upgrades don't have to
+// copy them. Everything else is saved as one candid snapshot. Ids must never be reused.
+const SNAPSHOT: MemoryId = MemoryId::new(0);
+pub const KEYS: MemoryId = MemoryId::new(1);
+pub const AVATARS: MemoryId = MemoryId::new(2);
+pub const AUDIT_LOG: MemoryId = MemoryId::new(3);
+
+const WASM_PAGE_SIZE: u64 = 65_536;
+const MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Initialised on first use, which overwrites a snapshot saved by a release before the memory
+// manager, see `is_managed`
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+// A stable map value, stored candid encoded
+#[derive(Clone, Debug)]
+pub struct Candid<T>(pub T);
+
+impl<T: CandidType + DeserializeOwned> Storable for Candid<T> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).expect("Failed to encode stable value"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Candid(Decode!(bytes.as_ref(), T).expect("Failed to decode stable value"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+// Releases before the memory manager wrote their snapshot with `stable_save` from offset 0.
+// Must be checked before anything touches stable memory.
+pub fn is_managed() -> bool {
+    if ic_cdk::api::stable::stable64_size() == 0 {
+        return false;
+    }
+    let mut magic = [0; 3];
+    ic_cdk::api::stable::stable64_read(0, &mut magic);
+    &magic == MANAGER_MAGIC
+}
+
+// Length prefixed, in the snapshot's own memory
+pub fn write_snapshot(bytes: &[u8]) {
+    let memory = memory(SNAPSHOT);
+    let len = bytes.len() as u64;
+    let pages = (8 + len + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+    if memory.size() < pages && memory.grow(pages - memory.size()) < 0 {
+        ic_cdk::trap("Failed to grow stable memory for the snapshot");
+    }
+    memory.write(0, &len.to_le_bytes());
+    memory.write(8, bytes);
+}
+
+pub fn read_snapshot() -> Vec<u8> {
+    let memory = memory(SNAPSHOT);
+    if memory.size() == 0 {
+        return vec![];
+    }
+    let mut len = [0; 8];
+    memory.read(0, &mut len);
+    let mut bytes = vec![0; u64::from_le_bytes(len) as usize];
+    memory.read(8, &mut bytes);
+    bytes
+}
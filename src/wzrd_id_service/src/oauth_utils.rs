@@ -0,0 +1,482 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use candid::{Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk::export::candid::CandidType;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::audit_utils::{self, SecurityEventKind};
+use crate::challenge_utils::{self, Ceremony};
+use crate::id_utils::{self, RequestResult};
+use crate::token_utils::{self, Claims};
+use crate::{account_utils, session_utils};
+
+const AUTHORIZATION_TTL: u64 = 300_000_000_000; // 5 minutes to approve, same as the challenge
+const CODE_TTL: u64 = 60_000_000_000; // 1 minute to exchange the code
+const MAX_CLIENT_NAME_LENGTH: usize = 50;
+const MAX_NONCE_LENGTH: usize = 256;
+
+// Scopes a client can be granted, checked by chat and wallet against the `scope` claim. The
+// OpenID ones select what userinfo returns.
+pub const KNOWN_SCOPES: [&str; 9] = [
+    "openid", "profile", "email", "phone",
+    "chat:read", "chat:write",
+    "wallet:balance", "wallet:send", "wallet:manage"
+];
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub allowed_scopes: Vec<String>,
+    pub created_at: u64
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RegisterClientParams {
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub allowed_scopes: Vec<String>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ClientResult {
+    pub error: String,
+    pub result: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct AuthorizeRequestParams {
+    pub token: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: String, // space separated
+    pub code_challenge: String, // base64url SHA-256 of the client's code verifier (PKCE S256)
+    pub nonce: Option<String> // echoed in the id token of an `openid` grant
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct AuthorizeParams {
+    pub token: String,
+    pub key_id: String,
+    pub signature: String,
+    pub authenticator_data: String,
+    pub client_data_json: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct AuthorizeResult {
+    pub error: String,
+    pub code: String,
+    pub redirect_uri: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ExchangeCodeParams {
+    pub client_id: String,
+    pub code: String,
+    pub code_verifier: String,
+    pub redirect_uri: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ExchangeCodeResult {
+    pub error: String,
+    pub access_token: String,
+    pub refresh_token: String,
+    pub id_token: String // empty unless `openid` was granted
+}
+
+#[derive(Clone, Debug)]
+struct PendingAuthorization {
+    client_id: String,
+    redirect_uri: String,
+    scope: String,
+    code_challenge: String,
+    nonce: Option<String>,
+    expires_at: u64
+}
+
+#[derive(Clone, Debug)]
+struct AuthorizationCode {
+    user_name: String,
+    key_id: String,
+    authorized_at: u64,
+    grant: PendingAuthorization
+}
+
+pub type ClientStore = BTreeMap<String, OAuthClient>; //(client id => client)
+type PendingAuthorizationStore = BTreeMap<String, PendingAuthorization>; //(user_name => request awaiting approval)
+type CodeStore = BTreeMap<String, AuthorizationCode>; //(code => approved grant)
+
+thread_local! {
+    pub static CLIENT_STORE: RefCell<ClientStore> = RefCell::default();
+    static PENDING_AUTHORIZATION_STORE: RefCell<PendingAuthorizationStore> = RefCell::default();
+    static CODE_STORE: RefCell<CodeStore> = RefCell::default();
+}
+
+async fn random_string() -> Result<String, String> {
+    match ic_cdk::call::<(), (Vec<u8>,)>(Principal::management_canister(), "raw_rand", ()).await {
+        Ok((entropy,)) => Ok(URL_SAFE_NO_PAD.encode(entropy)),
+        Err((_, error)) => Err(error)
+    }
+}
+
+// Deduplicated and sorted, so that the claim reads the same however the client asked
+fn normalize_scope(scope: &str) -> String {
+    scope.split_whitespace().collect::<BTreeSet<&str>>().into_iter().collect::<Vec<&str>>().join(" ")
+}
+
+pub fn get_client(client_id: String) -> Option<OAuthClient> {
+    CLIENT_STORE.with(|client_store| client_store.borrow().get(&client_id).cloned())
+}
+
+pub async fn register_client(params: RegisterClientParams) -> ClientResult {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return ClientResult {
+            error: "Only controllers can register clients".to_string(),
+            result: "".to_string()
+        };
+    }
+    let name = params.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_CLIENT_NAME_LENGTH {
+        return ClientResult {
+            error: format!("Client name must be 1 to {} characters", MAX_CLIENT_NAME_LENGTH),
+            result: "".to_string()
+        };
+    }
+    if params.redirect_uris.is_empty() || params.redirect_uris.iter().any(|uri| !uri.starts_with("https://")) {
+        return ClientResult {
+            error: "Redirect URIs must be https URLs".to_string(),
+            result: "".to_string()
+        };
+    }
+    if let Some(scope) = params.allowed_scopes.iter().find(|scope| !KNOWN_SCOPES.contains(&scope.as_str())) {
+        return ClientResult {
+            error: format!("Unknown scope: {}", scope),
+            result: "".to_string()
+        };
+    }
+    let client_id = match random_string().await {
+        Ok(client_id) => client_id,
+        Err(error) => return ClientResult {
+            error,
+            result: "".to_string()
+        }
+    };
+    CLIENT_STORE.with(|client_store| {
+        client_store.borrow_mut().insert(client_id.clone(), OAuthClient {
+            client_id: client_id.clone(),
+            name,
+            redirect_uris: params.redirect_uris,
+            allowed_scopes: params.allowed_scopes,
+            created_at: time()
+        });
+    });
+    ClientResult {
+        error: "".to_string(),
+        result: client_id
+    }
+}
+
+// Removing a client signs it out of every account that approved it
+pub fn remove_client(client_id: String) -> ClientResult {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return ClientResult {
+            error: "Only controllers can remove clients".to_string(),
+            result: "".to_string()
+        };
+    }
+    if CLIENT_STORE.with(|client_store| client_store.borrow_mut().remove(&client_id)).is_none() {
+        return ClientResult {
+            error: "Unknown client".to_string(),
+            result: "".to_string()
+        };
+    }
+    session_utils::end_client_sessions(&client_id);
+    ClientResult {
+        error: "".to_string(),
+        result: client_id
+    }
+}
+
+// Checks the client's request on behalf of the signed-in user and returns the passkey
+// challenge that approves it
+pub async fn authorize_request(params: AuthorizeRequestParams) -> RequestResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return RequestResult{
+            error: "Invalid token".to_string(),
+            result: "".to_string()
+        }
+    };
+    let client = match get_client(params.client_id.clone()) {
+        Some(client) => client,
+        None => return RequestResult{
+            error: "Unknown client".to_string(),
+            result: "".to_string()
+        }
+    };
+    if !client.redirect_uris.contains(&params.redirect_uri) {
+        return RequestResult{
+            error: "Redirect URI is not registered for this client".to_string(),
+            result: "".to_string()
+        };
+    }
+    let scope = normalize_scope(&params.scope);
+    if scope.is_empty() {
+        return RequestResult{
+            error: "No scope requested".to_string(),
+            result: "".to_string()
+        };
+    }
+    if let Some(scope) = scope.split(' ').find(|scope| !client.allowed_scopes.iter().any(|allowed| allowed == scope)) {
+        return RequestResult{
+            error: format!("Scope not allowed for this client: {}", scope),
+            result: "".to_string()
+        };
+    }
+    if URL_SAFE_NO_PAD.decode(&params.code_challenge).map(|digest| digest.len()) != Ok(32) {
+        return RequestResult{
+            error: "Code challenge must be a base64url SHA-256 digest".to_string(),
+            result: "".to_string()
+        };
+    }
+    if params.nonce.as_ref().map_or(false, |nonce| nonce.len() > MAX_NONCE_LENGTH) {
+        return RequestResult{
+            error: format!("Nonce must be at most {} characters", MAX_NONCE_LENGTH),
+            result: "".to_string()
+        };
+    }
+    match challenge_utils::issue_challenge(claims.username.clone(), Ceremony::Authorize).await {
+        Ok(challenge) => {
+            PENDING_AUTHORIZATION_STORE.with(|pending_store| {
+                pending_store.borrow_mut().insert(claims.username, PendingAuthorization {
+                    client_id: params.client_id,
+                    redirect_uri: params.redirect_uri,
+                    scope,
+                    code_challenge: params.code_challenge,
+                    nonce: params.nonce,
+                    expires_at: time() + AUTHORIZATION_TTL
+                });
+            });
+            RequestResult{
+                error: "".to_string(),
+                result: challenge
+            }
+        }
+        Err(error) => RequestResult{
+            error,
+            result: "".to_string()
+        }
+    }
+}
+
+fn authorize_error(error: String) -> AuthorizeResult {
+    AuthorizeResult {
+        error,
+        code: "".to_string(),
+        redirect_uri: "".to_string()
+    }
+}
+
+// Approves the pending request with a passkey assertion and returns the code for the client
+pub async fn authorize(params: AuthorizeParams) -> AuthorizeResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return authorize_error("Invalid token".to_string())
+    };
+    let error = id_utils::verify_passkey(
+        &claims.username,
+        &params.key_id,
+        &params.signature,
+        &params.authenticator_data,
+        &params.client_data_json,
+        Ceremony::Authorize
+    );
+    if !error.is_empty() {
+        return authorize_error(error);
+    }
+    let grant = PENDING_AUTHORIZATION_STORE.with(|pending_store| pending_store.borrow_mut().remove(&claims.username));
+    let grant = match grant {
+        Some(grant) if grant.expires_at > time() => grant,
+        _ => return authorize_error("No authorization request pending".to_string())
+    };
+    let code = match random_string().await {
+        Ok(code) => code,
+        Err(error) => return authorize_error(error)
+    };
+    audit_utils::record(&claims.username, SecurityEventKind::ClientAuthorized, format!("{} {}", grant.client_id, grant.scope));
+    let redirect_uri = grant.redirect_uri.clone();
+    CODE_STORE.with(|code_store| {
+        let now = time();
+        let mut code_store = code_store.borrow_mut();
+        code_store.retain(|_, code| code.grant.expires_at > now);
+        code_store.insert(code.clone(), AuthorizationCode {
+            user_name: claims.username,
+            key_id: params.key_id,
+            authorized_at: now,
+            grant: PendingAuthorization {
+                expires_at: now + CODE_TTL,
+                ..grant
+            }
+        });
+    });
+    AuthorizeResult {
+        error: "".to_string(),
+        code,
+        redirect_uri
+    }
+}
+
+fn exchange_error(error: String) -> ExchangeCodeResult {
+    ExchangeCodeResult {
+        error,
+        access_token: "".to_string(),
+        refresh_token: "".to_string(),
+        id_token: "".to_string()
+    }
+}
+
+// The code is taken out of the store whatever the outcome, so a failed attempt burns it too
+fn redeem_code(code_store: &mut CodeStore, params: &ExchangeCodeParams, now: u64) -> Option<AuthorizationCode> {
+    let code = code_store.remove(&params.code)?;
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(params.code_verifier.as_bytes()));
+    if code.grant.expires_at <= now
+        || code.grant.client_id != params.client_id
+        || code.grant.redirect_uri != params.redirect_uri
+        || code.grant.code_challenge != challenge {
+        return None;
+    }
+    Some(code)
+}
+
+// Called by the client app, over `ExchangeCode` or the HTTP token endpoint. Codes are single use
+// and bound to the PKCE verifier, so an intercepted code is useless on its own.
+pub fn exchange_code(params: ExchangeCodeParams) -> ExchangeCodeResult {
+    let invalid_grant = || exchange_error("Invalid authorization code".to_string());
+    let code = match CODE_STORE.with(|code_store| redeem_code(&mut code_store.borrow_mut(), &params, time())) {
+        Some(code) if id_utils::has_key(&code.user_name, &code.key_id) => code,
+        _ => return invalid_grant()
+    };
+    let client = match get_client(params.client_id) {
+        Some(client) => client,
+        None => return invalid_grant()
+    };
+    let session_id = session_utils::create_session(
+        code.user_name.clone(),
+        code.key_id.clone(),
+        Some(client.name),
+        Some(client.client_id.clone())
+    );
+    let openid = code.grant.scope.split(' ').any(|scope| scope == "openid");
+    let tokens = id_utils::issue_tokens(
+        code.user_name.clone(),
+        code.key_id,
+        session_id,
+        Some(code.grant.scope),
+        Some(client.client_id.clone())
+    );
+    if !tokens.error.is_empty() {
+        return exchange_error(tokens.error);
+    }
+    let id_token = if openid {
+        let id_claims = token_utils::new_id_token_claims(
+            code.user_name.clone(),
+            account_utils::user_id(&code.user_name),
+            client.client_id,
+            code.grant.nonce,
+            code.authorized_at
+        );
+        match token_utils::sign_claims(&id_claims) {
+            Some(id_token) => id_token,
+            None => return exchange_error("Token signing key not ready".to_string())
+        }
+    }
+    else{
+        "".to_string()
+    };
+    ExchangeCodeResult {
+        error: "".to_string(),
+        access_token: tokens.access_token,
+        refresh_token: tokens.refresh_token,
+        id_token
+    }
+}
+
+// First-party tokens carry no scope and grant everything
+pub fn has_scope(claims: &Claims, scope: &str) -> bool {
+    match &claims.scope {
+        Some(granted) => granted.split(' ').any(|granted| granted == scope),
+        None => true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From RFC 7636, appendix B
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+    const REDIRECT_URI: &str = "https://app.example/callback";
+
+    fn code_store() -> CodeStore {
+        let mut code_store = CodeStore::new();
+        code_store.insert("code".to_string(), AuthorizationCode {
+            user_name: "alice".to_string(),
+            key_id: "key".to_string(),
+            authorized_at: 1_000,
+            grant: PendingAuthorization {
+                client_id: "client".to_string(),
+                redirect_uri: REDIRECT_URI.to_string(),
+                scope: "openid chat:read".to_string(),
+                code_challenge: CHALLENGE.to_string(),
+                nonce: None,
+                expires_at: 1_000 + CODE_TTL
+            }
+        });
+        code_store
+    }
+
+    fn params(code_verifier: &str, redirect_uri: &str) -> ExchangeCodeParams {
+        ExchangeCodeParams {
+            client_id: "client".to_string(),
+            code: "code".to_string(),
+            code_verifier: code_verifier.to_string(),
+            redirect_uri: redirect_uri.to_string()
+        }
+    }
+
+    #[test]
+    fn redeems_code_once() {
+        let mut code_store = code_store();
+        let code = redeem_code(&mut code_store, &params(VERIFIER, REDIRECT_URI), 2_000).unwrap();
+        assert_eq!(code.user_name, "alice");
+        assert!(redeem_code(&mut code_store, &params(VERIFIER, REDIRECT_URI), 2_000).is_none());
+    }
+
+    #[test]
+    fn rejects_wrong_verifier_and_burns_the_code() {
+        let mut code_store = code_store();
+        assert!(redeem_code(&mut code_store, &params("not-the-verifier", REDIRECT_URI), 2_000).is_none());
+        assert!(code_store.is_empty());
+        assert!(redeem_code(&mut code_store, &params(VERIFIER, REDIRECT_URI), 2_000).is_none());
+    }
+
+    #[test]
+    fn rejects_expired_code() {
+        assert!(redeem_code(&mut code_store(), &params(VERIFIER, REDIRECT_URI), 1_000 + CODE_TTL).is_none());
+    }
+
+    #[test]
+    fn rejects_other_redirect_uri_and_client() {
+        assert!(redeem_code(&mut code_store(), &params(VERIFIER, "https://app.example/other"), 2_000).is_none());
+        let other_client = ExchangeCodeParams {
+            client_id: "other".to_string(),
+            ..params(VERIFIER, REDIRECT_URI)
+        };
+        assert!(redeem_code(&mut code_store(), &other_client, 2_000).is_none());
+    }
+}
@@ -0,0 +1,138 @@
+use candid::{Deserialize, Principal};
+use ic_cdk::export::candid::CandidType;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use crate::audit_utils::{self, SecurityEventKind};
+use crate::id_utils;
+
+const MAX_PRINCIPALS_PER_USER: usize = 10;
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct UnlinkPrincipalParams {
+    pub token: String,
+    pub principal: Principal
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct PrincipalResult {
+    pub error: String,
+    pub result: bool
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ListPrincipalsResult {
+    pub error: String,
+    pub result: Vec<Principal>
+}
+
+pub type PrincipalStore = BTreeMap<Principal, String>; //(principal => user_name)
+
+thread_local! {
+    pub static PRINCIPAL_STORE: RefCell<PrincipalStore> = RefCell::default();
+}
+
+fn user_principals(user_name: &str) -> Vec<Principal> {
+    PRINCIPAL_STORE.with(|principal_store| {
+        principal_store.borrow().iter()
+            .filter(|(_, linked_user)| *linked_user == user_name)
+            .map(|(principal, _)| *principal)
+            .collect()
+    })
+}
+
+// Binds the calling principal to the account the token was issued for
+pub fn link_principal(token: String) -> PrincipalResult {
+    let claims = match id_utils::verify_token(&token) {
+        Some(claims) => claims,
+        None => return PrincipalResult{
+            error: "Invalid token".to_string(),
+            result: false
+        }
+    };
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return PrincipalResult{
+            error: "Anonymous principal can't be linked".to_string(),
+            result: false
+        };
+    }
+    match PRINCIPAL_STORE.with(|principal_store| principal_store.borrow().get(&caller).cloned()) {
+        Some(user_name) if user_name == claims.username => return PrincipalResult{
+            error: "".to_string(),
+            result: true
+        },
+        Some(_) => return PrincipalResult{
+            error: "Principal is linked to another user".to_string(),
+            result: false
+        },
+        None => {}
+    }
+    if user_principals(&claims.username).len() >= MAX_PRINCIPALS_PER_USER {
+        return PrincipalResult{
+            error: "Too many linked principals".to_string(),
+            result: false
+        };
+    }
+    audit_utils::record(&claims.username, SecurityEventKind::PrincipalLinked, caller.to_string());
+    PRINCIPAL_STORE.with(|principal_store| principal_store.borrow_mut().insert(caller, claims.username));
+    PrincipalResult{
+        error: "".to_string(),
+        result: true
+    }
+}
+
+pub fn unlink_principal(params: UnlinkPrincipalParams) -> PrincipalResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return PrincipalResult{
+            error: "Invalid token".to_string(),
+            result: false
+        }
+    };
+    PRINCIPAL_STORE.with(|principal_store| {
+        let mut principal_store = principal_store.borrow_mut();
+        if principal_store.get(&params.principal) == Some(&claims.username) {
+            principal_store.remove(&params.principal);
+            audit_utils::record(&claims.username, SecurityEventKind::PrincipalUnlinked, params.principal.to_string());
+            PrincipalResult{
+                error: "".to_string(),
+                result: true
+            }
+        }
+        else{
+            PrincipalResult{
+                error: "Principal isn't linked".to_string(),
+                result: false
+            }
+        }
+    })
+}
+
+pub fn list_principals(token: String) -> ListPrincipalsResult {
+    match id_utils::verify_token(&token) {
+        Some(claims) => ListPrincipalsResult{
+            error: "".to_string(),
+            result: user_principals(&claims.username)
+        },
+        None => ListPrincipalsResult{
+            error: "Invalid token".to_string(),
+            result: vec![]
+        }
+    }
+}
+
+pub fn resolve_principal(principal: Principal) -> Option<String> {
+    PRINCIPAL_STORE.with(|principal_store| principal_store.borrow().get(&principal).cloned())
+}
+
+pub fn rename_user(old_user_name: &str, new_user_name: &str) {
+    PRINCIPAL_STORE.with(|principal_store| {
+        for linked_user in principal_store.borrow_mut().values_mut().filter(|linked_user| *linked_user == old_user_name) {
+            *linked_user = new_user_name.to_string();
+        }
+    });
+}
+
+pub fn unlink_user(user_name: &str) {
+    PRINCIPAL_STORE.with(|principal_store| principal_store.borrow_mut().retain(|_, linked_user| linked_user != user_name));
+}
@@ -0,0 +1,544 @@
+use candid::Deserialize;
+use ic_cdk::api::time;
+use ic_cdk::export::candid::CandidType;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::audit_utils::{self, SecurityEventKind};
+use crate::verification_utils::{self, Channel};
+use crate::{avatar_utils, id_utils};
+
+const MAX_NAME_LENGTH: usize = 50;
+const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_PHONE_LENGTH: usize = 16; // E.164, with the leading +
+const MAX_DISPLAY_NAME_LENGTH: usize = 50;
+const MAX_BIO_LENGTH: usize = 300;
+const MAX_LINKS: usize = 5;
+const MAX_LINK_LENGTH: usize = 200;
+const MAX_STATUS_LENGTH: usize = 100;
+const MAX_EMOJI_LENGTH: usize = 8; // chars, so that sequences with modifiers fit
+
+#[derive(Clone, Debug, Default, Deserialize, CandidType)]
+pub struct Profile {
+    pub phone: Option<String>,
+    pub email: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub email_verified: Option<bool>,
+    pub phone_verified: Option<bool>,
+    pub discoverable: Option<bool>, // listed by `SearchUsers` unless false
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub links: Option<Vec<String>>,
+    pub status: Option<Status>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct Status {
+    pub text: String,
+    pub emoji: Option<String>,
+    pub expires_at: Option<u64>
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, CandidType, PartialEq, Eq)]
+pub enum Visibility {
+    Public,
+    Contacts,
+    Private
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ProfileVisibility {
+    pub phone: Visibility,
+    pub email: Visibility,
+    pub first_name: Visibility,
+    pub last_name: Visibility
+}
+
+// Names are shown to everyone, contact details only to the owner until they opt in
+impl Default for ProfileVisibility {
+    fn default() -> Self {
+        ProfileVisibility {
+            phone: Visibility::Private,
+            email: Visibility::Private,
+            first_name: Visibility::Public,
+            last_name: Visibility::Public
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct SetProfileParams {
+    pub token: String,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub visibility: Option<ProfileVisibility>,
+    // These keep their previous value when omitted, an empty value clears them
+    pub discoverable: Option<bool>,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub links: Option<Vec<String>>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct SetStatusParams {
+    pub token: String,
+    pub status: Option<Status> // None clears the status
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct SetProfileResult{
+    pub error: String,
+    pub result: bool
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct GetProfileParams {
+    pub token: Option<String>,
+    pub user_name: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct GetProfileResult{
+    pub error: String,
+    pub result: Option<Profile>,
+    pub visibility: Option<ProfileVisibility>, // only returned to the owner
+    pub avatar_url: Option<String>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ContactParams {
+    pub token: String,
+    pub user_name: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ContactResult {
+    pub error: String,
+    pub result: bool
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ListContactsResult {
+    pub error: String,
+    pub result: Vec<String>
+}
+
+pub type ProfileStore = BTreeMap<String, Profile>; //(user_name => profile)
+pub type VisibilityStore = BTreeMap<String, ProfileVisibility>; //(user_name => field visibility)
+pub type ContactStore = BTreeMap<String, BTreeSet<String>>; //(user_name => contacts allowed to see `Contacts` fields)
+
+thread_local! {
+    pub static PROFILE_STORE: RefCell<ProfileStore> = RefCell::default();
+    pub static VISIBILITY_STORE: RefCell<VisibilityStore> = RefCell::default();
+    pub static CONTACT_STORE: RefCell<ContactStore> = RefCell::default();
+}
+
+fn is_contact(owner: &str, viewer: &str) -> bool {
+    CONTACT_STORE.with(|contact_store| {
+        match contact_store.borrow().get(owner) {
+            Some(contacts) => contacts.contains(viewer),
+            None => false
+        }
+    })
+}
+
+fn get_visibility(user_name: &str) -> ProfileVisibility {
+    VISIBILITY_STORE.with(|visibility_store| visibility_store.borrow().get(user_name).cloned().unwrap_or_default())
+}
+
+fn check_length(field: &str, value: &str, max_length: usize) -> Result<(), String> {
+    if value.chars().count() > max_length {
+        return Err(format!("{} is longer than {} characters", field, max_length));
+    }
+    if value.chars().any(|c| c.is_control() && c != '\n') {
+        return Err(format!("{} contains control characters", field));
+    }
+    Ok(())
+}
+
+fn check_name(field: &str, name: &str) -> Result<(), String> {
+    check_length(field, name, MAX_NAME_LENGTH)?;
+    if name.contains('\n') {
+        return Err(format!("{} must be a single line", field));
+    }
+    Ok(())
+}
+
+// Empty clears the field, anything else must be something a code could be sent to
+fn check_contact(contact: &str, channel: Channel) -> Result<(), String> {
+    let (field, max_length) = match channel {
+        Channel::Email => ("Email", MAX_EMAIL_LENGTH),
+        Channel::Phone => ("Phone", MAX_PHONE_LENGTH)
+    };
+    check_length(field, contact, max_length)?;
+    if !contact.is_empty() && (contact.contains(char::is_whitespace) || !verification_utils::is_valid_target(contact, channel)) {
+        return Err(match channel {
+            Channel::Email => format!("Email is not a valid address: {}", contact),
+            Channel::Phone => format!("Phone must be in international format, like +14155550100: {}", contact)
+        });
+    }
+    Ok(())
+}
+
+fn check_links(links: &[String]) -> Result<(), String> {
+    if links.len() > MAX_LINKS {
+        return Err(format!("At most {} links are allowed", MAX_LINKS));
+    }
+    for link in links {
+        check_length("Link", link, MAX_LINK_LENGTH)?;
+        if !link.starts_with("https://") || link.contains(char::is_whitespace) {
+            return Err(format!("Link must be an https URL: {}", link));
+        }
+    }
+    Ok(())
+}
+
+fn check_status(status: &Status) -> Result<(), String> {
+    check_length("Status", &status.text, MAX_STATUS_LENGTH)?;
+    if let Some(emoji) = &status.emoji {
+        check_length("Emoji", emoji, MAX_EMOJI_LENGTH)?;
+    }
+    if status.expires_at.map_or(false, |expires_at| expires_at <= time()) {
+        return Err("Status expiry is in the past".to_string());
+    }
+    Ok(())
+}
+
+// Trimmed value, or None for an empty one so that it clears the field
+fn non_empty(value: String) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() { None } else { Some(value.to_string()) }
+}
+
+fn active_status(status: Option<Status>) -> Option<Status> {
+    let now = time();
+    status.filter(|status| status.expires_at.map_or(true, |expires_at| expires_at > now))
+}
+
+pub fn set_profile(params: SetProfileParams) -> SetProfileResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return SetProfileResult{
+            error: "Invalid token".to_string(),
+            result: false
+        }
+    };
+    let checks = [
+        params.first_name.as_ref().map(|first_name| check_name("First name", first_name)),
+        params.last_name.as_ref().map(|last_name| check_name("Last name", last_name)),
+        params.email.as_ref().map(|email| check_contact(email, Channel::Email)),
+        params.phone.as_ref().map(|phone| check_contact(phone, Channel::Phone)),
+        params.display_name.as_ref().map(|display_name| check_length("Display name", display_name, MAX_DISPLAY_NAME_LENGTH)),
+        params.bio.as_ref().map(|bio| check_length("Bio", bio, MAX_BIO_LENGTH)),
+        params.links.as_ref().map(|links| check_links(links))
+    ];
+    if let Some(Err(error)) = checks.into_iter().flatten().find(|check| check.is_err()) {
+        return SetProfileResult{
+            error,
+            result: false
+        };
+    }
+    audit_utils::record(&claims.username, SecurityEventKind::ProfileUpdated, "".to_string());
+    PROFILE_STORE.with(|profile_store| {
+        let mut profile_store = profile_store.borrow_mut();
+        let old = profile_store.get(&claims.username).cloned();
+        // Verification survives only as long as the verified value is kept
+        let (email_verified, phone_verified) = match &old {
+            Some(old) => (
+                old.email_verified.filter(|_| old.email == params.email),
+                old.phone_verified.filter(|_| old.phone == params.phone)
+            ),
+            None => (None, None)
+        };
+        let (discoverable, display_name, bio, links, status) = match old {
+            Some(old) => (old.discoverable, old.display_name, old.bio, old.links, old.status),
+            None => (None, None, None, None, None)
+        };
+        profile_store.insert(claims.username.clone(), Profile {
+            first_name: params.first_name,
+            last_name: params.last_name,
+            phone: params.phone,
+            email: params.email,
+            email_verified,
+            phone_verified,
+            discoverable: params.discoverable.or(discoverable),
+            display_name: match params.display_name {
+                Some(display_name) => non_empty(display_name),
+                None => display_name
+            },
+            bio: match params.bio {
+                Some(bio) => non_empty(bio),
+                None => bio
+            },
+            links: match params.links {
+                Some(links) if links.is_empty() => None,
+                Some(links) => Some(links),
+                None => links
+            },
+            status
+        });
+    });
+    if let Some(visibility) = params.visibility {
+        VISIBILITY_STORE.with(|visibility_store| {
+            visibility_store.borrow_mut().insert(claims.username, visibility);
+        });
+    }
+    SetProfileResult{
+        error: "".to_string(),
+        result: true
+    }
+}
+
+pub fn set_status(params: SetStatusParams) -> SetProfileResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return SetProfileResult{
+            error: "Invalid token".to_string(),
+            result: false
+        }
+    };
+    if let Some(Err(error)) = params.status.as_ref().map(check_status) {
+        return SetProfileResult{
+            error,
+            result: false
+        };
+    }
+    let status = params.status.filter(|status| !status.text.trim().is_empty() || status.emoji.is_some());
+    PROFILE_STORE.with(|profile_store| {
+        let mut profile_store = profile_store.borrow_mut();
+        profile_store.entry(claims.username).or_default().status = status;
+    });
+    SetProfileResult{
+        error: "".to_string(),
+        result: true
+    }
+}
+
+// Anonymous callers see `Public` fields, the owner's contacts also see `Contacts` fields.
+// Display name, bio, links, status and avatar are always public.
+pub fn get_profile(params: GetProfileParams) -> GetProfileResult {
+    if !id_utils::has_user(&params.user_name) {
+        return GetProfileResult{
+            error: "Username doesn't exist".to_string(),
+            result: None,
+            visibility: None,
+            avatar_url: None
+        };
+    }
+    let viewer = match params.token {
+        Some(token) => match id_utils::verify_token(&token) {
+            Some(claims) => Some(claims.username),
+            None => return GetProfileResult{
+                error: "Invalid token".to_string(),
+                result: None,
+                visibility: None,
+                avatar_url: None
+            }
+        },
+        None => None
+    };
+    let mut profile = match PROFILE_STORE.with(|profile_store| profile_store.borrow().get(&params.user_name).cloned()) {
+        Some(profile) => profile,
+        None => return GetProfileResult{
+            error: "User profile doesn't exist".to_string(),
+            result: None,
+            visibility: None,
+            avatar_url: None
+        }
+    };
+    profile.status = active_status(profile.status);
+    let avatar_url = avatar_utils::avatar_url(&params.user_name);
+    let visibility = get_visibility(&params.user_name);
+    if viewer.as_deref() == Some(params.user_name.as_str()) {
+        return GetProfileResult{
+            error: "".to_string(),
+            result: Some(profile),
+            visibility: Some(visibility),
+            avatar_url
+        };
+    }
+
+    let contact = match &viewer {
+        Some(viewer) => is_contact(&params.user_name, viewer),
+        None => false
+    };
+    let visible = |visibility: Visibility| {
+        match visibility {
+            Visibility::Public => true,
+            Visibility::Contacts => contact,
+            Visibility::Private => false
+        }
+    };
+    GetProfileResult{
+        error: "".to_string(),
+        result: Some(Profile {
+            phone: profile.phone.filter(|_| visible(visibility.phone)),
+            email: profile.email.filter(|_| visible(visibility.email)),
+            first_name: profile.first_name.filter(|_| visible(visibility.first_name)),
+            last_name: profile.last_name.filter(|_| visible(visibility.last_name)),
+            email_verified: profile.email_verified.filter(|_| visible(visibility.email)),
+            phone_verified: profile.phone_verified.filter(|_| visible(visibility.phone)),
+            discoverable: None,
+            display_name: profile.display_name,
+            bio: profile.bio,
+            links: profile.links,
+            status: profile.status
+        }),
+        visibility: None,
+        avatar_url
+    }
+}
+
+pub fn is_discoverable(user_name: &str) -> bool {
+    PROFILE_STORE.with(|profile_store| {
+        profile_store.borrow().get(user_name).and_then(|profile| profile.discoverable).unwrap_or(true)
+    })
+}
+
+// The chosen display name, or else first and last name as shown to anyone
+pub fn public_display_name(user_name: &str) -> Option<String> {
+    let profile = PROFILE_STORE.with(|profile_store| profile_store.borrow().get(user_name).cloned())?;
+    if profile.display_name.is_some() {
+        return profile.display_name;
+    }
+    let visibility = get_visibility(user_name);
+    let names: Vec<String> = [
+        profile.first_name.filter(|_| visibility.first_name == Visibility::Public),
+        profile.last_name.filter(|_| visibility.last_name == Visibility::Public)
+    ].into_iter().flatten().filter(|name| !name.trim().is_empty()).collect();
+    if names.is_empty() {
+        None
+    }
+    else{
+        Some(names.join(" "))
+    }
+}
+
+pub fn add_contact(params: ContactParams) -> ContactResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return ContactResult{
+            error: "Invalid token".to_string(),
+            result: false
+        }
+    };
+    if !id_utils::has_user(&params.user_name) {
+        return ContactResult{
+            error: "Username doesn't exist".to_string(),
+            result: false
+        };
+    }
+    CONTACT_STORE.with(|contact_store| {
+        contact_store.borrow_mut().entry(claims.username).or_default().insert(params.user_name);
+    });
+    ContactResult{
+        error: "".to_string(),
+        result: true
+    }
+}
+
+pub fn remove_contact(params: ContactParams) -> ContactResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return ContactResult{
+            error: "Invalid token".to_string(),
+            result: false
+        }
+    };
+    let removed = CONTACT_STORE.with(|contact_store| {
+        match contact_store.borrow_mut().get_mut(&claims.username) {
+            Some(contacts) => contacts.remove(&params.user_name),
+            None => false
+        }
+    });
+    if removed {
+        ContactResult{
+            error: "".to_string(),
+            result: true
+        }
+    }
+    else{
+        ContactResult{
+            error: "Contact doesn't exist".to_string(),
+            result: false
+        }
+    }
+}
+
+pub fn list_contacts(token: String) -> ListContactsResult {
+    let claims = match id_utils::verify_token(&token) {
+        Some(claims) => claims,
+        None => return ListContactsResult{
+            error: "Invalid token".to_string(),
+            result: vec![]
+        }
+    };
+    CONTACT_STORE.with(|contact_store| {
+        ListContactsResult{
+            error: "".to_string(),
+            result: contact_store.borrow().get(&claims.username).map(|contacts| contacts.iter().cloned().collect()).unwrap_or_default()
+        }
+    })
+}
+
+pub fn rename_profile(old_user_name: &str, new_user_name: &str) {
+    avatar_utils::rename_avatar(old_user_name, new_user_name);
+    PROFILE_STORE.with(|profile_store| {
+        let mut profile_store = profile_store.borrow_mut();
+        if let Some(profile) = profile_store.remove(old_user_name) {
+            profile_store.insert(new_user_name.to_string(), profile);
+        }
+    });
+    VISIBILITY_STORE.with(|visibility_store| {
+        let mut visibility_store = visibility_store.borrow_mut();
+        if let Some(visibility) = visibility_store.remove(old_user_name) {
+            visibility_store.insert(new_user_name.to_string(), visibility);
+        }
+    });
+    CONTACT_STORE.with(|contact_store| {
+        let mut contact_store = contact_store.borrow_mut();
+        if let Some(contacts) = contact_store.remove(old_user_name) {
+            contact_store.insert(new_user_name.to_string(), contacts);
+        }
+        for contacts in contact_store.values_mut() {
+            if contacts.remove(old_user_name) {
+                contacts.insert(new_user_name.to_string());
+            }
+        }
+    });
+}
+
+// Drops the user's profile, avatar and contacts, and removes them from everyone else's contacts
+pub fn delete_profile(user_name: &str) {
+    avatar_utils::delete_avatar(user_name);
+    PROFILE_STORE.with(|profile_store| profile_store.borrow_mut().remove(user_name));
+    VISIBILITY_STORE.with(|visibility_store| visibility_store.borrow_mut().remove(user_name));
+    CONTACT_STORE.with(|contact_store| {
+        let mut contact_store = contact_store.borrow_mut();
+        contact_store.remove(user_name);
+        for contacts in contact_store.values_mut() {
+            contacts.remove(user_name);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_names_and_contacts() {
+        assert!(check_name("First name", "Ada").is_ok());
+        assert!(check_name("First name", "Ada\nLovelace").is_err());
+        assert!(check_name("First name", &"a".repeat(MAX_NAME_LENGTH + 1)).is_err());
+        assert!(check_contact("", Channel::Email).is_ok());
+        assert!(check_contact("ada@example.com", Channel::Email).is_ok());
+        assert!(check_contact("ada @example.com", Channel::Email).is_err());
+        assert!(check_contact(&format!("{}@example.com", "a".repeat(MAX_EMAIL_LENGTH)), Channel::Email).is_err());
+        assert!(check_contact("+14155550100", Channel::Phone).is_ok());
+        assert!(check_contact("4155550100", Channel::Phone).is_err());
+        assert!(check_contact(&format!("+{}", "1".repeat(MAX_PHONE_LENGTH)), Channel::Phone).is_err());
+    }
+}
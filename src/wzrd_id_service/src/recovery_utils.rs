@@ -0,0 +1,796 @@
+use candid::{Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk::export::candid::CandidType;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::audit_utils::{self, SecurityEventKind};
+use crate::challenge_utils::{self, Ceremony};
+use crate::memory_utils::Candid;
+use crate::id_utils::{self, FidoKey, PasskeyResult, RequestResult, KEY_STORE};
+use crate::{lockout_utils, session_utils, token_utils, totp_utils, username_utils, webauthn_utils};
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const MAX_GUARDIANS: usize = 5;
+const RECOVERY_WINDOW: u64 = 172_800_000_000_000; // 48 hours for guardians to approve
+// Anyone may ask guardians to recover an account, so new requests are spaced out per account and
+// per caller. Requests never replace each other, guardians approve the one whose id the owner
+// gave them.
+const RECOVERY_REQUEST_INTERVAL: u64 = 3_600_000_000_000; // 1 hour
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RecoveryCodes {
+    pub salt: Vec<u8>,
+    pub hashes: Vec<Vec<u8>> // unused codes only
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct Guardians {
+    pub guardians: Vec<String>,
+    pub threshold: u32 // kept when guardians leave, which can leave it out of reach
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct PendingRecovery {
+    pub user_name: String,
+    pub recovery_id: String,
+    pub key_id: String,
+    pub public_key: String,
+    pub nickname: Option<String>,
+    pub approvals: BTreeSet<String>,
+    pub created_at: u64,
+    pub expires_at: u64
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct NewPasskey {
+    pub key_id: String,
+    pub public_key: String,
+    pub nickname: Option<String>,
+    pub attestation_object: String, // created over a `RecoverPasskeyRequest` challenge
+    pub client_data_json: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RecoverWithCodeParams {
+    pub user_name: String,
+    pub recovery_code: String,
+    pub passkey: NewPasskey
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct SetGuardiansParams {
+    pub token: String,
+    pub guardians: Vec<String>, // empty turns guardian recovery off
+    pub threshold: u32
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RequestGuardianRecoveryParams {
+    pub user_name: String,
+    pub passkey: NewPasskey
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ApproveRecoveryParams {
+    pub token: String,
+    pub user_name: String,
+    pub recovery_id: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RecoveryCodesResult {
+    pub error: String,
+    pub result: Vec<String>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RecoveryResult {
+    pub error: String,
+    pub result: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ApproveRecoveryResult {
+    pub error: String,
+    pub approvals: u32,
+    pub completed: bool // the new passkey is bound
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RecoveryRequestInfo {
+    pub user_name: String,
+    pub recovery_id: String,
+    pub key_id: String,
+    pub approvals: u32,
+    pub threshold: u32,
+    pub approved_by_me: bool,
+    pub created_at: u64,
+    pub expires_at: u64
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RecoveryStatusResult {
+    pub error: String,
+    pub recovery_codes_left: u32,
+    pub guardians: Vec<String>,
+    pub threshold: u32,
+    pub guardian_recovery_available: bool, // false once too few guardians are left to approve
+    pub pending: Vec<RecoveryRequestInfo>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ListRecoveryRequestsResult {
+    pub error: String,
+    pub result: Vec<RecoveryRequestInfo>
+}
+
+pub type RecoveryCodeStore = BTreeMap<String, RecoveryCodes>; //(user_name => recovery codes)
+pub type GuardianStore = BTreeMap<String, Guardians>; //(user_name => guardians)
+pub type RecoveryRequestStore = BTreeMap<String, PendingRecovery>; //(recovery id => recovery awaiting guardians)
+pub type RequesterStore = BTreeMap<Principal, u64>; //(caller => last recovery requested at)
+
+thread_local! {
+    pub static RECOVERY_CODE_STORE: RefCell<RecoveryCodeStore> = RefCell::default();
+    pub static GUARDIAN_STORE: RefCell<GuardianStore> = RefCell::default();
+    pub static RECOVERY_REQUEST_STORE: RefCell<RecoveryRequestStore> = RefCell::default();
+    pub static REQUESTER_STORE: RefCell<RequesterStore> = RefCell::default();
+}
+
+impl Guardians {
+    fn can_recover(&self) -> bool {
+        self.threshold as usize <= self.guardians.len()
+    }
+
+    fn is_approved(&self, pending: &PendingRecovery) -> bool {
+        pending.approvals.len() as u32 >= self.threshold
+    }
+}
+
+// An empty list clears the guardians, whatever the threshold
+fn check_threshold(guardian_count: usize, threshold: u32) -> String {
+    if guardian_count > MAX_GUARDIANS {
+        format!("At most {} guardians", MAX_GUARDIANS)
+    }
+    else if guardian_count > 0 && (threshold == 0 || threshold as usize > guardian_count) {
+        "Threshold must be between 1 and the number of guardians".to_string()
+    }
+    else{
+        "".to_string()
+    }
+}
+
+// Counts each guardian once, on a live recovery of `user_name` only
+fn add_approval(request_store: &mut RecoveryRequestStore, recovery_id: &str, user_name: &str, guardian: &str, now: u64) -> Option<PendingRecovery> {
+    let pending = request_store.get_mut(recovery_id)
+        .filter(|pending| pending.user_name == user_name && pending.expires_at > now)?;
+    pending.approvals.insert(guardian.to_string());
+    Some(pending.clone())
+}
+
+async fn random_bytes() -> Result<Vec<u8>, String> {
+    match ic_cdk::call::<(), (Vec<u8>,)>(Principal::management_canister(), "raw_rand", ()).await {
+        Ok((entropy,)) => Ok(entropy),
+        Err((_, error)) => Err(error)
+    }
+}
+
+// Replaces the user's recovery codes and returns them, they are never shown again
+pub async fn new_recovery_codes(user_name: &str) -> Result<Vec<String>, String> {
+    let entropy = random_bytes().await?;
+    let (salt, hashes, codes) = totp_utils::new_one_time_codes(&entropy, RECOVERY_CODE_COUNT);
+    // The account may have been deleted while waiting for randomness
+    if !id_utils::has_user(&user_name.to_string()) {
+        return Err("Username not registered".to_string());
+    }
+    RECOVERY_CODE_STORE.with(|code_store| {
+        code_store.borrow_mut().insert(user_name.to_string(), RecoveryCodes {
+            salt,
+            hashes
+        });
+    });
+    Ok(codes)
+}
+
+// Seconds until another recovery may be requested after one at `last_request_at`
+fn request_retry_after(last_request_at: Option<u64>, now: u64) -> Option<u64> {
+    let next_request_at = last_request_at? + RECOVERY_REQUEST_INTERVAL;
+    if next_request_at > now {
+        Some((next_request_at - now + 999_999_999) / 1_000_000_000)
+    }
+    else{
+        None
+    }
+}
+
+// Live recoveries of the account, oldest first
+fn pending_recoveries(user_name: &str) -> Vec<PendingRecovery> {
+    let now = time();
+    RECOVERY_REQUEST_STORE.with(|request_store| {
+        let mut request_store = request_store.borrow_mut();
+        request_store.retain(|_, pending| pending.expires_at > now);
+        let mut pending: Vec<PendingRecovery> = request_store.values()
+            .filter(|pending| pending.user_name == user_name)
+            .cloned()
+            .collect();
+        pending.sort_by_key(|pending| pending.created_at);
+        pending
+    })
+}
+
+fn remove_recoveries(user_name: &str) -> Vec<PendingRecovery> {
+    RECOVERY_REQUEST_STORE.with(|request_store| {
+        let mut request_store = request_store.borrow_mut();
+        let recovery_ids: Vec<String> = request_store.iter()
+            .filter(|(_, pending)| pending.user_name == user_name)
+            .map(|(recovery_id, _)| recovery_id.clone())
+            .collect();
+        recovery_ids.iter().filter_map(|recovery_id| request_store.remove(recovery_id)).collect()
+    })
+}
+
+fn request_info(pending: &PendingRecovery, guardian: Option<&str>) -> RecoveryRequestInfo {
+    let threshold = GUARDIAN_STORE.with(|guardian_store| {
+        guardian_store.borrow().get(&pending.user_name).map_or(0, |guardians| guardians.threshold)
+    });
+    RecoveryRequestInfo {
+        user_name: pending.user_name.clone(),
+        recovery_id: pending.recovery_id.clone(),
+        key_id: pending.key_id.clone(),
+        approvals: pending.approvals.len() as u32,
+        threshold,
+        approved_by_me: guardian.map_or(false, |guardian| pending.approvals.contains(guardian)),
+        created_at: pending.created_at,
+        expires_at: pending.expires_at
+    }
+}
+
+// The passkey must come from a creation ceremony over a recovery challenge for this account, so
+// that only keys we can verify assertions with get bound
+fn check_passkey_ceremony(user_name: &str, passkey: &NewPasskey) -> String {
+    match webauthn_utils::parse_client_data(&passkey.client_data_json) {
+        Ok(client_data) => {
+            let error = challenge_utils::consume_challenge(&client_data.challenge, user_name, Ceremony::Recover);
+            if error.is_empty() {
+                id_utils::check_new_passkey(&passkey.key_id, &passkey.public_key, &passkey.attestation_object, &client_data)
+            }
+            else{
+                error
+            }
+        }
+        Err(error) => error
+    }
+}
+
+// Adds the recovered passkey and signs out every session, the lost device included. Old keys
+// stay until the user removes them.
+fn bind_passkey(user_name: &str, key_id: String, public_key: String, nickname: Option<String>, method: &str) -> String {
+    let mut keys = match id_utils::passkeys(user_name) {
+        Some(keys) => keys,
+        None => return "Username not registered".to_string()
+    };
+    if keys.iter().any(|key| key.key_id == key_id) {
+        return "Passkey already registered".to_string();
+    }
+    keys.push(FidoKey {
+        nickname: nickname.unwrap_or_else(|| key_id.clone()),
+        key_id: key_id.clone(),
+        public_key,
+        created_at: time(),
+        last_used_at: None
+    });
+    KEY_STORE.with(|key_store| key_store.borrow_mut().insert(user_name.to_string(), Candid(keys)));
+    session_utils::end_user_sessions(user_name);
+    remove_recoveries(user_name);
+    audit_utils::record(user_name, SecurityEventKind::AccountRecovered, format!("{} {}", method, key_id));
+    "".to_string()
+}
+
+// Challenge for the replacement passkey of `RecoverWithCode` and `RequestGuardianRecovery`
+pub async fn recover_passkey_request(user_name: String) -> RequestResult {
+    let user_name = match username_utils::find_user(&user_name) {
+        Some(user_name) => user_name,
+        None => return RequestResult {
+            error: "Username not registered".to_string(),
+            result: "".to_string()
+        }
+    };
+    match challenge_utils::issue_challenge(user_name, Ceremony::Recover).await {
+        Ok(challenge) => RequestResult {
+            error: "".to_string(),
+            result: challenge
+        },
+        Err(error) => RequestResult {
+            error,
+            result: "".to_string()
+        }
+    }
+}
+
+pub async fn regenerate_recovery_codes(token: String) -> RecoveryCodesResult {
+    let claims = match id_utils::verify_token(&token) {
+        Some(claims) => claims,
+        None => return RecoveryCodesResult {
+            error: "Invalid token".to_string(),
+            result: vec![]
+        }
+    };
+    if !totp_utils::has_step_up(&claims) {
+        return RecoveryCodesResult {
+            error: "Step-up verification required".to_string(),
+            result: vec![]
+        };
+    }
+    match new_recovery_codes(&claims.username).await {
+        Ok(codes) => RecoveryCodesResult {
+            error: "".to_string(),
+            result: codes
+        },
+        Err(error) => RecoveryCodesResult {
+            error,
+            result: vec![]
+        }
+    }
+}
+
+pub fn get_recovery_status(token: String) -> RecoveryStatusResult {
+    let claims = match id_utils::verify_token(&token) {
+        Some(claims) => claims,
+        None => return RecoveryStatusResult {
+            error: "Invalid token".to_string(),
+            recovery_codes_left: 0,
+            guardians: vec![],
+            threshold: 0,
+            guardian_recovery_available: false,
+            pending: vec![]
+        }
+    };
+    let user_name = claims.username;
+    let recovery_codes_left = RECOVERY_CODE_STORE.with(|code_store| {
+        code_store.borrow().get(&user_name).map_or(0, |codes| codes.hashes.len() as u32)
+    });
+    let guardians = GUARDIAN_STORE.with(|guardian_store| guardian_store.borrow().get(&user_name).cloned());
+    RecoveryStatusResult {
+        error: "".to_string(),
+        recovery_codes_left,
+        threshold: guardians.as_ref().map_or(0, |guardians| guardians.threshold),
+        guardian_recovery_available: guardians.as_ref().map_or(false, |guardians| guardians.can_recover()),
+        guardians: guardians.map(|guardians| guardians.guardians).unwrap_or_default(),
+        pending: pending_recoveries(&user_name).iter().map(|pending| request_info(pending, None)).collect()
+    }
+}
+
+// Binds a new passkey with one of the codes handed out at registration. Wrong codes count
+// towards the same lockout as sign-in attempts, which unlike sign-in gates them per user too,
+// as codes can be guessed.
+pub fn recover_with_code(params: RecoverWithCodeParams) -> PasskeyResult {
+    let user_name = match username_utils::find_user(&params.user_name) {
+        Some(user_name) => user_name,
+        None => return PasskeyResult {
+            error: "Username not registered".to_string(),
+            result: false
+        }
+    };
+    let lockout_error = lockout_utils::check_caller_attempt();
+    if !lockout_error.is_empty() {
+        return PasskeyResult {
+            error: lockout_error,
+            result: false
+        };
+    }
+    // Checked first, so that a malformed passkey doesn't cost a recovery code. Until a recovery
+    // challenge for the user is consumed, failures count against the caller alone.
+    let error = check_passkey_ceremony(&user_name, &params.passkey);
+    if !error.is_empty() {
+        lockout_utils::record_caller_failure();
+        return PasskeyResult {
+            error,
+            result: false
+        };
+    }
+    let lockout_error = lockout_utils::check_attempt(&user_name);
+    if !lockout_error.is_empty() {
+        return PasskeyResult {
+            error: lockout_error,
+            result: false
+        };
+    }
+    let used = RECOVERY_CODE_STORE.with(|code_store| {
+        match code_store.borrow_mut().get_mut(&user_name) {
+            Some(codes) => totp_utils::use_one_time_code(&codes.salt, &mut codes.hashes, &params.recovery_code),
+            None => false
+        }
+    });
+    if !used {
+        lockout_utils::record_failure(&user_name);
+        return PasskeyResult {
+            error: "Invalid recovery code".to_string(),
+            result: false
+        };
+    }
+    lockout_utils::record_success(&user_name);
+    let passkey = params.passkey;
+    let error = bind_passkey(&user_name, passkey.key_id, passkey.public_key, passkey.nickname, "recovery code");
+    PasskeyResult {
+        result: error.is_empty(),
+        error
+    }
+}
+
+// Guardians must be other registered users. Changing them cancels pending recoveries.
+pub fn set_guardians(params: SetGuardiansParams) -> RecoveryResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return RecoveryResult {
+            error: "Invalid token".to_string(),
+            result: "".to_string()
+        }
+    };
+    // Guardians can hand the account to a new passkey, so they're as sensitive as the passkeys
+    if !totp_utils::has_step_up(&claims) {
+        return RecoveryResult {
+            error: "Step-up verification required".to_string(),
+            result: "".to_string()
+        };
+    }
+    let mut guardians = BTreeSet::new();
+    for guardian in &params.guardians {
+        match username_utils::find_user(guardian) {
+            Some(guardian) if guardian != claims.username => {
+                guardians.insert(guardian);
+            }
+            Some(_) => return RecoveryResult {
+                error: "You can't be your own guardian".to_string(),
+                result: "".to_string()
+            },
+            None => return RecoveryResult {
+                error: format!("Username not registered: {}", guardian),
+                result: "".to_string()
+            }
+        }
+    }
+    let error = check_threshold(guardians.len(), params.threshold);
+    if !error.is_empty() {
+        return RecoveryResult {
+            error,
+            result: "".to_string()
+        };
+    }
+    let guardians: Vec<String> = guardians.into_iter().collect();
+    let detail = format!("{} of {}", params.threshold, guardians.join(","));
+    GUARDIAN_STORE.with(|guardian_store| {
+        let mut guardian_store = guardian_store.borrow_mut();
+        if guardians.is_empty() {
+            guardian_store.remove(&claims.username);
+        }
+        else{
+            guardian_store.insert(claims.username.clone(), Guardians {
+                guardians,
+                threshold: params.threshold
+            });
+        }
+    });
+    remove_recoveries(&claims.username);
+    audit_utils::record(&claims.username, SecurityEventKind::GuardiansChanged, detail);
+    RecoveryResult {
+        error: "".to_string(),
+        result: "".to_string()
+    }
+}
+
+// Proposes a new passkey for guardians to approve. Returns the recovery id, which the user
+// shares with the guardians so they can tell the request apart from an impostor's.
+pub fn request_guardian_recovery(params: RequestGuardianRecoveryParams) -> RecoveryResult {
+    let user_name = match username_utils::find_user(&params.user_name) {
+        Some(user_name) => user_name,
+        None => return RecoveryResult {
+            error: "Username not registered".to_string(),
+            result: "".to_string()
+        }
+    };
+    match GUARDIAN_STORE.with(|guardian_store| guardian_store.borrow().get(&user_name).cloned()) {
+        Some(guardians) if guardians.can_recover() => {}
+        Some(_) => return RecoveryResult {
+            error: "Too few guardians left to approve, the owner has to choose new ones".to_string(),
+            result: "".to_string()
+        },
+        None => return RecoveryResult {
+            error: "No guardians set for this account".to_string(),
+            result: "".to_string()
+        }
+    }
+    if id_utils::has_key(&user_name, &params.passkey.key_id) {
+        return RecoveryResult {
+            error: "Passkey already registered".to_string(),
+            result: "".to_string()
+        };
+    }
+    // Signed-out callers share the anonymous principal, so they couldn't be told apart
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return RecoveryResult {
+            error: "Recovery requests must be signed, a throwaway session key will do".to_string(),
+            result: "".to_string()
+        };
+    }
+    let now = time();
+    let last_request_at = pending_recoveries(&user_name).last().map(|pending| pending.created_at);
+    if let Some(retry_after) = request_retry_after(last_request_at, now) {
+        return RecoveryResult {
+            error: format!("A recovery was requested recently, retry after {} seconds", retry_after),
+            result: "".to_string()
+        };
+    }
+    let last_caller_request_at = REQUESTER_STORE.with(|requester_store| {
+        let mut requester_store = requester_store.borrow_mut();
+        requester_store.retain(|_, requested_at| *requested_at + RECOVERY_REQUEST_INTERVAL > now);
+        requester_store.get(&caller).copied()
+    });
+    if let Some(retry_after) = request_retry_after(last_caller_request_at, now) {
+        return RecoveryResult {
+            error: format!("You requested a recovery recently, retry after {} seconds", retry_after),
+            result: "".to_string()
+        };
+    }
+    let error = check_passkey_ceremony(&user_name, &params.passkey);
+    if !error.is_empty() {
+        return RecoveryResult {
+            error,
+            result: "".to_string()
+        };
+    }
+    let recovery_id = token_utils::new_token_id();
+    REQUESTER_STORE.with(|requester_store| requester_store.borrow_mut().insert(caller, now));
+    RECOVERY_REQUEST_STORE.with(|request_store| {
+        request_store.borrow_mut().insert(recovery_id.clone(), PendingRecovery {
+            user_name: user_name.clone(),
+            recovery_id: recovery_id.clone(),
+            key_id: params.passkey.key_id,
+            public_key: params.passkey.public_key,
+            nickname: params.passkey.nickname,
+            approvals: BTreeSet::new(),
+            created_at: now,
+            expires_at: now + RECOVERY_WINDOW
+        });
+    });
+    audit_utils::record(&user_name, SecurityEventKind::RecoveryRequested, recovery_id.clone());
+    RecoveryResult {
+        error: "".to_string(),
+        result: recovery_id
+    }
+}
+
+// Pending recoveries of the accounts the caller guards
+pub fn list_recovery_requests(token: String) -> ListRecoveryRequestsResult {
+    let claims = match id_utils::verify_token(&token) {
+        Some(claims) => claims,
+        None => return ListRecoveryRequestsResult {
+            error: "Invalid token".to_string(),
+            result: vec![]
+        }
+    };
+    let guarded: Vec<String> = GUARDIAN_STORE.with(|guardian_store| {
+        guardian_store.borrow().iter()
+            .filter(|(_, guardians)| guardians.guardians.contains(&claims.username))
+            .map(|(user_name, _)| user_name.clone())
+            .collect()
+    });
+    let result = guarded.iter()
+        .flat_map(|user_name| pending_recoveries(user_name))
+        .map(|pending| request_info(&pending, Some(&claims.username)))
+        .collect();
+    ListRecoveryRequestsResult {
+        error: "".to_string(),
+        result
+    }
+}
+
+// Binds the passkey once enough guardians approved within the window
+pub fn approve_recovery(params: ApproveRecoveryParams) -> ApproveRecoveryResult {
+    let error_result = |error: &str| ApproveRecoveryResult {
+        error: error.to_string(),
+        approvals: 0,
+        completed: false
+    };
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return error_result("Invalid token")
+    };
+    let user_name = match username_utils::find_user(&params.user_name) {
+        Some(user_name) => user_name,
+        None => return error_result("Username not registered")
+    };
+    let guardians = match GUARDIAN_STORE.with(|guardian_store| guardian_store.borrow().get(&user_name).cloned()) {
+        Some(guardians) if guardians.guardians.contains(&claims.username) => guardians,
+        _ => return error_result("You are not a guardian of this account")
+    };
+    let pending = RECOVERY_REQUEST_STORE.with(|request_store| {
+        add_approval(&mut request_store.borrow_mut(), &params.recovery_id, &user_name, &claims.username, time())
+    });
+    let pending = match pending {
+        Some(pending) => pending,
+        None => return error_result("No such recovery request")
+    };
+    audit_utils::record(&user_name, SecurityEventKind::RecoveryApproved, format!("{} {}", pending.recovery_id, claims.username));
+    let approvals = pending.approvals.len() as u32;
+    if !guardians.is_approved(&pending) {
+        return ApproveRecoveryResult {
+            error: "".to_string(),
+            approvals,
+            completed: false
+        };
+    }
+    // Its ceremony was checked when the recovery was requested
+    let error = bind_passkey(&user_name, pending.key_id, pending.public_key, pending.nickname, "guardians");
+    ApproveRecoveryResult {
+        completed: error.is_empty(),
+        error,
+        approvals
+    }
+}
+
+// Lets an owner who still has a passkey stop the recoveries they didn't ask for
+pub fn cancel_recovery(token: String) -> RecoveryResult {
+    let claims = match id_utils::verify_token(&token) {
+        Some(claims) => claims,
+        None => return RecoveryResult {
+            error: "Invalid token".to_string(),
+            result: "".to_string()
+        }
+    };
+    let cancelled = remove_recoveries(&claims.username);
+    if cancelled.is_empty() {
+        return RecoveryResult {
+            error: "No recovery pending".to_string(),
+            result: "".to_string()
+        };
+    }
+    for pending in cancelled {
+        audit_utils::record(&claims.username, SecurityEventKind::RecoveryCancelled, pending.recovery_id);
+    }
+    RecoveryResult {
+        error: "".to_string(),
+        result: "".to_string()
+    }
+}
+
+// Also follows the user in other accounts' guardian lists and approvals
+pub fn rename_user(old_user_name: &str, new_user_name: &str) {
+    RECOVERY_CODE_STORE.with(|code_store| {
+        let mut code_store = code_store.borrow_mut();
+        if let Some(codes) = code_store.remove(old_user_name) {
+            code_store.insert(new_user_name.to_string(), codes);
+        }
+    });
+    GUARDIAN_STORE.with(|guardian_store| {
+        let mut guardian_store = guardian_store.borrow_mut();
+        if let Some(guardians) = guardian_store.remove(old_user_name) {
+            guardian_store.insert(new_user_name.to_string(), guardians);
+        }
+        for guardians in guardian_store.values_mut() {
+            for guardian in guardians.guardians.iter_mut().filter(|guardian| *guardian == old_user_name) {
+                *guardian = new_user_name.to_string();
+            }
+        }
+    });
+    RECOVERY_REQUEST_STORE.with(|request_store| {
+        for pending in request_store.borrow_mut().values_mut() {
+            if pending.user_name == old_user_name {
+                pending.user_name = new_user_name.to_string();
+            }
+            if pending.approvals.remove(old_user_name) {
+                pending.approvals.insert(new_user_name.to_string());
+            }
+        }
+    });
+}
+
+// A deleted guardian leaves the threshold as the owner chose it, even where that puts it out of
+// reach: quietly needing fewer approvals would weaken the account. The owner is told through
+// their audit log and recovery status instead, and has to choose new guardians.
+pub fn delete_user(user_name: &str) {
+    RECOVERY_CODE_STORE.with(|code_store| code_store.borrow_mut().remove(user_name));
+    let guarded = GUARDIAN_STORE.with(|guardian_store| {
+        let mut guardian_store = guardian_store.borrow_mut();
+        guardian_store.remove(user_name);
+        let mut guarded = vec![];
+        for (owner, guardians) in guardian_store.iter_mut() {
+            if guardians.guardians.contains(&user_name.to_string()) {
+                guardians.guardians.retain(|guardian| guardian != user_name);
+                guarded.push(owner.clone());
+            }
+        }
+        guarded
+    });
+    for owner in guarded {
+        audit_utils::record(&owner, SecurityEventKind::GuardianRemoved, user_name.to_string());
+    }
+    RECOVERY_REQUEST_STORE.with(|request_store| {
+        let mut request_store = request_store.borrow_mut();
+        request_store.retain(|_, pending| pending.user_name != user_name);
+        for pending in request_store.values_mut() {
+            pending.approvals.remove(user_name);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guardians(threshold: u32) -> Guardians {
+        Guardians {
+            guardians: vec!["bob".to_string(), "carol".to_string(), "dave".to_string()],
+            threshold
+        }
+    }
+
+    fn request_store() -> RecoveryRequestStore {
+        let mut request_store = RecoveryRequestStore::new();
+        request_store.insert("r1".to_string(), PendingRecovery {
+            user_name: "alice".to_string(),
+            recovery_id: "r1".to_string(),
+            key_id: "new-key".to_string(),
+            public_key: "".to_string(),
+            nickname: None,
+            approvals: BTreeSet::new(),
+            created_at: 1_000,
+            expires_at: 1_000 + RECOVERY_WINDOW
+        });
+        request_store
+    }
+
+    #[test]
+    fn spaces_recovery_requests() {
+        let now = 10 * RECOVERY_REQUEST_INTERVAL;
+        assert_eq!(request_retry_after(None, now), None);
+        assert_eq!(request_retry_after(Some(now - RECOVERY_REQUEST_INTERVAL), now), None);
+        assert_eq!(request_retry_after(Some(now - RECOVERY_REQUEST_INTERVAL + 1), now), Some(1));
+        assert_eq!(request_retry_after(Some(now), now), Some(RECOVERY_REQUEST_INTERVAL / 1_000_000_000));
+    }
+
+    #[test]
+    fn threshold_must_fit_the_guardians() {
+        assert_eq!(check_threshold(3, 2), "");
+        assert_eq!(check_threshold(3, 3), "");
+        assert_eq!(check_threshold(0, 0), "");
+        assert!(!check_threshold(3, 0).is_empty());
+        assert!(!check_threshold(3, 4).is_empty());
+        assert!(!check_threshold(MAX_GUARDIANS + 1, 1).is_empty());
+    }
+
+    #[test]
+    fn completes_at_the_threshold() {
+        let mut request_store = request_store();
+        let guardians = guardians(2);
+        let pending = add_approval(&mut request_store, "r1", "alice", "bob", 2_000).unwrap();
+        assert!(!guardians.is_approved(&pending));
+        let pending = add_approval(&mut request_store, "r1", "alice", "carol", 2_000).unwrap();
+        assert!(guardians.is_approved(&pending));
+    }
+
+    #[test]
+    fn counts_each_guardian_once() {
+        let mut request_store = request_store();
+        add_approval(&mut request_store, "r1", "alice", "bob", 2_000).unwrap();
+        let pending = add_approval(&mut request_store, "r1", "alice", "bob", 3_000).unwrap();
+        assert_eq!(pending.approvals.len(), 1);
+        assert!(!guardians(2).is_approved(&pending));
+    }
+
+    #[test]
+    fn ignores_expired_and_mismatched_requests() {
+        let mut request_store = request_store();
+        assert!(add_approval(&mut request_store, "r1", "alice", "bob", 1_000 + RECOVERY_WINDOW).is_none());
+        assert!(add_approval(&mut request_store, "r1", "mallory", "bob", 2_000).is_none());
+        assert!(add_approval(&mut request_store, "r2", "alice", "bob", 2_000).is_none());
+        assert!(request_store["r1"].approvals.is_empty());
+    }
+
+    #[test]
+    fn stops_when_too_few_guardians_are_left() {
+        let mut guardians = guardians(3);
+        assert!(guardians.can_recover());
+        guardians.guardians.pop();
+        assert!(!guardians.can_recover());
+    }
+}
@@ -0,0 +1,153 @@
+use candid::Deserialize;
+use ic_cdk::export::candid::CandidType;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::audit_utils::{self, SecurityEventKind};
+use crate::{id_utils, session_utils};
+
+#[derive(Clone, Copy, Debug, Deserialize, CandidType, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+    Service
+}
+
+impl Role {
+    // The form carried in the `roles` claim that chat and wallet check against
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+            Role::Service => "service"
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct SetRolesParams {
+    pub user_name: String,
+    pub roles: Vec<Role>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RolesResult {
+    pub error: String,
+    pub result: Vec<Role>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct UserRoles {
+    pub user_name: String,
+    pub roles: Vec<Role>
+}
+
+pub type RoleStore = BTreeMap<String, BTreeSet<Role>>; //(user_name => roles besides User)
+
+thread_local! {
+    pub static ROLE_STORE: RefCell<RoleStore> = RefCell::default();
+}
+
+// Every registered user holds `User`, only the extra roles are stored
+pub fn roles(user_name: &str) -> Vec<Role> {
+    let mut roles = vec![Role::User];
+    ROLE_STORE.with(|role_store| {
+        if let Some(extra_roles) = role_store.borrow().get(user_name) {
+            roles.extend(extra_roles.iter().copied());
+        }
+    });
+    roles
+}
+
+pub fn has_role(user_name: &str, role: Role) -> bool {
+    roles(user_name).contains(&role)
+}
+
+pub fn token_roles(user_name: &str) -> Vec<String> {
+    roles(user_name).iter().map(|role| role.as_str().to_string()).collect()
+}
+
+// Replaces the roles of a user. Tokens pick up the change on their next refresh.
+pub fn set_roles(params: SetRolesParams) -> RolesResult {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return RolesResult {
+            error: "Only controllers can set roles".to_string(),
+            result: vec![]
+        };
+    }
+    if !id_utils::has_user(&params.user_name) {
+        return RolesResult {
+            error: "Username not registered".to_string(),
+            result: vec![]
+        };
+    }
+    let extra_roles: BTreeSet<Role> = params.roles.into_iter().filter(|role| *role != Role::User).collect();
+    let detail = extra_roles.iter().map(|role| role.as_str()).collect::<Vec<&str>>().join(",");
+    let previous_roles = ROLE_STORE.with(|role_store| {
+        let mut role_store = role_store.borrow_mut();
+        if extra_roles.is_empty() {
+            role_store.remove(&params.user_name)
+        }
+        else{
+            role_store.insert(params.user_name.clone(), extra_roles.clone())
+        }
+    });
+    audit_utils::record(&params.user_name, SecurityEventKind::RolesChanged, detail);
+    // Tokens carry the roles they were issued with, so a removed role is only gone once the
+    // sessions holding it are
+    if previous_roles.map_or(false, |previous_roles| !previous_roles.is_subset(&extra_roles)) {
+        session_utils::end_user_sessions(&params.user_name);
+    }
+    RolesResult {
+        error: "".to_string(),
+        result: roles(&params.user_name)
+    }
+}
+
+pub fn get_roles(user_name: String) -> RolesResult {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return RolesResult {
+            error: "Only controllers can read roles".to_string(),
+            result: vec![]
+        };
+    }
+    if !id_utils::has_user(&user_name) {
+        return RolesResult {
+            error: "Username not registered".to_string(),
+            result: vec![]
+        };
+    }
+    RolesResult {
+        error: "".to_string(),
+        result: roles(&user_name)
+    }
+}
+
+// Users holding any role besides `User`
+pub fn list_privileged_users() -> Vec<UserRoles> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return vec![];
+    }
+    ROLE_STORE.with(|role_store| {
+        role_store.borrow().keys().map(|user_name| UserRoles {
+            user_name: user_name.clone(),
+            roles: roles(user_name)
+        }).collect()
+    })
+}
+
+pub fn rename_user(old_user_name: &str, new_user_name: &str) {
+    ROLE_STORE.with(|role_store| {
+        let mut role_store = role_store.borrow_mut();
+        if let Some(extra_roles) = role_store.remove(old_user_name) {
+            role_store.insert(new_user_name.to_string(), extra_roles);
+        }
+    });
+}
+
+pub fn delete_user(user_name: &str) {
+    ROLE_STORE.with(|role_store| {
+        role_store.borrow_mut().remove(user_name);
+    });
+}
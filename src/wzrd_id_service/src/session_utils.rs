@@ -0,0 +1,180 @@
+use candid::Deserialize;
+use ic_cdk::api::time;
+use ic_cdk::export::candid::CandidType;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use crate::audit_utils::{self, SecurityEventKind};
+use crate::id_utils;
+use crate::token_utils::{self, REFRESH_TOKEN_LIFETIME_SECS};
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct Session {
+    pub user_name: String,
+    pub key_id: String,
+    pub client_label: String,
+    pub created_at: u64,
+    pub last_active_at: u64,
+    pub client_id: Option<String> // third-party app the session was authorized for
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct SessionInfo {
+    pub session_id: String,
+    pub key_id: String,
+    pub client_label: String,
+    pub created_at: u64,
+    pub last_active_at: u64,
+    pub current: bool
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ListSessionsResult {
+    pub error: String,
+    pub result: Vec<SessionInfo>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RevokeSessionParams {
+    pub token: String,
+    pub session_id: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RevokeSessionResult {
+    pub error: String,
+    pub result: bool
+}
+
+pub type SessionStore = BTreeMap<String, Session>; //(session id => session)
+
+thread_local! {
+    pub static SESSION_STORE: RefCell<SessionStore> = RefCell::default();
+}
+
+fn is_expired(session: &Session, now: u64) -> bool {
+    session.last_active_at + REFRESH_TOKEN_LIFETIME_SECS * 1_000_000_000 <= now
+}
+
+pub fn create_session(user_name: String, key_id: String, client_label: Option<String>, client_id: Option<String>) -> String {
+    let session_id = token_utils::new_token_id();
+    let now = time();
+    SESSION_STORE.with(|session_store| {
+        let mut session_store = session_store.borrow_mut();
+        session_store.retain(|_, session| !is_expired(session, now));
+        session_store.insert(session_id.clone(), Session {
+            user_name,
+            key_id,
+            client_label: client_label.unwrap_or_else(|| "Unknown device".to_string()),
+            created_at: now,
+            last_active_at: now,
+            client_id
+        });
+    });
+    session_id
+}
+
+// Marks the session active and returns it, `None` once it has been revoked
+pub fn touch_session(session_id: &str) -> Option<Session> {
+    SESSION_STORE.with(|session_store| {
+        let mut session_store = session_store.borrow_mut();
+        let session = session_store.get_mut(session_id)?;
+        session.last_active_at = time();
+        Some(session.clone())
+    })
+}
+
+pub fn rename_user_sessions(old_user_name: &str, new_user_name: &str) {
+    SESSION_STORE.with(|session_store| {
+        for session in session_store.borrow_mut().values_mut().filter(|session| session.user_name == old_user_name) {
+            session.user_name = new_user_name.to_string();
+        }
+    });
+}
+
+pub fn end_session(session_id: String) {
+    SESSION_STORE.with(|session_store| session_store.borrow_mut().remove(&session_id));
+    token_utils::revoke_session(session_id);
+}
+
+fn end_sessions_where<F: Fn(&Session) -> bool>(filter: F) {
+    let session_ids: Vec<String> = SESSION_STORE.with(|session_store| {
+        session_store.borrow().iter()
+            .filter(|(_, session)| filter(session))
+            .map(|(session_id, _)| session_id.clone())
+            .collect()
+    });
+    for session_id in session_ids {
+        end_session(session_id);
+    }
+}
+
+// Signs out every device that logged in with the given passkey
+pub fn end_key_sessions(user_name: &str, key_id: &str) {
+    end_sessions_where(|session| session.user_name == user_name && session.key_id == key_id);
+}
+
+pub fn end_client_sessions(client_id: &str) {
+    end_sessions_where(|session| session.client_id.as_deref() == Some(client_id));
+}
+
+pub fn end_user_sessions(user_name: &str) {
+    end_sessions_where(|session| session.user_name == user_name);
+}
+
+pub fn list_sessions(token: String) -> ListSessionsResult {
+    let claims = match id_utils::verify_token(&token) {
+        Some(claims) => claims,
+        None => return ListSessionsResult {
+            error: "Invalid token".to_string(),
+            result: vec![]
+        }
+    };
+    let now = time();
+    SESSION_STORE.with(|session_store| {
+        let result = session_store.borrow().iter()
+            .filter(|(_, session)| session.user_name == claims.username && !is_expired(session, now))
+            .map(|(session_id, session)| SessionInfo {
+                session_id: session_id.clone(),
+                key_id: session.key_id.clone(),
+                client_label: session.client_label.clone(),
+                created_at: session.created_at,
+                last_active_at: session.last_active_at,
+                current: *session_id == claims.sid
+            })
+            .collect();
+        ListSessionsResult {
+            error: "".to_string(),
+            result
+        }
+    })
+}
+
+pub fn revoke_session(params: RevokeSessionParams) -> RevokeSessionResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return RevokeSessionResult {
+            error: "Invalid token".to_string(),
+            result: false
+        }
+    };
+    let owned = SESSION_STORE.with(|session_store| {
+        match session_store.borrow().get(&params.session_id) {
+            Some(session) => session.user_name == claims.username,
+            None => false
+        }
+    });
+    if owned {
+        audit_utils::record(&claims.username, SecurityEventKind::SessionRevoked, params.session_id.clone());
+        end_session(params.session_id);
+        RevokeSessionResult {
+            error: "".to_string(),
+            result: true
+        }
+    }
+    else{
+        RevokeSessionResult {
+            error: "Session doesn't exist".to_string(),
+            result: false
+        }
+    }
+}
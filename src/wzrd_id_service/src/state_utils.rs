@@ -0,0 +1,192 @@
+use candid::{Decode, Deserialize, Encode};
+use ic_cdk::export::candid::CandidType;
+use std::collections::BTreeMap;
+use crate::account_utils::{RedirectStore, UserIdStore, UsernameHistoryStore, REDIRECT_STORE, USERNAME_HISTORY_STORE, USER_ID_STORE};
+use crate::admin_utils::{SuspensionStore, SUSPENSION_STORE};
+use crate::audit_utils::{SecurityEvent, UserEventIndex, AUDIT_LOG, NEXT_EVENT_ID, USER_EVENT_INDEX};
+use crate::avatar_utils::{Avatar, AVATAR_STORE};
+use crate::cascade_utils::{TaskQueue, TASK_QUEUE};
+use crate::id_utils::{FidoKey, KEY_STORE};
+use crate::lockout_utils::{AttemptStore, ATTEMPT_STORE};
+use crate::memory_utils::{self, Candid};
+use crate::oauth_utils::{ClientStore, CLIENT_STORE};
+use crate::principal_utils::{PrincipalStore, PRINCIPAL_STORE};
+use crate::recovery_utils::{GuardianStore, RecoveryCodeStore, RecoveryRequestStore, RequesterStore, GUARDIAN_STORE, RECOVERY_CODE_STORE, RECOVERY_REQUEST_STORE, REQUESTER_STORE};
+use crate::role_utils::{RoleStore, ROLE_STORE};
+use crate::profile_utils::{ContactStore, ProfileStore, VisibilityStore, CONTACT_STORE, PROFILE_STORE, VISIBILITY_STORE};
+use crate::session_utils::{SessionStore, SESSION_STORE};
+use crate::username_utils::{UsernamePolicy, USERNAME_POLICY};
+use crate::verification_utils::{CodeSender, CODE_SENDER};
+use crate::webauthn_utils::{RelyingParty, RELYING_PARTY};
+use crate::totp_utils::{TotpStore, TOTP_STORE};
+use crate::token_utils::{RevocationStore, SigningKeyStore, REVOCATION_STORE, SIGNING_KEY_STORE};
+
+// Bump when the layout of `StableState` changes. New fields must be `Option`s so that
+// state saved by an older release still decodes; migrate them in `restore_state`.
+//  1: key and profile stores
+//  2: token signing keys
+//  3: token revocation list
+//  4: sessions
+//  5: profile visibility and contacts
+//  6: verification code sender
+//  7: linked principals
+//  8: username policy
+//  9: pending chat and wallet updates
+// 10: user ids, username redirects and history
+// 11: security audit log
+// 12: user roles
+// 13: suspensions and bans
+// 14: avatars
+// 15: third-party clients
+// 16: TOTP enrollments
+// 17: recovery codes, guardians and pending recoveries
+// 18: WebAuthn relying party
+// 19: pending recoveries keyed by recovery id, replacing those keyed by user
+// 20: errors and conflicts of pending chat and wallet updates
+// 21: keys, avatars and the audit log moved to stable structures, left empty here
+// 22: failed attempt counters
+// 23: last guardian recovery request per caller
+const STATE_VERSION: u32 = 23;
+
+// Where releases up to version 20 kept the stores that now live in stable structures
+type LegacyKeyStore = BTreeMap<String, Vec<FidoKey>>; //(user_name => Fido keys)
+type LegacyAuditLog = BTreeMap<u64, SecurityEvent>; //(event id => event)
+type LegacyAvatarStore = BTreeMap<String, Avatar>; //(user_name => avatar)
+
+#[derive(Deserialize, CandidType)]
+pub struct StableState {
+    pub version: u32,
+    pub key_store: LegacyKeyStore,
+    pub profile_store: ProfileStore,
+    pub signing_key_store: Option<SigningKeyStore>,
+    pub revocation_store: Option<RevocationStore>,
+    pub session_store: Option<SessionStore>,
+    pub visibility_store: Option<VisibilityStore>,
+    pub contact_store: Option<ContactStore>,
+    pub code_sender: Option<CodeSender>,
+    pub principal_store: Option<PrincipalStore>,
+    pub username_policy: Option<UsernamePolicy>,
+    pub task_queue: Option<TaskQueue>,
+    pub user_id_store: Option<UserIdStore>,
+    pub redirect_store: Option<RedirectStore>,
+    pub username_history_store: Option<UsernameHistoryStore>,
+    pub audit_log: Option<LegacyAuditLog>,
+    pub user_event_index: Option<UserEventIndex>,
+    pub next_event_id: Option<u64>,
+    pub role_store: Option<RoleStore>,
+    pub suspension_store: Option<SuspensionStore>,
+    pub avatar_store: Option<LegacyAvatarStore>,
+    pub client_store: Option<ClientStore>,
+    pub totp_store: Option<TotpStore>,
+    pub recovery_code_store: Option<RecoveryCodeStore>,
+    pub guardian_store: Option<GuardianStore>,
+    pub relying_party: Option<RelyingParty>,
+    pub recovery_request_store: Option<RecoveryRequestStore>,
+    pub attempt_store: Option<AttemptStore>,
+    pub requester_store: Option<RequesterStore>
+}
+
+pub fn save_state() {
+    let state = StableState {
+        version: STATE_VERSION,
+        key_store: LegacyKeyStore::new(),
+        profile_store: PROFILE_STORE.with(|profile_store| profile_store.borrow().clone()),
+        signing_key_store: Some(SIGNING_KEY_STORE.with(|key_store| key_store.borrow().clone())),
+        revocation_store: Some(REVOCATION_STORE.with(|revocation_store| revocation_store.borrow().clone())),
+        session_store: Some(SESSION_STORE.with(|session_store| session_store.borrow().clone())),
+        visibility_store: Some(VISIBILITY_STORE.with(|visibility_store| visibility_store.borrow().clone())),
+        contact_store: Some(CONTACT_STORE.with(|contact_store| contact_store.borrow().clone())),
+        code_sender: CODE_SENDER.with(|code_sender| code_sender.borrow().clone()),
+        principal_store: Some(PRINCIPAL_STORE.with(|principal_store| principal_store.borrow().clone())),
+        username_policy: Some(USERNAME_POLICY.with(|policy| policy.borrow().clone())),
+        task_queue: Some(TASK_QUEUE.with(|task_queue| task_queue.borrow().clone())),
+        user_id_store: Some(USER_ID_STORE.with(|user_id_store| user_id_store.borrow().clone())),
+        redirect_store: Some(REDIRECT_STORE.with(|redirect_store| redirect_store.borrow().clone())),
+        username_history_store: Some(USERNAME_HISTORY_STORE.with(|history_store| history_store.borrow().clone())),
+        audit_log: None,
+        user_event_index: Some(USER_EVENT_INDEX.with(|event_index| event_index.borrow().clone())),
+        next_event_id: Some(NEXT_EVENT_ID.with(|next_id| next_id.get())),
+        role_store: Some(ROLE_STORE.with(|role_store| role_store.borrow().clone())),
+        suspension_store: Some(SUSPENSION_STORE.with(|suspension_store| suspension_store.borrow().clone())),
+        avatar_store: None,
+        client_store: Some(CLIENT_STORE.with(|client_store| client_store.borrow().clone())),
+        totp_store: Some(TOTP_STORE.with(|totp_store| totp_store.borrow().clone())),
+        recovery_code_store: Some(RECOVERY_CODE_STORE.with(|code_store| code_store.borrow().clone())),
+        guardian_store: Some(GUARDIAN_STORE.with(|guardian_store| guardian_store.borrow().clone())),
+        relying_party: RELYING_PARTY.with(|relying_party| relying_party.borrow().clone()),
+        recovery_request_store: Some(RECOVERY_REQUEST_STORE.with(|request_store| request_store.borrow().clone())),
+        attempt_store: Some(ATTEMPT_STORE.with(|attempt_store| attempt_store.borrow().clone())),
+        requester_store: Some(REQUESTER_STORE.with(|requester_store| requester_store.borrow().clone()))
+    };
+    match Encode!(&state) {
+        Ok(bytes) => memory_utils::write_snapshot(&bytes),
+        Err(err) => ic_cdk::trap(&format!("Failed to save state: {:?}", err))
+    }
+}
+
+pub fn restore_state() {
+    // Releases before versioned state never wrote to stable memory
+    if ic_cdk::api::stable::stable64_size() == 0 {
+        return;
+    }
+    let state = if memory_utils::is_managed() {
+        match Decode!(&memory_utils::read_snapshot(), StableState) {
+            Ok(state) => state,
+            Err(err) => ic_cdk::trap(&format!("Failed to restore state: {}", err))
+        }
+    }
+    else{
+        // Saved with `stable_save` by version 20 or older, read before the memory manager
+        // takes over stable memory
+        match ic_cdk::storage::stable_restore::<(StableState,)>() {
+            Ok((state,)) => state,
+            Err(err) => ic_cdk::trap(&format!("Failed to restore state: {}", err))
+        }
+    };
+    if state.version > STATE_VERSION {
+        ic_cdk::trap(&format!("Unsupported state version {}", state.version));
+    }
+    KEY_STORE.with(|key_store| {
+        let mut key_store = key_store.borrow_mut();
+        for (user_name, keys) in state.key_store {
+            key_store.insert(user_name, Candid(keys));
+        }
+    });
+    PROFILE_STORE.with(|profile_store| *profile_store.borrow_mut() = state.profile_store);
+    SIGNING_KEY_STORE.with(|key_store| *key_store.borrow_mut() = state.signing_key_store.unwrap_or_default());
+    REVOCATION_STORE.with(|revocation_store| *revocation_store.borrow_mut() = state.revocation_store.unwrap_or_default());
+    SESSION_STORE.with(|session_store| *session_store.borrow_mut() = state.session_store.unwrap_or_default());
+    VISIBILITY_STORE.with(|visibility_store| *visibility_store.borrow_mut() = state.visibility_store.unwrap_or_default());
+    CONTACT_STORE.with(|contact_store| *contact_store.borrow_mut() = state.contact_store.unwrap_or_default());
+    CODE_SENDER.with(|code_sender| *code_sender.borrow_mut() = state.code_sender);
+    PRINCIPAL_STORE.with(|principal_store| *principal_store.borrow_mut() = state.principal_store.unwrap_or_default());
+    USERNAME_POLICY.with(|policy| *policy.borrow_mut() = state.username_policy.unwrap_or_default());
+    TASK_QUEUE.with(|task_queue| *task_queue.borrow_mut() = state.task_queue.unwrap_or_default());
+    USER_ID_STORE.with(|user_id_store| *user_id_store.borrow_mut() = state.user_id_store.unwrap_or_default());
+    REDIRECT_STORE.with(|redirect_store| *redirect_store.borrow_mut() = state.redirect_store.unwrap_or_default());
+    USERNAME_HISTORY_STORE.with(|history_store| *history_store.borrow_mut() = state.username_history_store.unwrap_or_default());
+    AUDIT_LOG.with(|audit_log| {
+        let mut audit_log = audit_log.borrow_mut();
+        for (id, event) in state.audit_log.unwrap_or_default() {
+            audit_log.insert(id, Candid(event));
+        }
+    });
+    USER_EVENT_INDEX.with(|event_index| *event_index.borrow_mut() = state.user_event_index.unwrap_or_default());
+    NEXT_EVENT_ID.with(|next_id| next_id.set(state.next_event_id.unwrap_or_default()));
+    ROLE_STORE.with(|role_store| *role_store.borrow_mut() = state.role_store.unwrap_or_default());
+    SUSPENSION_STORE.with(|suspension_store| *suspension_store.borrow_mut() = state.suspension_store.unwrap_or_default());
+    AVATAR_STORE.with(|avatar_store| {
+        let mut avatar_store = avatar_store.borrow_mut();
+        for (user_name, avatar) in state.avatar_store.unwrap_or_default() {
+            avatar_store.insert(user_name, Candid(avatar));
+        }
+    });
+    CLIENT_STORE.with(|client_store| *client_store.borrow_mut() = state.client_store.unwrap_or_default());
+    TOTP_STORE.with(|totp_store| *totp_store.borrow_mut() = state.totp_store.unwrap_or_default());
+    RECOVERY_CODE_STORE.with(|code_store| *code_store.borrow_mut() = state.recovery_code_store.unwrap_or_default());
+    GUARDIAN_STORE.with(|guardian_store| *guardian_store.borrow_mut() = state.guardian_store.unwrap_or_default());
+    RELYING_PARTY.with(|relying_party| *relying_party.borrow_mut() = state.relying_party);
+    RECOVERY_REQUEST_STORE.with(|request_store| *request_store.borrow_mut() = state.recovery_request_store.unwrap_or_default());
+    ATTEMPT_STORE.with(|attempt_store| *attempt_store.borrow_mut() = state.attempt_store.unwrap_or_default());
+    REQUESTER_STORE.with(|requester_store| *requester_store.borrow_mut() = state.requester_store.unwrap_or_default());
+}
@@ -0,0 +1,356 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use candid::Principal;
+use ed25519_dalek::{Signature, Signer, SigningKey as Ed25519SigningKey, Verifier};
+use ic_cdk::api::time;
+use ic_cdk::export::candid::CandidType;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use crate::{http_utils, totp_utils};
+
+// Retired keys keep verifying tokens for as long as a refresh token they signed may live, so
+// that sessions survive a rotation
+const ROTATION_GRACE_PERIOD: u64 = REFRESH_TOKEN_LIFETIME_SECS * 1_000_000_000;
+pub const ACCESS_TOKEN_LIFETIME_SECS: u64 = 900; // 15 minutes
+pub const REFRESH_TOKEN_LIFETIME_SECS: u64 = 2_592_000; // 30 days
+
+pub const ACCESS_TOKEN: &str = "access";
+pub const REFRESH_TOKEN: &str = "refresh";
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct SigningKey {
+    pub kid: String,
+    pub secret: Vec<u8>, // Ed25519 seed
+    pub retired_at: Option<u64>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct PublicSigningKey {
+    pub kid: String,
+    pub public_key: Vec<u8>,
+    pub expires_at: Option<u64>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RotateKeyResult {
+    pub error: String,
+    pub result: String
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Claims {
+    #[serde(default)]
+    pub iss: String,
+    pub username: String,
+    #[serde(default)]
+    pub uid: String, // stable across username changes
+    #[serde(rename = "keyId")]
+    pub key_id: String,
+    pub typ: String,
+    pub jti: String,
+    pub sid: String,
+    // The client a third-party token was issued to, or this canister for first-party tokens
+    #[serde(default)]
+    pub aud: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    // Space separated scopes of a third-party token, first-party tokens have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    // Whether TOTP was enabled when the token was issued. Only a hint for the app, step-up checks
+    // go by the account's current state, so older tokens can't skip them.
+    #[serde(default)]
+    pub mfa: bool,
+    // When a TOTP code was last verified for this token, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step_up: Option<u64>,
+    pub iat: u64,
+    pub exp: u64
+}
+
+// OpenID Connect identity token, handed to clients alongside the tokens of an `openid` grant.
+// It lacks the claims of an access token, so it never passes for one.
+#[derive(Serialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    pub auth_time: u64,
+    pub preferred_username: String,
+    pub iat: u64,
+    pub exp: u64
+}
+
+#[derive(Serialize, Deserialize)]
+struct JwtHeader {
+    alg: String,
+    typ: String,
+    kid: String
+}
+
+pub type SigningKeyStore = Vec<SigningKey>; //(oldest => newest, the last one signs)
+pub type RevocationStore = BTreeMap<String, u64>; //(token id or session id => expiry in seconds)
+
+thread_local! {
+    pub static SIGNING_KEY_STORE: RefCell<SigningKeyStore> = RefCell::default();
+    pub static REVOCATION_STORE: RefCell<RevocationStore> = RefCell::default();
+    static TOKEN_COUNTER: Cell<u64> = Cell::new(0);
+}
+
+fn is_usable(key: &SigningKey, now: u64) -> bool {
+    match key.retired_at {
+        Some(retired_at) => retired_at + ROTATION_GRACE_PERIOD > now,
+        None => true
+    }
+}
+
+fn ed25519_key(key: &SigningKey) -> Option<Ed25519SigningKey> {
+    let seed: [u8; 32] = key.secret.as_slice().try_into().ok()?;
+    Some(Ed25519SigningKey::from_bytes(&seed))
+}
+
+async fn new_signing_key() -> Result<SigningKey, String> {
+    let res = ic_cdk::call::<(), (Vec<u8>,)>(Principal::management_canister(), "raw_rand", ()).await;
+    match res {
+        Ok((secret,)) => {
+            let seed: [u8; 32] = match secret.as_slice().try_into() {
+                Ok(seed) => seed,
+                Err(_) => return Err("Unexpected entropy length".to_string())
+            };
+            let public_key = Ed25519SigningKey::from_bytes(&seed).verifying_key();
+            Ok(SigningKey {
+                kid: URL_SAFE_NO_PAD.encode(&Sha256::digest(public_key.as_bytes())[..8]),
+                secret,
+                retired_at: None
+            })
+        }
+        Err((_, error)) => Err(error)
+    }
+}
+
+// Called from a zero-delay timer after install, as `raw_rand` is not available in `init`
+pub async fn init_signing_key() {
+    if SIGNING_KEY_STORE.with(|key_store| !key_store.borrow().is_empty()) {
+        return;
+    }
+    match new_signing_key().await {
+        Ok(key) => {
+            SIGNING_KEY_STORE.with(|key_store| key_store.borrow_mut().push(key));
+            http_utils::certify_well_known();
+        }
+        Err(error) => ic_cdk::print(format!("Failed to generate signing key: {}", error))
+    }
+}
+
+pub async fn rotate_signing_key() -> RotateKeyResult {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return RotateKeyResult {
+            error: "Only controllers can rotate the signing key".to_string(),
+            result: "".to_string()
+        };
+    }
+    match new_signing_key().await {
+        Ok(key) => {
+            let now = time();
+            let kid = key.kid.clone();
+            SIGNING_KEY_STORE.with(|key_store| {
+                let mut key_store = key_store.borrow_mut();
+                key_store.retain(|key| is_usable(key, now));
+                for old_key in key_store.iter_mut().filter(|key| key.retired_at.is_none()) {
+                    old_key.retired_at = Some(now);
+                }
+                key_store.push(key);
+            });
+            http_utils::certify_well_known();
+            RotateKeyResult {
+                error: "".to_string(),
+                result: kid
+            }
+        }
+        Err(error) => RotateKeyResult {
+            error,
+            result: "".to_string()
+        }
+    }
+}
+
+// Public halves of every key that can still verify tokens, polled by chat and wallet
+pub fn get_signing_keys() -> Vec<PublicSigningKey> {
+    let now = time();
+    SIGNING_KEY_STORE.with(|key_store| {
+        key_store.borrow().iter().filter(|key| is_usable(key, now)).filter_map(|key| {
+            Some(PublicSigningKey {
+                kid: key.kid.clone(),
+                public_key: ed25519_key(key)?.verifying_key().to_bytes().to_vec(),
+                expires_at: key.retired_at.map(|retired_at| retired_at + ROTATION_GRACE_PERIOD)
+            })
+        }).collect()
+    })
+}
+
+// Unique per canister: the counter separates ids issued within the same message
+pub fn new_token_id() -> String {
+    let counter = TOKEN_COUNTER.with(|counter| {
+        counter.set(counter.get() + 1);
+        counter.get()
+    });
+    format!("{:x}-{:x}", time(), counter)
+}
+
+pub fn new_claims(user_name: String, uid: String, key_id: String, sid: String, roles: Vec<String>, scope: Option<String>, typ: &str) -> Claims {
+    let now = time() / 1_000_000_000;
+    let lifetime = if typ == REFRESH_TOKEN { REFRESH_TOKEN_LIFETIME_SECS } else { ACCESS_TOKEN_LIFETIME_SECS };
+    Claims {
+        iss: http_utils::issuer(),
+        mfa: totp_utils::is_enabled(&user_name),
+        username: user_name,
+        uid,
+        key_id,
+        typ: typ.to_string(),
+        jti: new_token_id(),
+        sid,
+        aud: ic_cdk::id().to_text(),
+        roles,
+        scope,
+        step_up: None,
+        iat: now,
+        exp: now + lifetime
+    }
+}
+
+// Revokes a token id or a whole session until `expires_at`, after which the tokens are dead anyway
+pub fn revoke(id: String, expires_at: u64) {
+    let now = time() / 1_000_000_000;
+    REVOCATION_STORE.with(|revocation_store| {
+        let mut revocation_store = revocation_store.borrow_mut();
+        revocation_store.retain(|_, expiry| *expiry > now);
+        let expiry = revocation_store.entry(id).or_insert(expires_at);
+        *expiry = (*expiry).max(expires_at);
+    });
+}
+
+pub fn revoke_session(sid: String) {
+    revoke(sid, time() / 1_000_000_000 + REFRESH_TOKEN_LIFETIME_SECS);
+}
+
+pub fn is_revoked(claims: &Claims) -> bool {
+    REVOCATION_STORE.with(|revocation_store| {
+        let revocation_store = revocation_store.borrow();
+        revocation_store.contains_key(&claims.jti) || revocation_store.contains_key(&claims.sid)
+    })
+}
+
+pub fn new_id_token_claims(user_name: String, uid: String, client_id: String, nonce: Option<String>, auth_time: u64) -> IdTokenClaims {
+    let now = time() / 1_000_000_000;
+    IdTokenClaims {
+        iss: http_utils::issuer(),
+        sub: uid,
+        aud: client_id,
+        nonce,
+        auth_time: auth_time / 1_000_000_000,
+        preferred_username: user_name,
+        iat: now,
+        exp: now + ACCESS_TOKEN_LIFETIME_SECS
+    }
+}
+
+pub fn sign_claims<T: Serialize>(claims: &T) -> Option<String> {
+    let key = SIGNING_KEY_STORE.with(|key_store| key_store.borrow().last().cloned())?;
+    let signer = ed25519_key(&key)?;
+    let header = JwtHeader {
+        alg: "EdDSA".to_string(),
+        typ: "JWT".to_string(),
+        kid: key.kid
+    };
+    let signing_input = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).ok()?),
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).ok()?)
+    );
+    let signature = signer.sign(signing_input.as_bytes());
+    Some(format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature.to_bytes())))
+}
+
+// Checks the signature, expiry and revocation, returns the claims of a valid token
+pub fn verify_claims(token: &str) -> Option<Claims> {
+    SIGNING_KEY_STORE.with(|key_store| verify_with_keys(token, &key_store.borrow(), time()))
+}
+
+fn verify_with_keys(token: &str, keys: &[SigningKey], now: u64) -> Option<Claims> {
+    let mut parts = token.split('.');
+    let (header, payload, signature) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let header: JwtHeader = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+    if header.alg != "EdDSA" {
+        return None;
+    }
+    let key = keys.iter().find(|key| key.kid == header.kid && is_usable(key, now))?;
+    let signature = Signature::from_slice(&URL_SAFE_NO_PAD.decode(signature).ok()?).ok()?;
+    let signing_input = &token[..token.rfind('.')?];
+    ed25519_key(key)?.verifying_key().verify(signing_input.as_bytes(), &signature).ok()?;
+
+    let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    if claims.exp <= now / 1_000_000_000 || is_revoked(&claims) {
+        return None;
+    }
+    Some(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+    const HOUR: u64 = 3_600_000_000_000;
+
+    fn key(kid: &str, seed: u8, retired_at: Option<u64>) -> SigningKey {
+        SigningKey {
+            kid: kid.to_string(),
+            secret: vec![seed; 32],
+            retired_at
+        }
+    }
+
+    // Signs with the only key in the store, as it was before the rotation
+    fn refresh_token(signing_key: SigningKey) -> String {
+        let now = NOW / 1_000_000_000;
+        let claims = Claims {
+            iss: "".to_string(),
+            username: "alice".to_string(),
+            uid: "u1".to_string(),
+            key_id: "key".to_string(),
+            typ: REFRESH_TOKEN.to_string(),
+            jti: "t1".to_string(),
+            sid: "s1".to_string(),
+            aud: "".to_string(),
+            roles: vec![],
+            scope: None,
+            mfa: false,
+            step_up: None,
+            iat: now - REFRESH_TOKEN_LIFETIME_SECS + 3_600,
+            exp: now + 3_600
+        };
+        SIGNING_KEY_STORE.with(|key_store| *key_store.borrow_mut() = vec![signing_key]);
+        sign_claims(&claims).unwrap()
+    }
+
+    #[test]
+    fn refresh_tokens_outlive_a_rotation() {
+        let token = refresh_token(key("old", 1, None));
+        // Retired an hour after the token was issued, just under 30 days ago
+        let keys = vec![key("old", 1, Some(NOW - ROTATION_GRACE_PERIOD + 2 * HOUR)), key("new", 2, None)];
+        assert_eq!(verify_with_keys(&token, &keys, NOW).unwrap().typ, REFRESH_TOKEN);
+    }
+
+    #[test]
+    fn retired_keys_stop_verifying_after_the_refresh_lifetime() {
+        let token = refresh_token(key("old", 1, None));
+        let keys = vec![key("old", 1, Some(NOW - ROTATION_GRACE_PERIOD)), key("new", 2, None)];
+        assert!(verify_with_keys(&token, &keys, NOW).is_none());
+    }
+}
@@ -0,0 +1,513 @@
+use candid::{Deserialize, Principal};
+use hmac::{Hmac, Mac};
+use ic_cdk::api::time;
+use ic_cdk::export::candid::CandidType;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use crate::audit_utils::{self, SecurityEventKind};
+use crate::challenge_utils::Ceremony;
+use crate::id_utils::{self, RequestResult, TokenResult};
+use crate::token_utils::{self, Claims, ACCESS_TOKEN};
+use crate::{account_utils, role_utils};
+
+const ISSUER_NAME: &str = "WZRD";
+const SECRET_LENGTH: usize = 20; // 160 bits, as recommended by RFC 4226
+const TIME_STEP_SECS: u64 = 30;
+const CODE_DIGITS: u32 = 6;
+const DRIFT_STEPS: u64 = 1; // codes of the neighbouring steps are accepted for clock drift
+const BACKUP_CODE_COUNT: usize = 10;
+const ONE_TIME_CODE_LENGTH: usize = 10; // base32 characters, 50 bits each
+const MAX_FAILURES: u32 = 5;
+const FAILURE_BLOCK: u64 = 900_000_000_000; // 15 minutes
+// How long a verified code counts for wallet sends and account deletion. The wallet service
+// holds its own copy of this value.
+pub const STEP_UP_WINDOW_SECS: u64 = 300;
+pub const STEP_UP_REQUIRED: &str = "Step-up verification required";
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct Totp {
+    pub secret: Vec<u8>,
+    pub confirmed: bool,
+    pub salt: Vec<u8>,
+    pub backup_codes: Vec<Vec<u8>>, // salted hashes of the unused codes
+    pub last_step: u64, // codes of this step and earlier can't be replayed
+    pub failures: u32,
+    pub blocked_until: u64,
+    pub enabled_at: u64
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct BeginTotpEnrollmentParams {
+    pub token: String,
+    pub key_id: String, // a registered passkey, asserting over the `TotpEnrollmentRequest` challenge
+    pub signature: String,
+    pub authenticator_data: String,
+    pub client_data_json: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct TotpEnrollmentResult {
+    pub error: String,
+    pub secret: String, // base32, for authenticator apps that can't scan the URI
+    pub provisioning_uri: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct TotpCodeParams {
+    pub token: String,
+    pub code: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct BackupCodesResult {
+    pub error: String,
+    pub result: Vec<String>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct TotpStatusResult {
+    pub error: String,
+    pub enabled: bool,
+    pub backup_codes_left: u32
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct TotpResult {
+    pub error: String,
+    pub result: bool
+}
+
+pub type TotpStore = BTreeMap<String, Totp>; //(user_name => enrollment, confirmed or not)
+
+thread_local! {
+    pub static TOTP_STORE: RefCell<TotpStore> = RefCell::default();
+}
+
+async fn random_bytes() -> Result<Vec<u8>, String> {
+    match ic_cdk::call::<(), (Vec<u8>,)>(Principal::management_canister(), "raw_rand", ()).await {
+        Ok((entropy,)) => Ok(entropy),
+        Err((_, error)) => Err(error)
+    }
+}
+
+// RFC 4226 with HMAC-SHA1, which is what authenticator apps assume by default
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    binary % 10u32.pow(CODE_DIGITS)
+}
+
+fn base32(data: &[u8]) -> String {
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, data)
+}
+
+// Usernames are folded to lowercase but may still hold characters a URI can't carry as is
+fn encode_uri_component(value: &str) -> String {
+    value.bytes().map(|byte| {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            (byte as char).to_string()
+        }
+        else{
+            format!("%{:02X}", byte)
+        }
+    }).collect()
+}
+
+fn provisioning_uri(user_name: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        ISSUER_NAME, encode_uri_component(user_name), base32(secret), ISSUER_NAME, CODE_DIGITS, TIME_STEP_SECS
+    )
+}
+
+// One-time codes are typed by hand, so dashes, spaces and case are ignored
+fn normalize_one_time_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_uppercase()
+}
+
+fn hash_one_time_code(salt: &[u8], code: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(normalize_one_time_code(code).as_bytes());
+    hasher.finalize().to_vec()
+}
+
+// Stretches one `raw_rand` result into `count` codes and a fresh salt. Returns the salt, the
+// hashes to store and the codes as shown to the user, grouped in fives.
+pub fn new_one_time_codes(entropy: &[u8], count: usize) -> (Vec<u8>, Vec<Vec<u8>>, Vec<String>) {
+    let salt = Sha256::new().chain_update(entropy).chain_update(b"salt").finalize().to_vec();
+    let codes: Vec<String> = (0..count).map(|index| {
+        let digest = Sha256::new().chain_update(entropy).chain_update([index as u8]).finalize();
+        base32(&digest)[..ONE_TIME_CODE_LENGTH].to_string()
+    }).collect();
+    let hashes = codes.iter().map(|code| hash_one_time_code(&salt, code)).collect();
+    (salt, hashes, codes.iter().map(|code| format!("{}-{}", &code[..5], &code[5..])).collect())
+}
+
+// Removes the code from `hashes` when it's one of them
+pub fn use_one_time_code(salt: &[u8], hashes: &mut Vec<Vec<u8>>, code: &str) -> bool {
+    let hash = hash_one_time_code(salt, code);
+    match hashes.iter().position(|stored| *stored == hash) {
+        Some(index) => {
+            hashes.remove(index);
+            true
+        }
+        None => false
+    }
+}
+
+fn new_backup_codes(totp: &mut Totp, entropy: &[u8]) -> Vec<String> {
+    let (salt, hashes, codes) = new_one_time_codes(entropy, BACKUP_CODE_COUNT);
+    totp.salt = salt;
+    totp.backup_codes = hashes;
+    codes
+}
+
+// Checks an authenticator code, or a backup code when allowed, and counts failures. Returns
+// whether a backup code was used up.
+fn check_code(totp: &mut Totp, code: &str, allow_backup: bool, now: u64) -> Result<bool, String> {
+    if totp.blocked_until > now {
+        return Err("Too many failed codes, try again later".to_string());
+    }
+    let code = code.trim();
+    if code.len() == CODE_DIGITS as usize && code.chars().all(|c| c.is_ascii_digit()) {
+        let current_step = now / 1_000_000_000 / TIME_STEP_SECS;
+        let code: u32 = code.parse().unwrap_or(u32::MAX);
+        let matched = (current_step.saturating_sub(DRIFT_STEPS)..=current_step + DRIFT_STEPS)
+            .find(|step| *step > totp.last_step && hotp(&totp.secret, *step) == code);
+        if let Some(step) = matched {
+            totp.last_step = step;
+            totp.failures = 0;
+            return Ok(false);
+        }
+    }
+    else if allow_backup && use_one_time_code(&totp.salt, &mut totp.backup_codes, code) {
+        totp.failures = 0;
+        return Ok(true);
+    }
+    totp.failures += 1;
+    if totp.failures >= MAX_FAILURES {
+        totp.failures = 0;
+        totp.blocked_until = now + FAILURE_BLOCK;
+    }
+    Err("Invalid code".to_string())
+}
+
+// Runs `check_code` against the user's confirmed enrollment and records spent backup codes
+fn verify_code(user_name: &str, code: &str, allow_backup: bool) -> String {
+    let checked = TOTP_STORE.with(|totp_store| {
+        match totp_store.borrow_mut().get_mut(user_name) {
+            Some(totp) if totp.confirmed => check_code(totp, code, allow_backup, time()),
+            _ => Err("TOTP is not enabled".to_string())
+        }
+    });
+    match checked {
+        Ok(used_backup_code) => {
+            if used_backup_code {
+                audit_utils::record(user_name, SecurityEventKind::BackupCodeUsed, "".to_string());
+            }
+            "".to_string()
+        }
+        Err(error) => error
+    }
+}
+
+pub fn is_enabled(user_name: &str) -> bool {
+    TOTP_STORE.with(|totp_store| totp_store.borrow().get(user_name).map_or(false, |totp| totp.confirmed))
+}
+
+// Users without TOTP never need a step-up
+pub fn has_step_up(claims: &Claims) -> bool {
+    if !is_enabled(&claims.username) {
+        return true;
+    }
+    let now = time() / 1_000_000_000;
+    claims.step_up.map_or(false, |verified_at| verified_at + STEP_UP_WINDOW_SECS > now)
+}
+
+pub async fn totp_enrollment_request(token: String) -> RequestResult {
+    id_utils::step_up_challenge(token, Ceremony::EnrollTotp).await
+}
+
+// Starts over any unconfirmed enrollment. TOTP is only enabled once a code from the app is
+// confirmed, so a secret that never made it into an app can't lock anyone out. Like adding a
+// passkey, it takes an assertion from a registered passkey, or a stolen token could lock the
+// owner out behind an authenticator app they don't have.
+pub async fn begin_totp_enrollment(params: BeginTotpEnrollmentParams) -> TotpEnrollmentResult {
+    let error_result = |error: String| TotpEnrollmentResult {
+        error,
+        secret: "".to_string(),
+        provisioning_uri: "".to_string()
+    };
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return error_result("Invalid token".to_string())
+    };
+    if is_enabled(&claims.username) {
+        return error_result("TOTP is already enabled".to_string());
+    }
+    let error = id_utils::verify_passkey(
+        &claims.username,
+        &params.key_id,
+        &params.signature,
+        &params.authenticator_data,
+        &params.client_data_json,
+        Ceremony::EnrollTotp
+    );
+    if !error.is_empty() {
+        return error_result(error);
+    }
+    let secret = match random_bytes().await {
+        Ok(entropy) => entropy[..SECRET_LENGTH].to_vec(),
+        Err(error) => return error_result(error)
+    };
+    TOTP_STORE.with(|totp_store| {
+        totp_store.borrow_mut().insert(claims.username.clone(), Totp {
+            secret: secret.clone(),
+            confirmed: false,
+            salt: vec![],
+            backup_codes: vec![],
+            last_step: 0,
+            failures: 0,
+            blocked_until: 0,
+            enabled_at: 0
+        });
+    });
+    TotpEnrollmentResult {
+        error: "".to_string(),
+        secret: base32(&secret),
+        provisioning_uri: provisioning_uri(&claims.username, &secret)
+    }
+}
+
+// Enables TOTP with the first code from the app and returns the backup codes, which are only
+// ever shown here
+pub async fn confirm_totp_enrollment(params: TotpCodeParams) -> BackupCodesResult {
+    let error_result = |error: String| BackupCodesResult {
+        error,
+        result: vec![]
+    };
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return error_result("Invalid token".to_string())
+    };
+    let checked = TOTP_STORE.with(|totp_store| {
+        match totp_store.borrow_mut().get_mut(&claims.username) {
+            Some(totp) if !totp.confirmed => check_code(totp, &params.code, false, time()).map(|_| totp.secret.clone()),
+            Some(_) => Err("TOTP is already enabled".to_string()),
+            None => Err("No TOTP enrollment started".to_string())
+        }
+    });
+    let secret = match checked {
+        Ok(secret) => secret,
+        Err(error) => return error_result(error)
+    };
+    let entropy = match random_bytes().await {
+        Ok(entropy) => entropy,
+        Err(error) => return error_result(error)
+    };
+    // The enrollment may have been restarted while waiting for randomness
+    let codes = TOTP_STORE.with(|totp_store| {
+        match totp_store.borrow_mut().get_mut(&claims.username) {
+            Some(totp) if !totp.confirmed && totp.secret == secret => {
+                totp.confirmed = true;
+                totp.enabled_at = time();
+                Some(new_backup_codes(totp, &entropy))
+            }
+            _ => None
+        }
+    });
+    match codes {
+        Some(codes) => {
+            audit_utils::record(&claims.username, SecurityEventKind::TotpEnabled, "".to_string());
+            BackupCodesResult {
+                error: "".to_string(),
+                result: codes
+            }
+        }
+        None => error_result("TOTP enrollment changed, start again".to_string())
+    }
+}
+
+// Replaces every backup code. Needs a code from the app, a backup code can't mint new ones.
+pub async fn regenerate_backup_codes(params: TotpCodeParams) -> BackupCodesResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return BackupCodesResult {
+            error: "Invalid token".to_string(),
+            result: vec![]
+        }
+    };
+    let error = verify_code(&claims.username, &params.code, false);
+    if !error.is_empty() {
+        return BackupCodesResult {
+            error,
+            result: vec![]
+        };
+    }
+    let entropy = match random_bytes().await {
+        Ok(entropy) => entropy,
+        Err(error) => return BackupCodesResult {
+            error,
+            result: vec![]
+        }
+    };
+    let codes = TOTP_STORE.with(|totp_store| {
+        match totp_store.borrow_mut().get_mut(&claims.username) {
+            Some(totp) if totp.confirmed => new_backup_codes(totp, &entropy),
+            _ => vec![]
+        }
+    });
+    BackupCodesResult {
+        error: if codes.is_empty() { "TOTP is not enabled".to_string() } else { "".to_string() },
+        result: codes
+    }
+}
+
+pub fn disable_totp(params: TotpCodeParams) -> TotpResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return TotpResult {
+            error: "Invalid token".to_string(),
+            result: false
+        }
+    };
+    let error = verify_code(&claims.username, &params.code, true);
+    if !error.is_empty() {
+        return TotpResult {
+            error,
+            result: false
+        };
+    }
+    TOTP_STORE.with(|totp_store| totp_store.borrow_mut().remove(&claims.username));
+    audit_utils::record(&claims.username, SecurityEventKind::TotpDisabled, "".to_string());
+    TotpResult {
+        error: "".to_string(),
+        result: true
+    }
+}
+
+pub fn get_totp_status(token: String) -> TotpStatusResult {
+    let claims = match id_utils::verify_token(&token) {
+        Some(claims) => claims,
+        None => return TotpStatusResult {
+            error: "Invalid token".to_string(),
+            enabled: false,
+            backup_codes_left: 0
+        }
+    };
+    TOTP_STORE.with(|totp_store| {
+        let totp_store = totp_store.borrow();
+        let totp = totp_store.get(&claims.username).filter(|totp| totp.confirmed);
+        TotpStatusResult {
+            error: "".to_string(),
+            enabled: totp.is_some(),
+            backup_codes_left: totp.map_or(0, |totp| totp.backup_codes.len() as u32)
+        }
+    })
+}
+
+// Verifies a code and returns an access token for the same session carrying the `step_up`
+// claim. The session's refresh token stays as it is, refreshed tokens drop the claim.
+pub fn step_up(params: TotpCodeParams) -> TokenResult {
+    let error_result = |error: String| TokenResult {
+        error,
+        access_token: "".to_string(),
+        refresh_token: "".to_string()
+    };
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return error_result("Invalid token".to_string())
+    };
+    let error = verify_code(&claims.username, &params.code, true);
+    if !error.is_empty() {
+        return error_result(error);
+    }
+    let uid = account_utils::user_id(&claims.username);
+    let roles = role_utils::token_roles(&claims.username);
+    let mut access_claims = token_utils::new_claims(claims.username, uid, claims.key_id, claims.sid, roles, None, ACCESS_TOKEN);
+    access_claims.step_up = Some(time() / 1_000_000_000);
+    match token_utils::sign_claims(&access_claims) {
+        Some(access_token) => TokenResult {
+            error: "".to_string(),
+            access_token,
+            refresh_token: "".to_string()
+        },
+        None => error_result("Token signing key not ready".to_string())
+    }
+}
+
+pub fn rename_user(old_user_name: &str, new_user_name: &str) {
+    TOTP_STORE.with(|totp_store| {
+        let mut totp_store = totp_store.borrow_mut();
+        if let Some(totp) = totp_store.remove(old_user_name) {
+            totp_store.insert(new_user_name.to_string(), totp);
+        }
+    });
+}
+
+pub fn delete_user(user_name: &str) {
+    TOTP_STORE.with(|totp_store| {
+        totp_store.borrow_mut().remove(user_name);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4226, appendix D
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn enrolled() -> Totp {
+        Totp {
+            secret: RFC_SECRET.to_vec(),
+            confirmed: true,
+            salt: vec![],
+            backup_codes: vec![],
+            last_step: 0,
+            failures: 0,
+            blocked_until: 0,
+            enabled_at: 0
+        }
+    }
+
+    #[test]
+    fn hotp_matches_rfc_vectors() {
+        let expected = [755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(RFC_SECRET, counter as u64), *code);
+        }
+    }
+
+    #[test]
+    fn base32_secret_has_no_padding() {
+        assert_eq!(base32(RFC_SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn backup_codes_are_single_use_and_forgiving() {
+        let mut totp = enrolled();
+        let codes = new_backup_codes(&mut totp, &[7u8; 32]);
+        assert_eq!(codes.len(), BACKUP_CODE_COUNT);
+        let typed = codes[3].to_lowercase().replace('-', " ");
+        assert_eq!(check_code(&mut totp, &typed, true, 0), Ok(true));
+        assert!(check_code(&mut totp, &codes[3], true, 0).is_err());
+        assert!(check_code(&mut totp, &codes[4], false, 0).is_err());
+        assert_eq!(totp.backup_codes.len(), BACKUP_CODE_COUNT - 1);
+    }
+
+    #[test]
+    fn codes_are_not_replayed() {
+        let mut totp = enrolled();
+        // T = 59s is step 1, RFC 6238 gives 94287082 for eight digits
+        let now = 59_000_000_000;
+        assert_eq!(check_code(&mut totp, "287082", false, now), Ok(false));
+        assert!(check_code(&mut totp, "287082", false, now).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+use candid::Deserialize;
+use ic_cdk::export::candid::CandidType;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::{account_utils, cascade_utils};
+use crate::id_utils::KEY_STORE;
+
+// Error codes returned to signup, the frontend maps them to messages
+pub const USERNAME_EMPTY: &str = "USERNAME_EMPTY";
+pub const USERNAME_TOO_SHORT: &str = "USERNAME_TOO_SHORT";
+pub const USERNAME_TOO_LONG: &str = "USERNAME_TOO_LONG";
+pub const USERNAME_INVALID_CHARACTERS: &str = "USERNAME_INVALID_CHARACTERS";
+pub const USERNAME_INVALID_EDGE: &str = "USERNAME_INVALID_EDGE";
+pub const USERNAME_RESERVED: &str = "USERNAME_RESERVED";
+pub const USERNAME_TAKEN: &str = "USERNAME_TAKEN";
+pub const USERNAME_CONFUSABLE: &str = "USERNAME_CONFUSABLE";
+pub const USERNAME_UNCHANGED: &str = "USERNAME_UNCHANGED";
+pub const USERNAME_CHANGE_COOLDOWN: &str = "USERNAME_CHANGE_COOLDOWN";
+
+const DEFAULT_RESERVED_NAMES: [&str; 11] = [
+    "admin", "administrator", "root", "support", "help", "security",
+    "system", "wzrd", "moderator", "official", "staff"
+];
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct UsernamePolicy {
+    pub min_length: u32,
+    pub max_length: u32,
+    pub allowed_symbols: String, // allowed besides letters and digits, never at the edges
+    pub allow_unicode: bool, // non-ASCII letters and digits
+    pub reserved_names: BTreeSet<String>
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        UsernamePolicy {
+            min_length: 3,
+            max_length: 32,
+            allowed_symbols: "._-".to_string(),
+            allow_unicode: false,
+            reserved_names: DEFAULT_RESERVED_NAMES.iter().map(|name| name.to_string()).collect()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct SetUsernamePolicyParams {
+    pub min_length: u32,
+    pub max_length: u32,
+    pub allowed_symbols: String,
+    pub allow_unicode: bool
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct UsernameResult {
+    pub error: String,
+    pub result: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct PolicyResult {
+    pub error: String,
+    pub result: bool
+}
+
+type SkeletonIndex = BTreeMap<String, String>; //(skeleton => user_name)
+
+thread_local! {
+    pub static USERNAME_POLICY: RefCell<UsernamePolicy> = RefCell::default();
+    static SKELETON_INDEX: RefCell<SkeletonIndex> = RefCell::default();
+}
+
+// Case-folded form that new accounts are stored under
+pub fn fold(user_name: &str) -> String {
+    user_name.trim().to_lowercase()
+}
+
+// Collapses characters that render alike, so that look-alike names map to the same key
+pub fn skeleton(user_name: &str) -> String {
+    let mapped: String = fold(user_name).chars().filter_map(|c| {
+        let c = match c {
+            '.' | '_' | '-' => return None,
+            '0' | 'о' | 'ο' | 'σ' => 'o',
+            '1' | 'i' | 'і' | 'ι' | 'ӏ' | '|' => 'l',
+            '3' | 'е' | 'ε' => 'e',
+            '5' | 'ѕ' => 's',
+            'а' | 'α' => 'a',
+            'р' | 'ρ' => 'p',
+            'с' | 'ϲ' => 'c',
+            'у' | 'γ' => 'y',
+            'х' | 'χ' => 'x',
+            'ј' => 'j',
+            'ԁ' => 'd',
+            'ɡ' => 'g',
+            'к' | 'κ' => 'k',
+            'ν' => 'v',
+            'т' | 'τ' => 't',
+            'н' | 'η' => 'n',
+            'м' => 'm',
+            'в' | 'β' => 'b',
+            c => c
+        };
+        Some(c)
+    }).collect();
+    mapped.replace("rn", "m").replace("vv", "w")
+}
+
+fn check_format(user_name: &str, policy: &UsernamePolicy) -> Result<(), &'static str> {
+    if user_name.is_empty() {
+        return Err(USERNAME_EMPTY);
+    }
+    let length = user_name.chars().count() as u32;
+    if length < policy.min_length {
+        return Err(USERNAME_TOO_SHORT);
+    }
+    if length > policy.max_length {
+        return Err(USERNAME_TOO_LONG);
+    }
+    let is_letter = |c: char| c.is_ascii_alphanumeric() || (policy.allow_unicode && c.is_alphanumeric());
+    if !user_name.chars().all(|c| is_letter(c) || policy.allowed_symbols.contains(c)) {
+        return Err(USERNAME_INVALID_CHARACTERS);
+    }
+    match (user_name.chars().next(), user_name.chars().last()) {
+        (Some(first), Some(last)) if is_letter(first) && is_letter(last) => Ok(()),
+        _ => Err(USERNAME_INVALID_EDGE)
+    }
+}
+
+// Registered, kept as a redirect after a username change, or still being renamed or deleted
+// on the chat and wallet services
+fn is_claimed(user_name: &str) -> bool {
+    KEY_STORE.with(|key_store| key_store.borrow().contains_key(&user_name.to_string()))
+        || account_utils::resolve_redirect(user_name).is_some()
+        || cascade_utils::holds_name(user_name)
+}
+
+// Returns the folded name to register under, or one of the error codes above
+pub fn validate(user_name: &str) -> Result<String, String> {
+    let folded = fold(user_name);
+    let policy = USERNAME_POLICY.with(|policy| policy.borrow().clone());
+    check_format(&folded, &policy).map_err(|code| code.to_string())?;
+
+    let name_skeleton = skeleton(&folded);
+    if policy.reserved_names.iter().any(|reserved| skeleton(reserved) == name_skeleton) {
+        return Err(USERNAME_RESERVED.to_string());
+    }
+    if is_claimed(&folded) {
+        return Err(USERNAME_TAKEN.to_string());
+    }
+    match SKELETON_INDEX.with(|index| index.borrow().get(&name_skeleton).cloned()) {
+        Some(existing) if !is_claimed(&existing) => Ok(folded), // left behind by an expired redirect
+        Some(existing) if fold(&existing) == folded => Err(USERNAME_TAKEN.to_string()),
+        Some(_) => Err(USERNAME_CONFUSABLE.to_string()),
+        None => Ok(folded)
+    }
+}
+
+// Accounts registered before normalization keep their exact spelling, old names of renamed
+// accounts resolve to the current one while their redirect lasts
+pub fn find_user(user_name: &str) -> Option<String> {
+    KEY_STORE.with(|key_store| {
+        let key_store = key_store.borrow();
+        if key_store.contains_key(&user_name.to_string()) {
+            return Some(user_name.to_string());
+        }
+        let folded = fold(user_name);
+        if key_store.contains_key(&folded) {
+            Some(folded)
+        }
+        else{
+            None
+        }
+    }).or_else(|| account_utils::resolve_redirect(&fold(user_name)))
+}
+
+pub fn index_username(user_name: &str) {
+    SKELETON_INDEX.with(|index| index.borrow_mut().insert(skeleton(user_name), user_name.to_string()));
+}
+
+pub fn unindex_username(user_name: &str) {
+    SKELETON_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let name_skeleton = skeleton(user_name);
+        if index.get(&name_skeleton).map(String::as_str) == Some(user_name) {
+            index.remove(&name_skeleton);
+        }
+    });
+}
+
+// The index is derived from the key store, so it's rebuilt after an upgrade instead of persisted
+pub fn rebuild_index() {
+    let mut user_names: Vec<String> = KEY_STORE.with(|key_store| key_store.borrow().iter().map(|(user_name, _)| user_name).collect());
+    user_names.extend(account_utils::active_redirects());
+    SKELETON_INDEX.with(|index| index.borrow_mut().clear());
+    for user_name in user_names {
+        index_username(&user_name);
+    }
+}
+
+pub fn validate_username(user_name: String) -> UsernameResult {
+    match validate(&user_name) {
+        Ok(result) => UsernameResult {
+            error: "".to_string(),
+            result
+        },
+        Err(error) => UsernameResult {
+            error,
+            result: "".to_string()
+        }
+    }
+}
+
+pub fn get_username_policy() -> UsernamePolicy {
+    USERNAME_POLICY.with(|policy| policy.borrow().clone())
+}
+
+fn update_policy<F: FnOnce(&mut UsernamePolicy)>(update: F) -> PolicyResult {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return PolicyResult {
+            error: "Only controllers can change the username policy".to_string(),
+            result: false
+        };
+    }
+    USERNAME_POLICY.with(|policy| update(&mut policy.borrow_mut()));
+    PolicyResult {
+        error: "".to_string(),
+        result: true
+    }
+}
+
+pub fn set_username_policy(params: SetUsernamePolicyParams) -> PolicyResult {
+    if params.min_length == 0 || params.min_length > params.max_length {
+        return PolicyResult {
+            error: "Invalid length bounds".to_string(),
+            result: false
+        };
+    }
+    update_policy(|policy| {
+        policy.min_length = params.min_length;
+        policy.max_length = params.max_length;
+        policy.allowed_symbols = params.allowed_symbols;
+        policy.allow_unicode = params.allow_unicode;
+    })
+}
+
+pub fn add_reserved_names(names: Vec<String>) -> PolicyResult {
+    update_policy(|policy| policy.reserved_names.extend(names.iter().map(|name| fold(name))))
+}
+
+pub fn remove_reserved_names(names: Vec<String>) -> PolicyResult {
+    update_policy(|policy| {
+        for name in names {
+            policy.reserved_names.remove(&fold(&name));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_utils::Candid;
+
+    #[test]
+    fn folds_case_and_whitespace() {
+        assert_eq!(validate("  Alice.Smith ").unwrap(), "alice.smith");
+    }
+
+    #[test]
+    fn reports_format_errors() {
+        assert_eq!(validate("   ").unwrap_err(), USERNAME_EMPTY);
+        assert_eq!(validate("ab").unwrap_err(), USERNAME_TOO_SHORT);
+        assert_eq!(validate(&"a".repeat(33)).unwrap_err(), USERNAME_TOO_LONG);
+        assert_eq!(validate("bob smith").unwrap_err(), USERNAME_INVALID_CHARACTERS);
+        assert_eq!(validate("bоb").unwrap_err(), USERNAME_INVALID_CHARACTERS); // Cyrillic o
+        assert_eq!(validate(".bob").unwrap_err(), USERNAME_INVALID_EDGE);
+    }
+
+    #[test]
+    fn maps_look_alikes_to_one_skeleton() {
+        assert_eq!(skeleton("paypal"), skeleton("PayPa1"));
+        assert_eq!(skeleton("modern"), skeleton("modem"));
+        assert_eq!(skeleton("bob"), skeleton("bоb"));
+        assert_eq!(skeleton("john.doe"), skeleton("john_doe"));
+        assert_ne!(skeleton("alice"), skeleton("bob"));
+    }
+
+    #[test]
+    fn rejects_reserved_and_confusable_names() {
+        assert_eq!(validate("Adm1n").unwrap_err(), USERNAME_RESERVED);
+        KEY_STORE.with(|key_store| key_store.borrow_mut().insert("paypal".to_string(), Candid(vec![])));
+        index_username("paypal");
+        assert_eq!(validate("PAYPAL").unwrap_err(), USERNAME_TAKEN);
+        assert_eq!(validate("paypa1").unwrap_err(), USERNAME_CONFUSABLE);
+    }
+}
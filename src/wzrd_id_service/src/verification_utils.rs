@@ -0,0 +1,483 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use candid::{Deserialize, Nat, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs, TransformContext
+};
+use ic_cdk::api::time;
+use ic_cdk::export::candid::CandidType;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use crate::audit_utils::{self, SecurityEventKind};
+use crate::id_utils;
+use crate::profile_utils::PROFILE_STORE;
+
+const CODE_TTL: u64 = 600_000_000_000; // 10 minutes
+const RESEND_COOLDOWN: u64 = 60_000_000_000; // 1 minute
+const MAX_ATTEMPTS: u32 = 5;
+// Resends bring a fresh code, so guesses and codes are also capped per target over a day
+const USAGE_WINDOW: u64 = 86_400_000_000_000; // 24 hours
+const MAX_SENDS_PER_WINDOW: u32 = 5;
+const MAX_FAILURES_PER_WINDOW: u32 = 10;
+const MAX_RESPONSE_BYTES: u64 = 2_048;
+
+#[derive(Clone, Copy, Debug, Deserialize, CandidType, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Channel {
+    Email,
+    Phone
+}
+
+// Where codes are delivered. `Mock` keeps them in canister memory for local testing.
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub enum CodeSender {
+    Http { url: String, api_key: Option<String> },
+    Mock
+}
+
+#[derive(Clone, Debug)]
+pub struct PendingCode {
+    pub target: String,
+    pub code_hash: Vec<u8>,
+    pub salt: Vec<u8>,
+    pub issued_at: u64,
+    pub attempts: u32
+}
+
+#[derive(Clone, Debug)]
+struct Usage {
+    window_start: u64,
+    sends: u32,
+    failures: u32
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RequestVerificationParams {
+    pub token: String,
+    pub channel: Channel
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct ConfirmVerificationParams {
+    pub token: String,
+    pub channel: Channel,
+    pub code: String
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct VerificationResult {
+    pub error: String,
+    pub result: bool
+}
+
+type PendingCodeStore = BTreeMap<(String, Channel), PendingCode>; //((user_name, channel) => pending code)
+type UsageKey = (String, String);
+type UsageStore = BTreeMap<UsageKey, Usage>; //((user_name, target) => codes sent and failed attempts in the window)
+
+thread_local! {
+    pub static CODE_SENDER: RefCell<Option<CodeSender>> = RefCell::default();
+    static PENDING_CODE_STORE: RefCell<PendingCodeStore> = RefCell::default();
+    static USAGE_STORE: RefCell<UsageStore> = RefCell::default();
+    static MOCK_OUTBOX: RefCell<BTreeMap<String, String>> = RefCell::default(); //(target => last code)
+}
+
+fn hash_code(salt: &[u8], code: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(code.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+// Counts the attempt, returns an empty string when the code matches and is still valid
+fn check_code(pending: &mut PendingCode, code: &str, now: u64) -> String {
+    if pending.issued_at + CODE_TTL <= now {
+        return "Code expired".to_string();
+    }
+    if pending.attempts >= MAX_ATTEMPTS {
+        return "Too many attempts".to_string();
+    }
+    pending.attempts += 1;
+    if hash_code(&pending.salt, code) == pending.code_hash {
+        "".to_string()
+    }
+    else{
+        "Invalid code".to_string()
+    }
+}
+
+// The target's usage in the current window, a new window starts once the last one is over
+fn current_usage<'a>(usage_store: &'a mut UsageStore, key: &UsageKey, now: u64) -> &'a mut Usage {
+    let usage = usage_store.entry(key.clone()).or_insert(Usage {
+        window_start: now,
+        sends: 0,
+        failures: 0
+    });
+    if usage.window_start + USAGE_WINDOW <= now {
+        *usage = Usage {
+            window_start: now,
+            sends: 0,
+            failures: 0
+        };
+    }
+    usage
+}
+
+fn usage_error(usage: &Usage, now: u64) -> String {
+    if usage.sends >= MAX_SENDS_PER_WINDOW || usage.failures >= MAX_FAILURES_PER_WINDOW {
+        let retry_after = (usage.window_start + USAGE_WINDOW - now + 999_999_999) / 1_000_000_000;
+        format!("Too many verification attempts, retry after {} seconds", retry_after)
+    }
+    else{
+        "".to_string()
+    }
+}
+
+// Counts a code about to be sent, unless the target ran out of codes or attempts
+fn count_send(usage_store: &mut UsageStore, key: &UsageKey, now: u64) -> String {
+    usage_store.retain(|_, usage| usage.window_start + USAGE_WINDOW > now);
+    let usage = current_usage(usage_store, key, now);
+    let error = usage_error(usage, now);
+    if error.is_empty() {
+        usage.sends += 1;
+    }
+    error
+}
+
+// `check_code`, refused once the target ran out of attempts whichever code it holds
+fn count_attempt(usage_store: &mut UsageStore, key: &UsageKey, pending: &mut PendingCode, code: &str, now: u64) -> String {
+    let usage = current_usage(usage_store, key, now);
+    if usage.failures >= MAX_FAILURES_PER_WINDOW {
+        return usage_error(usage, now);
+    }
+    let error = check_code(pending, code, now);
+    if error == "Invalid code" {
+        usage.failures += 1;
+    }
+    error
+}
+
+fn profile_field(user_name: &str, channel: Channel) -> Option<String> {
+    PROFILE_STORE.with(|profile_store| {
+        let profile_store = profile_store.borrow();
+        let profile = profile_store.get(user_name)?;
+        match channel {
+            Channel::Email => profile.email.clone(),
+            Channel::Phone => profile.phone.clone()
+        }
+    })
+}
+
+pub fn is_valid_target(target: &str, channel: Channel) -> bool {
+    match channel {
+        Channel::Email => {
+            let mut parts = target.splitn(2, '@');
+            matches!((parts.next(), parts.next()), (Some(local), Some(domain)) if !local.is_empty() && domain.contains('.'))
+        }
+        Channel::Phone => {
+            target.starts_with('+') && target.len() > 7 && target[1..].chars().all(|c| c.is_ascii_digit())
+        }
+    }
+}
+
+// Every replica makes the outcall, so the provider gets the same request several times. The
+// idempotency key lets it deliver the code once.
+async fn send_code(sender: CodeSender, channel: Channel, target: &str, code: &str, idempotency_key: &str) -> String {
+    match sender {
+        CodeSender::Mock => {
+            MOCK_OUTBOX.with(|outbox| outbox.borrow_mut().insert(target.to_string(), code.to_string()));
+            "".to_string()
+        }
+        CodeSender::Http { url, api_key } => {
+            let channel = match channel {
+                Channel::Email => "email",
+                Channel::Phone => "phone"
+            };
+            let body = serde_json::json!({ "channel": channel, "to": target, "code": code });
+            let mut headers = vec![
+                HttpHeader {
+                    name: "Content-Type".to_string(),
+                    value: "application/json".to_string()
+                },
+                HttpHeader {
+                    name: "Idempotency-Key".to_string(),
+                    value: idempotency_key.to_string()
+                }
+            ];
+            if let Some(api_key) = api_key {
+                headers.push(HttpHeader {
+                    name: "Authorization".to_string(),
+                    value: format!("Bearer {}", api_key)
+                });
+            }
+            let request = CanisterHttpRequestArgument {
+                url,
+                max_response_bytes: Some(MAX_RESPONSE_BYTES),
+                method: HttpMethod::POST,
+                headers,
+                body: Some(body.to_string().into_bytes()),
+                transform: Some(TransformContext::new(crate::transform, vec![]))
+            };
+            match http_request(request).await {
+                Ok((response,)) if response.status >= Nat::from(200u64) && response.status < Nat::from(300u64) => "".to_string(),
+                Ok((response,)) => format!("Delivery failed with status {}", response.status),
+                Err((_, error)) => error
+            }
+        }
+    }
+}
+
+pub async fn request_verification(params: RequestVerificationParams) -> VerificationResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return VerificationResult{
+            error: "Invalid token".to_string(),
+            result: false
+        }
+    };
+    let target = match profile_field(&claims.username, params.channel) {
+        Some(target) if is_valid_target(&target, params.channel) => target,
+        _ => return VerificationResult{
+            error: "Nothing to verify".to_string(),
+            result: false
+        }
+    };
+    let sender = match CODE_SENDER.with(|sender| sender.borrow().clone()) {
+        Some(sender) => sender,
+        None => return VerificationResult{
+            error: "Verification sender not configured".to_string(),
+            result: false
+        }
+    };
+    let key = (claims.username, params.channel);
+    let now = time();
+    let cooling_down = PENDING_CODE_STORE.with(|code_store| {
+        match code_store.borrow().get(&key) {
+            Some(pending) => pending.issued_at + RESEND_COOLDOWN > now,
+            None => false
+        }
+    });
+    if cooling_down {
+        return VerificationResult{
+            error: "Code already sent, try again later".to_string(),
+            result: false
+        };
+    }
+    let error = USAGE_STORE.with(|usage_store| count_send(&mut usage_store.borrow_mut(), &(key.0.clone(), target.clone()), now));
+    if !error.is_empty() {
+        return VerificationResult{
+            error,
+            result: false
+        };
+    }
+
+    let entropy = match ic_cdk::call::<(), (Vec<u8>,)>(Principal::management_canister(), "raw_rand", ()).await {
+        Ok((entropy,)) => entropy,
+        Err((_, error)) => return VerificationResult{
+            error,
+            result: false
+        }
+    };
+    let mut number = [0u8; 8];
+    number.copy_from_slice(&entropy[..8]);
+    let code = format!("{:06}", u64::from_be_bytes(number) % 1_000_000);
+    let salt = entropy[8..].to_vec();
+    // Unique to this code without revealing the salt
+    let idempotency_key = URL_SAFE_NO_PAD.encode(Sha256::digest(&salt));
+    PENDING_CODE_STORE.with(|code_store| {
+        let mut code_store = code_store.borrow_mut();
+        code_store.retain(|_, pending| pending.issued_at + CODE_TTL > now);
+        code_store.insert(key.clone(), PendingCode {
+            target: target.clone(),
+            code_hash: hash_code(&salt, &code),
+            salt,
+            issued_at: now,
+            attempts: 0
+        });
+    });
+
+    let error = send_code(sender, params.channel, &target, &code, &idempotency_key).await;
+    if !error.is_empty() {
+        PENDING_CODE_STORE.with(|code_store| code_store.borrow_mut().remove(&key));
+    }
+    VerificationResult{
+        result: error.is_empty(),
+        error
+    }
+}
+
+pub fn confirm_verification(params: ConfirmVerificationParams) -> VerificationResult {
+    let claims = match id_utils::verify_token(&params.token) {
+        Some(claims) => claims,
+        None => return VerificationResult{
+            error: "Invalid token".to_string(),
+            result: false
+        }
+    };
+    let key = (claims.username.clone(), params.channel);
+    let checked = PENDING_CODE_STORE.with(|code_store| {
+        let mut code_store = code_store.borrow_mut();
+        let pending = code_store.get_mut(&key)?;
+        let usage_key = (claims.username.clone(), pending.target.clone());
+        let error = USAGE_STORE.with(|usage_store| {
+            count_attempt(&mut usage_store.borrow_mut(), &usage_key, pending, params.code.trim(), time())
+        });
+        let target = pending.target.clone();
+        // A code used up by failed attempts stays until it expires, so that its resend cooldown
+        // still applies
+        if error.is_empty() || error == "Code expired" {
+            code_store.remove(&key);
+        }
+        Some((error, target))
+    });
+    let (error, target) = match checked {
+        Some(checked) => checked,
+        None => return VerificationResult{
+            error: "No pending verification".to_string(),
+            result: false
+        }
+    };
+    if !error.is_empty() {
+        return VerificationResult{
+            error,
+            result: false
+        };
+    }
+
+    // The field may have been edited while the code was in flight
+    PROFILE_STORE.with(|profile_store| {
+        let mut profile_store = profile_store.borrow_mut();
+        match profile_store.get_mut(&claims.username) {
+            Some(profile) => {
+                let (value, verified) = match params.channel {
+                    Channel::Email => (&profile.email, &mut profile.email_verified),
+                    Channel::Phone => (&profile.phone, &mut profile.phone_verified)
+                };
+                if value.as_deref() == Some(target.as_str()) {
+                    *verified = Some(true);
+                    audit_utils::record(&claims.username, SecurityEventKind::ContactVerified, format!("{:?}", params.channel));
+                    VerificationResult{
+                        error: "".to_string(),
+                        result: true
+                    }
+                }
+                else{
+                    VerificationResult{
+                        error: "Profile changed during verification".to_string(),
+                        result: false
+                    }
+                }
+            }
+            None => VerificationResult{
+                error: "User profile doesn't exist".to_string(),
+                result: false
+            }
+        }
+    })
+}
+
+pub fn set_code_sender(sender: CodeSender) -> VerificationResult {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return VerificationResult{
+            error: "Only controllers can configure verification".to_string(),
+            result: false
+        };
+    }
+    CODE_SENDER.with(|code_sender| *code_sender.borrow_mut() = Some(sender));
+    VerificationResult{
+        error: "".to_string(),
+        result: true
+    }
+}
+
+// Lets local test harnesses read back codes delivered by the mock sender
+pub fn get_mock_code(target: String) -> Option<String> {
+    let is_mock = CODE_SENDER.with(|sender| matches!(*sender.borrow(), Some(CodeSender::Mock)));
+    if !is_mock || !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return None;
+    }
+    MOCK_OUTBOX.with(|outbox| outbox.borrow().get(&target).cloned())
+}
+
+// Strips headers so that every replica agrees on the provider response
+pub fn transform(response: TransformArgs) -> HttpResponse {
+    let mut t = response.response;
+    t.headers = vec![];
+    t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(code: &str) -> PendingCode {
+        let salt = vec![7u8; 24];
+        PendingCode {
+            target: "user@example.com".to_string(),
+            code_hash: hash_code(&salt, code),
+            salt,
+            issued_at: 1_000,
+            attempts: 0
+        }
+    }
+
+    #[test]
+    fn accepts_matching_code() {
+        let mut pending = pending("123456");
+        assert_eq!(check_code(&mut pending, "123456", 2_000), "");
+    }
+
+    #[test]
+    fn rejects_wrong_and_expired_codes() {
+        let mut pending = pending("123456");
+        assert_eq!(check_code(&mut pending, "654321", 2_000), "Invalid code");
+        assert_eq!(check_code(&mut pending, "123456", 1_000 + CODE_TTL), "Code expired");
+    }
+
+    #[test]
+    fn limits_attempts() {
+        let mut pending = pending("123456");
+        for _ in 0..MAX_ATTEMPTS {
+            assert_eq!(check_code(&mut pending, "000000", 2_000), "Invalid code");
+        }
+        assert_eq!(check_code(&mut pending, "123456", 2_000), "Too many attempts");
+    }
+
+    #[test]
+    fn resends_dont_bring_new_attempts() {
+        let mut usage_store = UsageStore::new();
+        let key = ("alice".to_string(), "user@example.com".to_string());
+        let mut now = 2_000;
+        for _ in 0..MAX_FAILURES_PER_WINDOW / MAX_ATTEMPTS {
+            assert_eq!(count_send(&mut usage_store, &key, now), "");
+            let mut pending = pending("123456");
+            for _ in 0..MAX_ATTEMPTS {
+                assert_eq!(count_attempt(&mut usage_store, &key, &mut pending, "000000", now), "Invalid code");
+            }
+            assert!(!count_attempt(&mut usage_store, &key, &mut pending, "123456", now).is_empty());
+            now += RESEND_COOLDOWN;
+        }
+        assert!(count_send(&mut usage_store, &key, now).starts_with("Too many verification attempts"));
+        let mut pending = pending("123456");
+        assert!(count_attempt(&mut usage_store, &key, &mut pending, "123456", now).starts_with("Too many verification attempts"));
+        assert_eq!(count_send(&mut usage_store, &key, 2_000 + USAGE_WINDOW), "");
+    }
+
+    #[test]
+    fn limits_codes_sent() {
+        let mut usage_store = UsageStore::new();
+        let key = ("alice".to_string(), "user@example.com".to_string());
+        for _ in 0..MAX_SENDS_PER_WINDOW {
+            assert_eq!(count_send(&mut usage_store, &key, 2_000), "");
+        }
+        assert!(!count_send(&mut usage_store, &key, 2_000).is_empty());
+        let mut pending = pending("123456");
+        assert_eq!(count_attempt(&mut usage_store, &key, &mut pending, "123456", 2_000), "");
+    }
+
+    #[test]
+    fn validates_targets() {
+        assert!(is_valid_target("user@example.com", Channel::Email));
+        assert!(!is_valid_target("user@localhost", Channel::Email));
+        assert!(is_valid_target("+14155550100", Channel::Phone));
+        assert!(!is_valid_target("4155550100", Channel::Phone));
+    }
+}
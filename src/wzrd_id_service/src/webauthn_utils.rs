@@ -0,0 +1,486 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::{Signature as Ed25519Signature, VerifyingKey as Ed25519VerifyingKey};
+use p256::ecdsa::{signature::Verifier, Signature as P256Signature, VerifyingKey as P256VerifyingKey};
+use p256::pkcs8::DecodePublicKey;
+use candid::Deserialize;
+use ciborium::value::Value;
+use ic_cdk::export::candid::CandidType;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use crate::http_utils;
+
+// rpIdHash (32) + flags (1) + signCount (4)
+const MIN_AUTHENTICATOR_DATA_LEN: usize = 37;
+const FLAGS_OFFSET: usize = 32;
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+// aaguid (16) + credentialIdLength (2) follow the fixed header when AT is set
+const CREDENTIAL_ID_OFFSET: usize = MIN_AUTHENTICATOR_DATA_LEN + 18;
+// COSE_Key labels and values (RFC 9053)
+const COSE_KTY: i128 = 1;
+const COSE_CRV: i128 = -1;
+const COSE_X: i128 = -2;
+const COSE_Y: i128 = -3;
+const COSE_KTY_OKP: i128 = 1;
+const COSE_KTY_EC2: i128 = 2;
+const COSE_CRV_P256: i128 = 1;
+const COSE_CRV_ED25519: i128 = 6;
+pub const CREATE_CEREMONY: &str = "webauthn.create";
+pub const GET_CEREMONY: &str = "webauthn.get";
+
+#[derive(Deserialize)]
+pub struct CollectedClientData {
+    #[serde(rename = "type")]
+    pub ceremony_type: String,
+    pub challenge: String,
+    pub origin: String
+}
+
+// Where passkeys may be used. Client data from any other origin is refused, which is what stops
+// a phishing proxy from relaying a ceremony.
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RelyingParty {
+    pub origins: Vec<String>, // e.g. "https://wzrd.app"
+    pub rp_ids: Vec<String>, // e.g. "wzrd.app", matched by hash against the authenticator data
+    pub require_user_verification: bool
+}
+
+// Optional argument to `init` and `post_upgrade`
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct InstallArgs {
+    pub relying_party: Option<RelyingParty>
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+pub struct RelyingPartyResult {
+    pub error: String,
+    pub result: bool
+}
+
+thread_local! {
+    pub static RELYING_PARTY: RefCell<Option<RelyingParty>> = RefCell::default();
+}
+
+pub enum CredentialPublicKey {
+    Es256(P256VerifyingKey),
+    EdDsa(Ed25519VerifyingKey),
+}
+
+pub fn decode_base64url(data: &str) -> Result<Vec<u8>, String> {
+    URL_SAFE_NO_PAD
+        .decode(data.trim_end_matches('='))
+        .map_err(|_| "Invalid base64url encoding".to_string())
+}
+
+pub fn parse_client_data(client_data_json: &str) -> Result<CollectedClientData, String> {
+    let client_data = decode_base64url(client_data_json)?;
+    serde_json::from_slice(&client_data).map_err(|_| "Invalid client data".to_string())
+}
+
+// Checks the parts of a ceremony that the signature alone doesn't: the client data must be of
+// the expected type and come from an allowed origin, and the authenticator data must be scoped
+// to an allowed rpId with the user present (and verified, when required).
+pub fn verify_ceremony(
+    relying_party: &RelyingParty,
+    client_data: &CollectedClientData,
+    authenticator_data: &str,
+    ceremony_type: &str
+) -> String {
+    if client_data.ceremony_type != ceremony_type {
+        return "Unexpected ceremony type".to_string();
+    }
+    if !relying_party.origins.contains(&client_data.origin) {
+        return "Origin not allowed".to_string();
+    }
+    let auth_data = match decode_base64url(authenticator_data) {
+        Ok(auth_data) if auth_data.len() >= MIN_AUTHENTICATOR_DATA_LEN => auth_data,
+        _ => return "Invalid authenticator data".to_string()
+    };
+    let rp_id_hash = &auth_data[..FLAGS_OFFSET];
+    if !relying_party.rp_ids.iter().any(|rp_id| Sha256::digest(rp_id.as_bytes()).as_slice() == rp_id_hash) {
+        return "Relying party not allowed".to_string();
+    }
+    let flags = auth_data[FLAGS_OFFSET];
+    if flags & FLAG_USER_PRESENT == 0 {
+        return "User not present".to_string();
+    }
+    if relying_party.require_user_verification && flags & FLAG_USER_VERIFIED == 0 {
+        return "User not verified".to_string();
+    }
+    "".to_string()
+}
+
+// `verify_ceremony` against the configured relying party, which must be set before anyone can
+// register or sign in
+pub fn check_ceremony(client_data: &CollectedClientData, authenticator_data: &str, ceremony_type: &str) -> String {
+    match RELYING_PARTY.with(|relying_party| relying_party.borrow().clone()) {
+        Some(relying_party) => verify_ceremony(&relying_party, client_data, authenticator_data, ceremony_type),
+        None => "Relying party not configured".to_string()
+    }
+}
+
+// Browsers only offer WebAuthn in secure contexts, localhost being the one http exception.
+// Origins are `scheme://host[:port]` exactly as browsers serialize them, with no path.
+fn is_secure_origin(origin: &str) -> bool {
+    let (authority, is_https) = match (origin.strip_prefix("https://"), origin.strip_prefix("http://")) {
+        (Some(authority), _) => (authority, true),
+        (None, Some(authority)) => (authority, false),
+        _ => return false
+    };
+    let (host, port) = match authority.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (authority, None)
+    };
+    let valid_host = !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+    let valid_port = port.map_or(true, |port| !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()));
+    valid_host && valid_port && (is_https || host == "localhost")
+}
+
+fn check_relying_party(relying_party: &RelyingParty) -> String {
+    if relying_party.origins.is_empty() || relying_party.rp_ids.is_empty() {
+        return "At least one origin and one rpId are required".to_string();
+    }
+    if !relying_party.origins.iter().all(|origin| is_secure_origin(origin)) {
+        return "Origins must be https, or http on localhost".to_string();
+    }
+    "".to_string()
+}
+
+pub fn set_relying_party(relying_party: RelyingParty) -> RelyingPartyResult {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return RelyingPartyResult {
+            error: "Only controllers can configure the relying party".to_string(),
+            result: false
+        };
+    }
+    let error = check_relying_party(&relying_party);
+    if !error.is_empty() {
+        return RelyingPartyResult {
+            error,
+            result: false
+        };
+    }
+    RELYING_PARTY.with(|stored| *stored.borrow_mut() = Some(relying_party));
+    // The discovery document links to the app's authorization page
+    http_utils::certify_well_known();
+    RelyingPartyResult {
+        error: "".to_string(),
+        result: true
+    }
+}
+
+// Applies the relying party passed to `init` or `post_upgrade`. An upgrade that would leave
+// none configured is refused, as every register and sign-in would fail until a controller
+// noticed, whereas a fresh install has no users to lock out yet.
+pub fn configure_on_install(args: Option<InstallArgs>, upgrading: bool) {
+    if let Some(relying_party) = args.and_then(|args| args.relying_party) {
+        let error = check_relying_party(&relying_party);
+        if !error.is_empty() {
+            ic_cdk::trap(&format!("Invalid relying party: {}", error));
+        }
+        RELYING_PARTY.with(|stored| *stored.borrow_mut() = Some(relying_party));
+    }
+    if upgrading && RELYING_PARTY.with(|stored| stored.borrow().is_none()) {
+        ic_cdk::trap("No relying party configured, pass one in the upgrade arguments");
+    }
+}
+
+pub fn get_relying_party() -> Option<RelyingParty> {
+    RELYING_PARTY.with(|relying_party| relying_party.borrow().clone())
+}
+
+// Public keys are the SubjectPublicKeyInfo DER returned by `AuthenticatorResponse.getPublicKey()`
+pub fn parse_public_key(public_key: &str) -> Result<CredentialPublicKey, String> {
+    let der = decode_base64url(public_key)?;
+    if let Ok(key) = P256VerifyingKey::from_public_key_der(&der) {
+        return Ok(CredentialPublicKey::Es256(key));
+    }
+    if let Ok(key) = Ed25519VerifyingKey::from_public_key_der(&der) {
+        return Ok(CredentialPublicKey::EdDsa(key));
+    }
+    Err("Unsupported public key".to_string())
+}
+
+fn cbor_field(map: &[(Value, Value)], matches: impl Fn(&Value) -> bool) -> Option<&Value> {
+    map.iter().find(|(key, _)| matches(key)).map(|(_, value)| value)
+}
+
+fn cose_field(map: &[(Value, Value)], label: i128) -> Option<&Value> {
+    cbor_field(map, |key| key.as_integer().map_or(false, |key| i128::from(key) == label))
+}
+
+fn cose_integer(map: &[(Value, Value)], label: i128) -> Option<i128> {
+    cose_field(map, label).and_then(|value| value.as_integer()).map(i128::from)
+}
+
+fn cose_bytes(map: &[(Value, Value)], label: i128) -> Option<&[u8]> {
+    cose_field(map, label).and_then(|value| value.as_bytes()).map(|bytes| bytes.as_slice())
+}
+
+// Whether the COSE_Key in the attested credential data is the same key as the SPKI `public_key`
+fn cose_key_matches(cose_key: &[(Value, Value)], public_key: &CredentialPublicKey) -> bool {
+    match public_key {
+        CredentialPublicKey::Es256(key) => {
+            let (Some(x), Some(y)) = (cose_bytes(cose_key, COSE_X), cose_bytes(cose_key, COSE_Y)) else {
+                return false;
+            };
+            let point = key.to_encoded_point(false);
+            cose_integer(cose_key, COSE_KTY) == Some(COSE_KTY_EC2)
+                && cose_integer(cose_key, COSE_CRV) == Some(COSE_CRV_P256)
+                && point.x().map_or(false, |key_x| key_x.as_slice() == x)
+                && point.y().map_or(false, |key_y| key_y.as_slice() == y)
+        }
+        CredentialPublicKey::EdDsa(key) => {
+            cose_integer(cose_key, COSE_KTY) == Some(COSE_KTY_OKP)
+                && cose_integer(cose_key, COSE_CRV) == Some(COSE_CRV_ED25519)
+                && cose_bytes(cose_key, COSE_X) == Some(key.as_bytes().as_slice())
+        }
+    }
+}
+
+// Binds a new passkey to its registration ceremony: the attested credential data must carry the
+// submitted credential id and the same key as `public_key`, so neither can be swapped for values
+// the authenticator never produced. The attestation statement itself isn't checked, as passkeys
+// are registered with `attestation: "none"`. Returns the authenticator data, base64url encoded,
+// for `check_ceremony`.
+pub fn verify_attestation(attestation_object: &str, key_id: &str, public_key: &str) -> Result<String, String> {
+    let key = parse_public_key(public_key)?;
+    let credential_id = decode_base64url(key_id)?;
+    let attestation: Value = ciborium::de::from_reader(decode_base64url(attestation_object)?.as_slice())
+        .map_err(|_| "Invalid attestation object".to_string())?;
+    let auth_data = attestation.as_map()
+        .and_then(|map| cbor_field(map, |key| key.as_text() == Some("authData")))
+        .and_then(|value| value.as_bytes())
+        .ok_or_else(|| "Invalid attestation object".to_string())?;
+    if auth_data.len() < CREDENTIAL_ID_OFFSET || auth_data[FLAGS_OFFSET] & FLAG_ATTESTED_CREDENTIAL_DATA == 0 {
+        return Err("Missing attested credential data".to_string());
+    }
+    let id_length = u16::from_be_bytes([auth_data[CREDENTIAL_ID_OFFSET - 2], auth_data[CREDENTIAL_ID_OFFSET - 1]]) as usize;
+    let key_offset = CREDENTIAL_ID_OFFSET + id_length;
+    if auth_data.len() < key_offset || auth_data[CREDENTIAL_ID_OFFSET..key_offset] != credential_id[..] {
+        return Err("Credential id mismatch".to_string());
+    }
+    // Extensions may follow the key, reading a single item leaves them be
+    let cose_key: Value = ciborium::de::from_reader(&auth_data[key_offset..])
+        .map_err(|_| "Invalid credential public key".to_string())?;
+    if !cose_key.as_map().map_or(false, |cose_key| cose_key_matches(cose_key, &key)) {
+        return Err("Credential public key mismatch".to_string());
+    }
+    Ok(URL_SAFE_NO_PAD.encode(auth_data))
+}
+
+// Checks the assertion signature over `authenticator_data || SHA-256(client_data_json)`.
+// All inputs are base64url encoded, returns an empty string on success.
+pub fn verify_assertion(
+    public_key: &str,
+    authenticator_data: &str,
+    client_data_json: &str,
+    signature: &str
+) -> String {
+    let key = match parse_public_key(public_key) {
+        Ok(key) => key,
+        Err(err) => return err
+    };
+    let (auth_data, client_data, signature) = match (
+        decode_base64url(authenticator_data),
+        decode_base64url(client_data_json),
+        decode_base64url(signature)
+    ) {
+        (Ok(auth_data), Ok(client_data), Ok(signature)) => (auth_data, client_data, signature),
+        _ => return "Invalid assertion encoding".to_string()
+    };
+    if auth_data.len() < MIN_AUTHENTICATOR_DATA_LEN {
+        return "Invalid authenticator data".to_string();
+    }
+
+    let mut signed_data = auth_data;
+    signed_data.extend_from_slice(&Sha256::digest(&client_data));
+
+    let verified = match key {
+        CredentialPublicKey::Es256(key) => match P256Signature::from_der(&signature) {
+            Ok(sig) => key.verify(&signed_data, &sig).is_ok(),
+            Err(_) => false
+        },
+        CredentialPublicKey::EdDsa(key) => match Ed25519Signature::from_slice(&signature) {
+            Ok(sig) => key.verify_strict(&signed_data, &sig).is_ok(),
+            Err(_) => false
+        }
+    };
+    if verified {
+        "".to_string()
+    }
+    else{
+        "Invalid signature".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Assertion recorded for rpId "localhost" with flags UP|UV and signCount 1
+    const AUTHENTICATOR_DATA: &str = "SZYN5YgOjGh0NBcPZHZgW4_krrmihjLHmVzzuoMdl2MFAAAAAQ";
+    const CLIENT_DATA_JSON: &str = "eyJ0eXBlIjoid2ViYXV0aG4uZ2V0IiwiY2hhbGxlbmdlIjoiZEdWemRDMWphR0ZzYkdWdVoyVSIsIm9yaWdpbiI6Imh0dHA6Ly9sb2NhbGhvc3Q6MzAwMCIsImNyb3NzT3JpZ2luIjpmYWxzZX0";
+
+    const ES256_PUBLIC_KEY: &str = "MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEHhhTL9R1TALzBB2cdc6zO4P_2BrHzk_ogsyxyYvFiW6kbDEcTi_0DdlqNlPm5FRF0y3-SG7O11x6kMahiIHAow";
+    const ES256_SIGNATURE: &str = "MEUCIQDN7nXtBKPgSTOnO4XGuJFb7L8JGfXs8rtcqOc5Q6hT-wIgXVzmW85a7nYq6AKvZd7C8mIYHpMQvyt92vljNcUxnTg";
+
+    const EDDSA_PUBLIC_KEY: &str = "MCowBQYDK2VwAyEA_RckOFqgx1tk-3jNYC-h2ZH96_drE8WO1wLqyDXp9hg";
+    const EDDSA_SIGNATURE: &str = "YCsUu2oaqZsWmTr9LNBCmxMQXEN8k2tqPXUCZKVS5aoGXd3Pp3GkHqZicnjC4BanpgekQI_VoW8jxIXW5kq6AQ";
+
+    #[test]
+    fn verifies_es256_assertion() {
+        assert_eq!(verify_assertion(ES256_PUBLIC_KEY, AUTHENTICATOR_DATA, CLIENT_DATA_JSON, ES256_SIGNATURE), "");
+    }
+
+    #[test]
+    fn verifies_eddsa_assertion() {
+        assert_eq!(verify_assertion(EDDSA_PUBLIC_KEY, AUTHENTICATOR_DATA, CLIENT_DATA_JSON, EDDSA_SIGNATURE), "");
+    }
+
+    #[test]
+    fn rejects_signature_from_other_key() {
+        assert_eq!(verify_assertion(ES256_PUBLIC_KEY, AUTHENTICATOR_DATA, CLIENT_DATA_JSON, EDDSA_SIGNATURE), "Invalid signature");
+        assert_eq!(verify_assertion(EDDSA_PUBLIC_KEY, AUTHENTICATOR_DATA, CLIENT_DATA_JSON, ES256_SIGNATURE), "Invalid signature");
+    }
+
+    #[test]
+    fn rejects_tampered_client_data() {
+        let tampered = URL_SAFE_NO_PAD.encode(br#"{"type":"webauthn.get","challenge":"b3RoZXI","origin":"http://localhost:3000"}"#);
+        assert_eq!(verify_assertion(ES256_PUBLIC_KEY, AUTHENTICATOR_DATA, &tampered, ES256_SIGNATURE), "Invalid signature");
+        assert_eq!(verify_assertion(EDDSA_PUBLIC_KEY, AUTHENTICATOR_DATA, &tampered, EDDSA_SIGNATURE), "Invalid signature");
+    }
+
+    #[test]
+    fn rejects_tampered_authenticator_data() {
+        let mut auth_data = decode_base64url(AUTHENTICATOR_DATA).unwrap();
+        auth_data[36] = 2;
+        let tampered = URL_SAFE_NO_PAD.encode(auth_data);
+        assert_eq!(verify_assertion(ES256_PUBLIC_KEY, &tampered, CLIENT_DATA_JSON, ES256_SIGNATURE), "Invalid signature");
+    }
+
+    fn localhost() -> RelyingParty {
+        RelyingParty {
+            origins: vec!["http://localhost:3000".to_string()],
+            rp_ids: vec!["localhost".to_string()],
+            require_user_verification: true
+        }
+    }
+
+    #[test]
+    fn accepts_ceremony_from_allowed_origin() {
+        let client_data = parse_client_data(CLIENT_DATA_JSON).unwrap();
+        assert_eq!(client_data.challenge, "dGVzdC1jaGFsbGVuZ2U");
+        assert_eq!(verify_ceremony(&localhost(), &client_data, AUTHENTICATOR_DATA, GET_CEREMONY), "");
+    }
+
+    #[test]
+    fn rejects_ceremony_from_elsewhere() {
+        let client_data = parse_client_data(CLIENT_DATA_JSON).unwrap();
+        assert_eq!(verify_ceremony(&localhost(), &client_data, AUTHENTICATOR_DATA, CREATE_CEREMONY), "Unexpected ceremony type");
+        let mut relying_party = localhost();
+        relying_party.origins = vec!["https://wzrd.app".to_string()];
+        assert_eq!(verify_ceremony(&relying_party, &client_data, AUTHENTICATOR_DATA, GET_CEREMONY), "Origin not allowed");
+        let mut relying_party = localhost();
+        relying_party.rp_ids = vec!["wzrd.app".to_string()];
+        assert_eq!(verify_ceremony(&relying_party, &client_data, AUTHENTICATOR_DATA, GET_CEREMONY), "Relying party not allowed");
+    }
+
+    #[test]
+    fn checks_user_presence_and_verification() {
+        let client_data = parse_client_data(CLIENT_DATA_JSON).unwrap();
+        let mut auth_data = decode_base64url(AUTHENTICATOR_DATA).unwrap();
+        auth_data[FLAGS_OFFSET] = FLAG_USER_PRESENT;
+        let present_only = URL_SAFE_NO_PAD.encode(&auth_data);
+        assert_eq!(verify_ceremony(&localhost(), &client_data, &present_only, GET_CEREMONY), "User not verified");
+        let mut relying_party = localhost();
+        relying_party.require_user_verification = false;
+        assert_eq!(verify_ceremony(&relying_party, &client_data, &present_only, GET_CEREMONY), "");
+        auth_data[FLAGS_OFFSET] = FLAG_USER_VERIFIED;
+        let absent = URL_SAFE_NO_PAD.encode(&auth_data);
+        assert_eq!(verify_ceremony(&relying_party, &client_data, &absent, GET_CEREMONY), "User not present");
+    }
+
+    #[test]
+    fn allows_http_only_on_localhost() {
+        assert!(is_secure_origin("https://wzrd.app"));
+        assert!(is_secure_origin("https://wzrd.app:8443"));
+        assert!(is_secure_origin("http://localhost"));
+        assert!(is_secure_origin("http://localhost:3000"));
+        assert!(!is_secure_origin("http://localhost.evil.com"));
+        assert!(!is_secure_origin("http://localhost@evil.com"));
+        assert!(!is_secure_origin("http://localhost:3000/path"));
+        assert!(!is_secure_origin("http://wzrd.app"));
+        assert!(!is_secure_origin("https://"));
+    }
+
+    fn attestation_object(public_key: &str, credential_id: &[u8]) -> String {
+        let mut auth_data = decode_base64url(AUTHENTICATOR_DATA).unwrap();
+        auth_data[FLAGS_OFFSET] |= FLAG_ATTESTED_CREDENTIAL_DATA;
+        auth_data.extend_from_slice(&[0; 16]);
+        auth_data.extend_from_slice(&(credential_id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(credential_id);
+        let cose_key = match parse_public_key(public_key).unwrap() {
+            CredentialPublicKey::Es256(key) => {
+                let point = key.to_encoded_point(false);
+                vec![
+                    (Value::from(COSE_KTY as i64), Value::from(COSE_KTY_EC2 as i64)),
+                    (Value::from(3), Value::from(-7)),
+                    (Value::from(COSE_CRV as i64), Value::from(COSE_CRV_P256 as i64)),
+                    (Value::from(COSE_X as i64), Value::Bytes(point.x().unwrap().to_vec())),
+                    (Value::from(COSE_Y as i64), Value::Bytes(point.y().unwrap().to_vec()))
+                ]
+            }
+            CredentialPublicKey::EdDsa(key) => vec![
+                (Value::from(COSE_KTY as i64), Value::from(COSE_KTY_OKP as i64)),
+                (Value::from(3), Value::from(-8)),
+                (Value::from(COSE_CRV as i64), Value::from(COSE_CRV_ED25519 as i64)),
+                (Value::from(COSE_X as i64), Value::Bytes(key.as_bytes().to_vec()))
+            ]
+        };
+        ciborium::ser::into_writer(&Value::Map(cose_key), &mut auth_data).unwrap();
+        let attestation = Value::Map(vec![
+            (Value::from("fmt"), Value::from("none")),
+            (Value::from("attStmt"), Value::Map(vec![])),
+            (Value::from("authData"), Value::Bytes(auth_data))
+        ]);
+        let mut encoded = vec![];
+        ciborium::ser::into_writer(&attestation, &mut encoded).unwrap();
+        URL_SAFE_NO_PAD.encode(encoded)
+    }
+
+    #[test]
+    fn binds_attested_credential_to_submitted_key() {
+        let key_id = URL_SAFE_NO_PAD.encode(b"credential-1");
+        for public_key in [ES256_PUBLIC_KEY, EDDSA_PUBLIC_KEY] {
+            let auth_data = verify_attestation(&attestation_object(public_key, b"credential-1"), &key_id, public_key).unwrap();
+            let client_data = parse_client_data(CLIENT_DATA_JSON).unwrap();
+            assert_eq!(verify_ceremony(&localhost(), &client_data, &auth_data, GET_CEREMONY), "");
+        }
+        let es256_attestation = attestation_object(ES256_PUBLIC_KEY, b"credential-1");
+        assert_eq!(verify_attestation(&es256_attestation, &key_id, EDDSA_PUBLIC_KEY).unwrap_err(), "Credential public key mismatch");
+        let other_id = URL_SAFE_NO_PAD.encode(b"credential-2");
+        assert_eq!(verify_attestation(&es256_attestation, &other_id, ES256_PUBLIC_KEY).unwrap_err(), "Credential id mismatch");
+    }
+
+    #[test]
+    fn requires_attested_credential_data() {
+        let key_id = URL_SAFE_NO_PAD.encode(b"credential-1");
+        let attestation = Value::Map(vec![(Value::from("authData"), Value::Bytes(decode_base64url(AUTHENTICATOR_DATA).unwrap()))]);
+        let mut encoded = vec![];
+        ciborium::ser::into_writer(&attestation, &mut encoded).unwrap();
+        assert_eq!(
+            verify_attestation(&URL_SAFE_NO_PAD.encode(encoded), &key_id, ES256_PUBLIC_KEY).unwrap_err(),
+            "Missing attested credential data"
+        );
+        assert_eq!(verify_attestation("AAAA", &key_id, ES256_PUBLIC_KEY).unwrap_err(), "Invalid attestation object");
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert_eq!(verify_assertion("AAAA", AUTHENTICATOR_DATA, CLIENT_DATA_JSON, ES256_SIGNATURE), "Unsupported public key");
+        assert_eq!(verify_assertion(ES256_PUBLIC_KEY, "AAAA", CLIENT_DATA_JSON, ES256_SIGNATURE), "Invalid authenticator data");
+        assert_eq!(verify_assertion(ES256_PUBLIC_KEY, AUTHENTICATOR_DATA, CLIENT_DATA_JSON, "***"), "Invalid assertion encoding");
+    }
+}
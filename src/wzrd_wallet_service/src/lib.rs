@@ -3,6 +3,7 @@ mod btc_types;
 mod btc_utils;
 mod icp_utils;
 mod evm_utils;
+mod token_utils;
 use ic_cdk::api::management_canister::{ bitcoin::BitcoinNetwork , http_request::{HttpResponse, TransformArgs}};
 use ic_cdk_macros::{update, query};
 use std::cell::{Cell, RefCell};
@@ -33,11 +34,32 @@ pub async fn destroy_wallet(params: wm_utils::CreateWalletParams) -> wm_utils::D
     wm_utils::destroy_wallet(params).await
 }
 
+#[update (name = "Delete_User_Wallet")]
+pub async fn delete_user_wallet(user_name: String) -> wm_utils::DeleteUserWalletResponse {
+    let network = NETWORK.with(|n| n.get());
+    wm_utils::delete_user_wallet(network, user_name).await
+}
+
+#[update (name = "Rename_User_Wallet")]
+pub fn rename_user_wallet(old_user_name: String, new_user_name: String) -> wm_utils::RenameUserWalletResponse {
+    wm_utils::rename_user_wallet(old_user_name, new_user_name)
+}
+
 #[update (name = "Get_Wallet_Address")]
 pub async fn get_wallet_address(params: wm_utils::CreateWalletParams) -> wm_utils::CreateWalletResponse {
     wm_utils::get_wallet_address(params).await
 }
 
+#[update (name = "Export_Wallet_Phrase")]
+pub async fn export_wallet_phrase(params: wm_utils::CreateWalletParams) -> wm_utils::WalletPhraseResponse {
+    wm_utils::export_wallet_phrase(params).await
+}
+
+#[update (name = "Get_User_Wallet")]
+pub async fn get_user_wallet(params: wm_utils::UserWalletParams) -> wm_utils::UserWalletResponse {
+    wm_utils::get_user_wallet(params).await
+}
+
 #[update (name = "Get_BTC_Balance")]
 pub async fn get_btc_balance(request: wm_utils::BalanceRequest) -> wm_utils::BalanceResult {
     let network = NETWORK.with(|n| n.get());
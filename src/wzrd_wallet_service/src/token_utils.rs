@@ -0,0 +1,9 @@
+pub const ID_SERVICE_CANISTER: &str = "o75p4-yqaaa-aaaal-adt2a-cai";
+// Scopes a third-party token must carry, first-party tokens pass every check
+pub const WALLET_BALANCE: &str = "wallet:balance";
+pub const WALLET_SEND: &str = "wallet:send";
+pub const WALLET_MANAGE: &str = "wallet:manage"; // third-party apps get addresses only
+
+// Sends, creating, importing and destroying wallets and admin lookups go through
+// `check_token_online`, roles are never read from the token for these
+wzrd_auth::token_checks!(ID_SERVICE_CANISTER);
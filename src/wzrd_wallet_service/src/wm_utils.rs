@@ -2,11 +2,8 @@ use candid::{CandidType, Deserialize, Principal};
 use std::collections::BTreeMap;
 use std::cell::RefCell;
 use bip39::{Mnemonic, Language}; 
-use sha2::Sha256;
-use hmac::{Hmac, Mac};
-use jwt::VerifyWithKey;
 use ic_cdk::api::management_canister::{ bitcoin::BitcoinNetwork , http_request::{HttpResponse, TransformArgs}};
-use crate::{btc_utils, icp_utils, evm_utils};
+use crate::{btc_utils, icp_utils, evm_utils, token_utils};
 
 #[derive(CandidType,Clone, Deserialize, Debug)]
 pub struct WalletInfo {
@@ -18,6 +15,14 @@ pub struct WalletInfo {
 
 type WalletStore = BTreeMap<String, WalletInfo>; //(user_name => wallet info)
 
+const EVM_NETWORKS: [&str; 3] = ["ethereum", "binance", "polygon"];
+// Matched by the ID service, which holds the rename for a controller instead of retrying it
+const RENAME_CONFLICT: &str = "New username already has a wallet";
+const RENAME_PENDING: &str = "Username change still in progress, try again later";
+// `wallet:manage` lets third-party apps read addresses, anything touching the phrase is first-party only
+const THIRD_PARTY_MANAGE: &str = "Third-party apps can only read wallet addresses";
+const STEP_UP_REQUIRED: &str = "Step-up verification required"; // same as the ID service
+
 thread_local! {
     pub static WALLET_STORE: RefCell<WalletStore> = RefCell::default();
 }
@@ -40,6 +45,18 @@ pub struct DestoryWalletResponse {
     pub result: bool
 }
 
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct DeleteUserWalletResponse {
+    pub error: String,
+    pub result: bool
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct RenameUserWalletResponse {
+    pub error: String,
+    pub result: bool
+}
+
 #[derive(CandidType, Clone, Debug, Deserialize)]
 pub struct CreateWalletResponse {
     pub error: String,
@@ -50,6 +67,28 @@ pub struct CreateWalletResponse {
     pub evm_address: String,
 }
 
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct WalletPhraseResponse {
+    pub error: String,
+    pub token: String,
+    pub phrase: String
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct UserWalletParams {
+    pub token: String,
+    pub user_name: String
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct UserWalletResponse {
+    pub error: String,
+    pub token: String,
+    pub btc_address: String,
+    pub icp_address: String,
+    pub evm_address: String,
+}
+
 #[derive(CandidType, Clone, Debug, Deserialize)]
 pub struct BalanceRequest {
     pub token: String
@@ -91,7 +130,7 @@ pub struct SendResult {
 }
 
 pub async fn create_wallet(network: BitcoinNetwork, key_name: String, params: CreateWalletParams) -> CreateWalletResponse {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token,)).await;
+    let user_validation = token_utils::check_token_online(params.token, token_utils::WALLET_MANAGE).await;
     match user_validation {
         Err(_err) => {
             CreateWalletResponse {
@@ -103,7 +142,7 @@ pub async fn create_wallet(network: BitcoinNetwork, key_name: String, params: Cr
                 btc_address: "".to_string()
             }
         }
-        Ok((token,)) => {
+        Ok((token, access)) => {
             if token == "".to_string() {
                 CreateWalletResponse {
                     error: "Invalid token".to_string(),
@@ -114,6 +153,26 @@ pub async fn create_wallet(network: BitcoinNetwork, key_name: String, params: Cr
                     btc_address: "".to_string()
                 }
             }
+            else if access.scoped {
+                CreateWalletResponse {
+                    error: THIRD_PARTY_MANAGE.to_string(),
+                    token,
+                    phrase: "".to_string(),
+                    icp_address: "".to_string(),
+                    evm_address: "".to_string(),
+                    btc_address: "".to_string()
+                }
+            }
+            else if is_rename_pending(&get_user_name(token.clone())).await {
+                CreateWalletResponse {
+                    error: RENAME_PENDING.to_string(),
+                    token,
+                    phrase: "".to_string(),
+                    icp_address: "".to_string(),
+                    evm_address: "".to_string(),
+                    btc_address: "".to_string()
+                }
+            }
             else{
                 let res = ic_cdk::call::<(), (Vec<u8>,)>(Principal::management_canister(), "raw_rand", ()).await;
                 match res {
@@ -171,7 +230,7 @@ pub async fn create_wallet(network: BitcoinNetwork, key_name: String, params: Cr
 }
 
 pub async fn import_wallet(network: BitcoinNetwork, key_name: String, params: ImportWalletParams) -> CreateWalletResponse {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token,)).await;
+    let user_validation = token_utils::check_token_online(params.token, token_utils::WALLET_MANAGE).await;
     match user_validation {
         Err(_err) => {
             CreateWalletResponse {
@@ -183,7 +242,7 @@ pub async fn import_wallet(network: BitcoinNetwork, key_name: String, params: Im
                 btc_address: "".to_string()
             }
         }
-        Ok((token,)) => {
+        Ok((token, access)) => {
             if token == "".to_string() {
                 CreateWalletResponse {
                     error: "Invalid token".to_string(),
@@ -194,6 +253,36 @@ pub async fn import_wallet(network: BitcoinNetwork, key_name: String, params: Im
                     btc_address: "".to_string()
                 }
             }
+            else if access.scoped {
+                CreateWalletResponse {
+                    error: THIRD_PARTY_MANAGE.to_string(),
+                    token,
+                    phrase: "".to_string(),
+                    icp_address: "".to_string(),
+                    evm_address: "".to_string(),
+                    btc_address: "".to_string()
+                }
+            }
+            else if !access.step_up {
+                CreateWalletResponse {
+                    error: STEP_UP_REQUIRED.to_string(),
+                    token,
+                    phrase: "".to_string(),
+                    icp_address: "".to_string(),
+                    evm_address: "".to_string(),
+                    btc_address: "".to_string()
+                }
+            }
+            else if is_rename_pending(&get_user_name(token.clone())).await {
+                CreateWalletResponse {
+                    error: RENAME_PENDING.to_string(),
+                    token,
+                    phrase: "".to_string(),
+                    icp_address: "".to_string(),
+                    evm_address: "".to_string(),
+                    btc_address: "".to_string()
+                }
+            }
             else{
                 let phrase = params.phrase;
                 let mnemonic = Mnemonic::from_phrase(phrase.clone(), Language::English);
@@ -250,7 +339,7 @@ pub async fn import_wallet(network: BitcoinNetwork, key_name: String, params: Im
 }
 
 pub async fn destroy_wallet(params: CreateWalletParams) -> DestoryWalletResponse {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token,)).await;
+    let user_validation = token_utils::check_token_online(params.token, token_utils::WALLET_MANAGE).await;
     match user_validation {
         Err(_err) => {
             DestoryWalletResponse {
@@ -259,7 +348,7 @@ pub async fn destroy_wallet(params: CreateWalletParams) -> DestoryWalletResponse
                 result: false
             }
         }
-        Ok((token,)) => {
+        Ok((token, access)) => {
             if token == "".to_string() {
                DestoryWalletResponse {
                     error: "Invalid token".to_string(),
@@ -267,6 +356,20 @@ pub async fn destroy_wallet(params: CreateWalletParams) -> DestoryWalletResponse
                     result: false
                 }
             }
+            else if access.scoped {
+                DestoryWalletResponse {
+                    error: THIRD_PARTY_MANAGE.to_string(),
+                    token,
+                    result: false
+                }
+            }
+            else if !access.step_up {
+                DestoryWalletResponse {
+                    error: STEP_UP_REQUIRED.to_string(),
+                    token,
+                    result: false
+                }
+            }
             else{
                 let user_name = get_user_name(token.clone());
                 WALLET_STORE.with(|wallet_store| {
@@ -291,8 +394,156 @@ pub async fn destroy_wallet(params: CreateWalletParams) -> DestoryWalletResponse
     }
 }
 
+// Called by the ID service when an account is deleted. The phrase is the only way to move
+// the funds, so the wallet is kept while any balance is non-zero or can't be checked.
+pub async fn delete_user_wallet(network: BitcoinNetwork, user_name: String) -> DeleteUserWalletResponse {
+    if ic_cdk::caller() != Principal::from_text(token_utils::ID_SERVICE_CANISTER).unwrap() {
+        return DeleteUserWalletResponse {
+            error: "Only the ID service can delete user wallets".to_string(),
+            result: false
+        };
+    }
+    let wallet_info = match WALLET_STORE.with(|wallet_store| wallet_store.borrow().get(&user_name).cloned()) {
+        Some(wallet_info) => wallet_info,
+        None => return DeleteUserWalletResponse {
+            error: "No wallet exist".to_string(),
+            result: false
+        }
+    };
+
+    let mut balances = vec![
+        btc_utils::get_btc_balance(network, wallet_info.btc_address.clone()).await,
+        icp_utils::get_icp_balance(wallet_info.icp_address.clone()).await
+    ];
+    for evm_network in EVM_NETWORKS.iter() {
+        let (balance, error) = evm_utils::get_evm_balance(evm_network.to_string(), wallet_info.evm_address.clone()).await;
+        balances.push((error, balance));
+        let (balance, error) = evm_utils::get_usdt_balance(evm_network.to_string(), wallet_info.evm_address.clone()).await;
+        balances.push((error, balance));
+    }
+    for (error, balance) in balances {
+        if error != "" {
+            return DeleteUserWalletResponse {
+                error: format!("Can't check wallet balance: {}", error),
+                result: false
+            };
+        }
+        if balance > 0 {
+            return DeleteUserWalletResponse {
+                error: "Wallet balance is not empty".to_string(),
+                result: false
+            };
+        }
+    }
+
+    WALLET_STORE.with(|wallet_store| {
+        wallet_store.borrow_mut().remove(&user_name);
+    });
+    DeleteUserWalletResponse {
+        error: "".to_string(),
+        result: true
+    }
+}
+
+// Called by the ID service after a username change. Addresses derive from the phrase, so
+// moving the entry keeps them intact.
+// A wallet already under the new name is a conflict that retrying can't resolve, so it's
+// reported as such and both wallets are left as they are
+pub fn rename_user_wallet(old_user_name: String, new_user_name: String) -> RenameUserWalletResponse {
+    if ic_cdk::caller() != Principal::from_text(token_utils::ID_SERVICE_CANISTER).unwrap() {
+        return RenameUserWalletResponse {
+            error: "Only the ID service can rename user wallets".to_string(),
+            result: false
+        };
+    }
+    WALLET_STORE.with(|wallet_store| {
+        let mut wallet_store = wallet_store.borrow_mut();
+        if !wallet_store.contains_key(&old_user_name) {
+            return RenameUserWalletResponse {
+                error: "".to_string(),
+                result: true
+            };
+        }
+        if wallet_store.contains_key(&new_user_name) {
+            return RenameUserWalletResponse {
+                error: RENAME_CONFLICT.to_string(),
+                result: false
+            };
+        }
+        if let Some(wallet_info) = wallet_store.remove(&old_user_name) {
+            wallet_store.insert(new_user_name, wallet_info);
+        }
+        RenameUserWalletResponse {
+            error: "".to_string(),
+            result: true
+        }
+    })
+}
+
+// Until the ID service has moved the user's wallet to their new name, a wallet created under
+// it would block the move. Fails closed when the ID service can't be reached.
+async fn is_rename_pending(user_name: &str) -> bool {
+    let res = ic_cdk::call::<(String,), (bool,)>(Principal::from_text(token_utils::ID_SERVICE_CANISTER).unwrap(), "IsRenamePending", (user_name.to_string(),)).await;
+    res.map_or(true, |(pending,)| pending)
+}
+
+// Support lookup for admins. Only the addresses are returned, never the phrase.
+pub async fn get_user_wallet(params: UserWalletParams) -> UserWalletResponse {
+    let user_validation = token_utils::check_token_online(params.token, token_utils::WALLET_MANAGE).await;
+    match user_validation {
+        Err(_err) => {
+            UserWalletResponse {
+                error: "Can't access ID service".to_string(),
+                token: "".to_string(),
+                icp_address: "".to_string(),
+                evm_address: "".to_string(),
+                btc_address: "".to_string()
+            }
+        }
+        Ok((token, access)) => {
+            if token == "".to_string() {
+                UserWalletResponse {
+                    error: "Invalid token".to_string(),
+                    token: "".to_string(),
+                    icp_address: "".to_string(),
+                    evm_address: "".to_string(),
+                    btc_address: "".to_string()
+                }
+            }
+            else if !access.roles.iter().any(|role| role == "admin") {
+                UserWalletResponse {
+                    error: "Only admins can look up user wallets".to_string(),
+                    token,
+                    icp_address: "".to_string(),
+                    evm_address: "".to_string(),
+                    btc_address: "".to_string()
+                }
+            }
+            else{
+                match WALLET_STORE.with(|wallet_store| wallet_store.borrow().get(&params.user_name).cloned()) {
+                    Some(wallet_info) => UserWalletResponse {
+                        error: "".to_string(),
+                        token,
+                        icp_address: wallet_info.icp_address,
+                        evm_address: wallet_info.evm_address,
+                        btc_address: wallet_info.btc_address
+                    },
+                    None => UserWalletResponse {
+                        error: "No wallet exist".to_string(),
+                        token,
+                        icp_address: "".to_string(),
+                        evm_address: "".to_string(),
+                        btc_address: "".to_string()
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The addresses of the caller's wallet. `phrase` is always empty, see `export_wallet_phrase`.
 pub async fn get_wallet_address(params: CreateWalletParams) -> CreateWalletResponse {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token,)).await;
+    let user_validation = token_utils::check_token_online(params.token, token_utils::WALLET_MANAGE).await;
     match user_validation {
         Err(_err) => {
             CreateWalletResponse {
@@ -304,7 +555,7 @@ pub async fn get_wallet_address(params: CreateWalletParams) -> CreateWalletRespo
                 btc_address: "".to_string()
             }
         }
-        Ok((token,)) => {
+        Ok((token, _)) => {
             if token == "".to_string() {
                 CreateWalletResponse {
                     error: "Invalid token".to_string(),
@@ -323,7 +574,7 @@ pub async fn get_wallet_address(params: CreateWalletParams) -> CreateWalletRespo
                         CreateWalletResponse {
                             error: "".to_string(),
                             token,
-                            phrase: wallet_info.phrase,
+                            phrase: "".to_string(),
                             icp_address: wallet_info.icp_address,
                             evm_address: wallet_info.evm_address,
                             btc_address: wallet_info.btc_address
@@ -345,8 +596,61 @@ pub async fn get_wallet_address(params: CreateWalletParams) -> CreateWalletRespo
     }
 }
 
+// The phrase controls the funds, so reading it back takes a first-party token with a recent
+// step-up, checked with the ID service
+pub async fn export_wallet_phrase(params: CreateWalletParams) -> WalletPhraseResponse {
+    let user_validation = token_utils::check_token_online(params.token, token_utils::WALLET_MANAGE).await;
+    match user_validation {
+        Err(_err) => {
+            WalletPhraseResponse {
+                error: "Can't access ID service".to_string(),
+                token: "".to_string(),
+                phrase: "".to_string()
+            }
+        }
+        Ok((token, access)) => {
+            if token == "".to_string() {
+                WalletPhraseResponse {
+                    error: "Invalid token".to_string(),
+                    token,
+                    phrase: "".to_string()
+                }
+            }
+            else if access.scoped {
+                WalletPhraseResponse {
+                    error: THIRD_PARTY_MANAGE.to_string(),
+                    token,
+                    phrase: "".to_string()
+                }
+            }
+            else if !access.step_up {
+                WalletPhraseResponse {
+                    error: STEP_UP_REQUIRED.to_string(),
+                    token,
+                    phrase: "".to_string()
+                }
+            }
+            else{
+                let user_name = get_user_name(token.clone());
+                match WALLET_STORE.with(|wallet_store| wallet_store.borrow().get(&user_name).cloned()) {
+                    Some(wallet_info) => WalletPhraseResponse {
+                        error: "".to_string(),
+                        token,
+                        phrase: wallet_info.phrase
+                    },
+                    None => WalletPhraseResponse {
+                        error: "No wallet exist".to_string(),
+                        token,
+                        phrase: "".to_string()
+                    }
+                }
+            }
+        }
+    }
+}
+
 pub async fn get_icp_balance(params: BalanceRequest) -> BalanceResult {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token,)).await;
+    let user_validation = token_utils::check_token(params.token, token_utils::WALLET_BALANCE).await;
     match user_validation {
         Err(_err) => {
             BalanceResult {
@@ -392,7 +696,7 @@ pub async fn get_icp_balance(params: BalanceRequest) -> BalanceResult {
 } 
 
 pub async fn send_icp(params: SendRequest) -> SendResult {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token,)).await;
+    let user_validation = token_utils::check_token_online(params.token, token_utils::WALLET_SEND).await;
     match user_validation {
         Err(_err) => {
             SendResult {
@@ -401,7 +705,7 @@ pub async fn send_icp(params: SendRequest) -> SendResult {
                 result: "".to_string()
             }
         }
-        Ok((token,)) => {
+        Ok((token, access)) => {
             if token == "".to_string() {
                 SendResult {
                     error: "Invalid token".to_string(),
@@ -409,6 +713,13 @@ pub async fn send_icp(params: SendRequest) -> SendResult {
                     result: "".to_string()
                 }
             }
+            else if !access.step_up {
+                SendResult {
+                    error: STEP_UP_REQUIRED.to_string(),
+                    token,
+                    result: "".to_string()
+                }
+            }
             else{
                 let user_name = get_user_name(token.clone());
                 let mut phrase = "".to_string();
@@ -438,7 +749,7 @@ pub async fn send_icp(params: SendRequest) -> SendResult {
 }
 
 pub async fn get_evm_balance(params: EVMBalanceRequest) -> BalanceResult {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token,)).await;
+    let user_validation = token_utils::check_token(params.token, token_utils::WALLET_BALANCE).await;
     match user_validation {
         Err(_err) => {
             BalanceResult {
@@ -484,7 +795,7 @@ pub async fn get_evm_balance(params: EVMBalanceRequest) -> BalanceResult {
 } 
 
 pub async fn send_evm(params: EVMSendRequest, key_name: String) -> SendResult {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token,)).await;
+    let user_validation = token_utils::check_token_online(params.token, token_utils::WALLET_SEND).await;
     match user_validation {
         Err(_err) => {
             SendResult {
@@ -493,7 +804,7 @@ pub async fn send_evm(params: EVMSendRequest, key_name: String) -> SendResult {
                 result: "".to_string()
             }
         }
-        Ok((token,)) => {
+        Ok((token, access)) => {
             if token == "".to_string() {
                 SendResult {
                     error: "Invalid token".to_string(),
@@ -501,6 +812,13 @@ pub async fn send_evm(params: EVMSendRequest, key_name: String) -> SendResult {
                     result: "".to_string()
                 }
             }
+            else if !access.step_up {
+                SendResult {
+                    error: STEP_UP_REQUIRED.to_string(),
+                    token,
+                    result: "".to_string()
+                }
+            }
             else{
                 let user_name = get_user_name(token.clone());
                 let mut phrase = "".to_string();
@@ -530,7 +848,7 @@ pub async fn send_evm(params: EVMSendRequest, key_name: String) -> SendResult {
 }
 
 pub async fn get_usdt_balance(params: EVMBalanceRequest) -> BalanceResult {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token,)).await;
+    let user_validation = token_utils::check_token(params.token, token_utils::WALLET_BALANCE).await;
     match user_validation {
         Err(_err) => {
             BalanceResult {
@@ -576,7 +894,7 @@ pub async fn get_usdt_balance(params: EVMBalanceRequest) -> BalanceResult {
 }
 
 pub async fn send_usdt(params: EVMSendRequest, key_name: String) -> SendResult {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token,)).await;
+    let user_validation = token_utils::check_token_online(params.token, token_utils::WALLET_SEND).await;
     match user_validation {
         Err(_err) => {
             SendResult {
@@ -585,7 +903,7 @@ pub async fn send_usdt(params: EVMSendRequest, key_name: String) -> SendResult {
                 result: "".to_string()
             }
         }
-        Ok((token,)) => {
+        Ok((token, access)) => {
             if token == "".to_string() {
                 SendResult {
                     error: "Invalid token".to_string(),
@@ -593,6 +911,13 @@ pub async fn send_usdt(params: EVMSendRequest, key_name: String) -> SendResult {
                     result: "".to_string()
                 }
             }
+            else if !access.step_up {
+                SendResult {
+                    error: STEP_UP_REQUIRED.to_string(),
+                    token,
+                    result: "".to_string()
+                }
+            }
             else{
                 let user_name = get_user_name(token.clone());
                 let mut phrase = "".to_string();
@@ -622,7 +947,7 @@ pub async fn send_usdt(params: EVMSendRequest, key_name: String) -> SendResult {
 }
 
 pub async fn get_btc_balance(network: BitcoinNetwork, params: BalanceRequest) -> BalanceResult {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token,)).await;
+    let user_validation = token_utils::check_token(params.token, token_utils::WALLET_BALANCE).await;
     match user_validation {
         Err(_err) => {
             BalanceResult {
@@ -668,7 +993,7 @@ pub async fn get_btc_balance(network: BitcoinNetwork, params: BalanceRequest) ->
 }
 
 pub async fn send_btc(network: BitcoinNetwork, key_name: String, params: SendRequest) -> SendResult {
-    let user_validation = ic_cdk::call::<(String,), (String,)>(Principal::from_text("o75p4-yqaaa-aaaal-adt2a-cai").unwrap(), "CheckToken", (params.token,)).await;
+    let user_validation = token_utils::check_token_online(params.token, token_utils::WALLET_SEND).await;
     match user_validation {
         Err(_err) => {
             SendResult {
@@ -677,7 +1002,7 @@ pub async fn send_btc(network: BitcoinNetwork, key_name: String, params: SendReq
                 result: "".to_string()
             }
         }
-        Ok((token,)) => {
+        Ok((token, access)) => {
             if token == "".to_string() {
                 SendResult {
                     error: "Invalid token".to_string(),
@@ -685,6 +1010,13 @@ pub async fn send_btc(network: BitcoinNetwork, key_name: String, params: SendReq
                     result: "".to_string()
                 }
             }
+            else if !access.step_up {
+                SendResult {
+                    error: STEP_UP_REQUIRED.to_string(),
+                    token,
+                    result: "".to_string()
+                }
+            }
             else{
                 let user_name = get_user_name(token.clone());
                 let mut phrase = "".to_string();
@@ -714,15 +1046,10 @@ pub async fn send_btc(network: BitcoinNetwork, key_name: String, params: SendReq
 }
 
 pub fn get_user_name(token: String) -> String {
-    let key: Hmac<Sha256> = Hmac::new_from_slice(b"wzrd-secret-key").unwrap();
-    let veri_claims;
-    let result: Result<BTreeMap<String, String>, jwt::Error> = token.as_str().verify_with_key(&key);
-    match result{
-        Ok(okay_result) => veri_claims = okay_result,
-        Err(_) => return "".to_string()
-    };
-    let user_name = &veri_claims["username"];
-    user_name.clone()
+    match token_utils::decode_claims(&token) {
+        Some(claims) => claims.username,
+        None => "".to_string()
+    }
 }
 
 pub fn transform(response: TransformArgs) -> HttpResponse {